@@ -0,0 +1,77 @@
+#![allow(clippy::all)]
+
+//! Compares the JSON and binary wire protocols encoding and decoding the largest directive,
+//! `SubmitProgram`, and the most frequent notification, a sequenced `Trade`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use vmx::auction::Side;
+use vmx::clock::Timestamp;
+use vmx::protocol::binary::BinaryProtocol;
+use vmx::protocol::json::JsonProtocol;
+use vmx::protocol::{ClientDirective, ClientNotification, WireProtocol};
+use vmx::vm::Program;
+use vmx::{Price, ProductId};
+
+fn submit_program() -> ClientDirective {
+    let mut assembly = String::new();
+    for idx in 0..16 {
+        assembly.push_str(&format!("movimm r{} {}\n", idx % 16, idx * 100));
+    }
+    for _ in 0..16 {
+        assembly.push_str("arrins r0 r1 r2\nadd r3 r4 r5\n");
+    }
+    assembly.push_str("halt");
+    ClientDirective::SubmitProgram {
+        product_id: ProductId(1),
+        program: Program::try_from_str(&assembly).unwrap(),
+    }
+}
+
+fn sequenced_trade() -> ClientNotification {
+    ClientNotification::Sequenced {
+        sequence: 1234,
+        notification: Box::new(ClientNotification::Trade {
+            product_id: ProductId(1),
+            side: Side::Bid,
+            price: Price(100),
+            quantity: 10,
+            timestamp: Timestamp(1_600_000_000_000_000_000),
+        }),
+    }
+}
+
+fn bench_protocol<W: WireProtocol>(c: &mut Criterion, name: &str) {
+    let directive = submit_program();
+    let notification = sequenced_trade();
+    let directive_bytes = W::try_client_directive_to_bytes(&directive).unwrap();
+    let notification_bytes = W::try_client_notification_to_bytes(&notification).unwrap();
+
+    let mut group = c.benchmark_group(format!("{}/submit_program", name));
+    group.throughput(Throughput::Bytes(directive_bytes.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| W::try_client_directive_to_bytes(&directive).unwrap())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| W::try_client_directive_from_bytes(&directive_bytes).unwrap())
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("{}/sequenced_trade", name));
+    group.throughput(Throughput::Bytes(notification_bytes.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| W::try_client_notification_to_bytes(&notification).unwrap())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| W::try_client_notification_from_bytes(&notification_bytes).unwrap())
+    });
+    group.finish();
+}
+
+fn protocols(c: &mut Criterion) {
+    bench_protocol::<JsonProtocol>(c, "json");
+    bench_protocol::<BinaryProtocol>(c, "binary");
+}
+
+criterion_group!(benches, protocols);
+criterion_main!(benches);
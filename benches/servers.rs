@@ -0,0 +1,142 @@
+#![allow(clippy::all)]
+
+//! Compares the thread-per-connection and evented servers moving raw frames, without any
+//! protocol decoding.
+//! - `throughput`: every client sends a burst of messages, timed until the server has them all
+//! - `round_trip`: one client sends a message and waits for the server to echo it back
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use vmx::server::{evented, tcp, OutgoingMessage, Server, ServerConfig, ServerMode};
+
+const MESSAGES_PER_CLIENT: usize = 100;
+const MESSAGE: &[u8] = b"{\"type\": \"UpdateParameters\", \"product_id\": 1}\n";
+
+trait BenchServer: Server + Sized {
+    fn start() -> (Self, SocketAddr);
+}
+
+impl BenchServer for tcp::Server {
+    fn start() -> (Self, SocketAddr) {
+        let mut server = tcp::Server::new(bench_config(ServerMode::Threaded));
+        server.start_listening().unwrap();
+        let address = server.local_addr().unwrap();
+        (server, address)
+    }
+}
+
+impl BenchServer for evented::Server {
+    fn start() -> (Self, SocketAddr) {
+        let mut server = evented::Server::new(bench_config(ServerMode::Evented));
+        server.start_listening().unwrap();
+        let address = server.local_addr().unwrap();
+        (server, address)
+    }
+}
+
+fn bench_config(mode: ServerMode) -> ServerConfig {
+    ServerConfig {
+        port: 0,
+        mode,
+        ..ServerConfig::default()
+    }
+}
+
+/// Connects the clients and waits until the server has heard from each of them
+fn connect_clients<S: Server>(
+    server: &mut S,
+    address: SocketAddr,
+    num_clients: usize,
+) -> Vec<TcpStream> {
+    let mut clients: Vec<TcpStream> = Vec::default();
+    for _ in 0..num_clients {
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_nodelay(true).unwrap();
+        client.write_all(MESSAGE).unwrap();
+        // Accepts as we go, so the listen backlog never fills
+        receive_messages(server, 1);
+        clients.push(client);
+    }
+    clients
+}
+
+fn receive_messages<S: Server>(server: &mut S, num_messages: usize) {
+    let mut received = 0;
+    while received < num_messages {
+        received += server.drain_pending_messages().len();
+    }
+}
+
+fn throughput<S: BenchServer>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(format!("throughput/{}", name));
+    for &num_clients in &[1usize, 10, 100, 300] {
+        let (mut server, address) = S::start();
+        let mut clients = connect_clients(&mut server, address, num_clients);
+        let burst: Vec<u8> = MESSAGE.repeat(MESSAGES_PER_CLIENT);
+
+        group.throughput(Throughput::Elements(
+            (num_clients * MESSAGES_PER_CLIENT) as u64,
+        ));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_clients),
+            &num_clients,
+            |b, &num_clients| {
+                b.iter(|| {
+                    for client in &mut clients {
+                        client.write_all(&burst).unwrap();
+                    }
+                    receive_messages(&mut server, num_clients * MESSAGES_PER_CLIENT);
+                })
+            },
+        );
+        drop(clients);
+        server.stop_listening().unwrap();
+    }
+    group.finish();
+}
+
+fn round_trip<S: BenchServer>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group("round_trip");
+    let (mut server, address) = S::start();
+    let mut client = connect_clients(&mut server, address, 1).remove(0);
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reply = vec![0u8; MESSAGE.len()];
+
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            client.write_all(MESSAGE).unwrap();
+            let echoes: Vec<OutgoingMessage> = loop {
+                let messages = server.drain_pending_messages();
+                if !messages.is_empty() {
+                    break messages
+                        .into_iter()
+                        .map(|message| OutgoingMessage {
+                            client_id: message.client_id,
+                            bytes: message.bytes,
+                        })
+                        .collect();
+                }
+            };
+            server.send_notifications(&echoes).unwrap();
+            client.read_exact(&mut reply).unwrap();
+        })
+    });
+    server.stop_listening().unwrap();
+    group.finish();
+}
+
+fn servers(c: &mut Criterion) {
+    throughput::<tcp::Server>(c, "threaded");
+    throughput::<evented::Server>(c, "evented");
+    round_trip::<tcp::Server>(c, "threaded");
+    round_trip::<evented::Server>(c, "evented");
+}
+
+criterion_group!(benches, servers);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+#![allow(clippy::all)]
+
+//! Compares stepping a program one instruction at a time, as the debugger does, with running it
+//! on the pre-decoded interpreter, as auctions do.  The program is a compiled strategy walking
+//! every level of a book, so most of its instructions read the book.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use vmx::strategy;
+use vmx::vm::{ExecutionState, Program, ProgramInstance};
+
+const LEVELS: &[u64] = &[10, 100, 1_000];
+const LOWEST_BID: u64 = 1_000;
+
+fn walk_book() -> Program {
+    strategy::compile(
+        "
+        let total = 0;
+        let weighted = 0;
+        for price in lowest_bid..best_bid + 1 {
+            total = total + bid_size(price);
+            weighted = weighted + price * bid_size(price);
+        }
+        if total > 0 {
+            bid(weighted / total, total);
+        }
+        ",
+    )
+    .unwrap()
+}
+
+/// A program instance set up as an auction sets it up, with a bid at each of `levels` prices
+fn instance(program: &Program, levels: u64) -> ProgramInstance {
+    let mut state = ExecutionState::default();
+    state.array_insert(1, 0, LOWEST_BID as i64);
+    state.array_insert(1, 1, (LOWEST_BID + levels - 1) as i64);
+    for price in LOWEST_BID..LOWEST_BID + levels {
+        state.array_insert(2, price, (price % 7 + 1) as i64);
+    }
+    let mut instance = ProgramInstance::new(program.clone(), state);
+    instance.set_gas_limit(u64::MAX);
+    for arr in 0..=8 {
+        instance.set_read_only(arr);
+    }
+    instance
+}
+
+fn vm(c: &mut Criterion) {
+    let program = walk_book();
+    let mut group = c.benchmark_group("vm/walk_book");
+    for levels in LEVELS {
+        let mut probe = instance(&program, *levels);
+        probe.run().unwrap();
+        group.throughput(Throughput::Elements(probe.steps_executed()));
+        group.bench_with_input(BenchmarkId::new("step", levels), levels, |b, levels| {
+            b.iter_batched(
+                || instance(&program, *levels),
+                |mut instance| while instance.execute_step().unwrap() {},
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("run", levels), levels, |b, levels| {
+            b.iter_batched(
+                || instance(&program, *levels),
+                |mut instance| instance.run().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, vm);
+criterion_main!(benches);
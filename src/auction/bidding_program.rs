@@ -1,12 +1,29 @@
-use super::{Book, Order, ParticipantParameters, ProductId, Side};
+use std::collections::BTreeMap;
+
+use super::{Book, Order, ParticipantParameters, ProgramStorage, RiskLimits, Side};
 use crate::participant::ParticipantId;
 use crate::{vm, Price};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     SelfCrossing,
+    OrderQuantityLimit,
+    StorageLimit,
+    MemoryLimit,
+    ReadOnlyArray,
+    StackOverflow,
+    StackUnderflow,
+    DivideByZero,
+    OutOfGas,
 }
 
+/// Where a program's storage is kept between rounds
+const STORAGE_ARRAY: u64 = 12;
+/// Charged for each write to storage on top of the usual cost of `arrins`
+const STORAGE_WRITE_GAS: u64 = 8;
+/// Everything but the outputs and storage
+const READ_ONLY_ARRAYS: &[u64] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 11];
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -16,7 +33,7 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {}
 
 /// Input
-/// ```{text}
+/// ```text
 /// arr0[param_idx]: parameter value
 ///
 /// arr1[0]: min bid price or 0 if none
@@ -28,16 +45,26 @@ impl std::error::Error for Error {}
 /// arr4[price]: #offers at price
 ///
 /// arr5[0]: my min bid price or 0 if none
-/// arr5[0]: my max bid price or 0 if none
+/// arr5[1]: my max bid price or 0 if none
 /// arr6[price]: #my bids at price
 ///
 /// arr7[0]: my min offer price or 0 if none
-/// arr7[0]: my max offer price or 0 if none
+/// arr7[1]: my max offer price or 0 if none
 /// arr8[price]: #my offers at price
+///
+/// arr11[0]: my position, the quantity I have bought minus the quantity I have sold
+/// ```
+///
+/// Storage, kept from one bidding round and auction to the next until the program is replaced
+/// ```text
+/// arr12[idx]: whatever the program wrote there last time it succeeded, or 0
 /// ```
 ///
+/// Inputs are read-only, and the program may use other arrays from arr13 on as scratch memory,
+/// within `RiskLimits`.
+///
 /// Output
-/// ```{text}
+/// ```text
 /// arr9[0]: 0 if reusing old bids, 1 if erasing old bids
 /// arr9[price]: #bids to add or subtract at price (negative result is error)
 /// arr10[0]: 0 if reusing old offers, 1 if erasing old offers
@@ -45,6 +72,8 @@ impl std::error::Error for Error {}
 /// ```
 pub struct ProgramInstance {
     vm_program_instance: vm::ProgramInstance,
+    max_order_quantity: u64,
+    max_storage_entries: u64,
 }
 
 impl ProgramInstance {
@@ -56,66 +85,118 @@ impl ProgramInstance {
     ) -> Self {
         let mut state = vm::ExecutionState::default();
 
-        if let Some((lowest_bid, highest_bid)) = book.bid_bounds() {
-            state.array_insert(1, 0, lowest_bid.into());
-            state.array_insert(1, 1, highest_bid.into());
-            for price in (lowest_bid.into()..=highest_bid.into()).map(|idx| Price(idx)) {
-                state.array_insert(2, price.into(), book.bid_quantity_at_price(price) as i64);
+        // Only prices with orders are filled in, the rest reading as 0, so orders far apart cost
+        // no more than orders close together
+        let mut insert_levels = |bounds_array,
+                                 bounds: Option<(Price, Price)>,
+                                 quantities_array,
+                                 quantities: BTreeMap<Price, i64>| {
+            if let Some((lowest, highest)) = bounds {
+                state.array_insert(bounds_array, 0, lowest.into());
+                state.array_insert(bounds_array, 1, highest.into());
             }
-        }
-
-        if let Some((lowest_offer, highest_offer)) = book.offer_bounds() {
-            state.array_insert(3, 0, lowest_offer.into());
-            state.array_insert(3, 1, highest_offer.into());
-            for price in (lowest_offer.into()..=highest_offer.into()).map(|idx| Price(idx)) {
-                state.array_insert(4, price.into(), book.offer_quantity_at_price(price) as i64);
-            }
-        }
-
-        if let Some((participant_lowest_bid, participant_highest_bid)) =
-            book.bid_bounds_for_participant(participant)
-        {
-            state.array_insert(5, 0, participant_lowest_bid.into());
-            state.array_insert(5, 1, participant_highest_bid.into());
-            for price in (participant_lowest_bid.into()..=participant_highest_bid.into())
-                .map(|idx| Price(idx))
-            {
-                state.array_insert(
-                    6,
-                    price.into(),
-                    book.bid_quantity_at_price_for_participant(price, participant) as i64,
-                );
-            }
-        }
-
-        if let Some((participant_lowest_offer, participant_highest_offer)) =
-            book.offer_bounds_for_participant(participant)
-        {
-            state.array_insert(5, 0, participant_lowest_offer.into());
-            state.array_insert(5, 1, participant_highest_offer.into());
-            for price in (participant_lowest_offer.into()..=participant_highest_offer.into())
-                .map(|idx| Price(idx))
-            {
-                state.array_insert(
-                    6,
-                    price.into(),
-                    book.bid_quantity_at_price_for_participant(price, participant) as i64,
-                );
+            for (price, quantity) in quantities {
+                state.array_insert(quantities_array, price.0, quantity);
             }
-        }
+        };
+        insert_levels(1, book.bid_bounds(), 2, book.bid_quantities());
+        insert_levels(3, book.offer_bounds(), 4, book.offer_quantities());
+        insert_levels(
+            5,
+            book.bid_bounds_for_participant(participant),
+            6,
+            book.bid_quantities_for_participant(participant),
+        );
+        insert_levels(
+            7,
+            book.offer_bounds_for_participant(participant),
+            8,
+            book.offer_quantities_for_participant(participant),
+        );
 
         for (param_idx, param_value) in &parameters.values {
             state.array_insert(0, *param_idx, *param_value);
         }
 
-        let vm_program_instance = vm::ProgramInstance::new(program.clone(), state);
+        let mut vm_program_instance = vm::ProgramInstance::new(program.clone(), state);
+        vm_program_instance.set_array_write_gas(STORAGE_ARRAY, STORAGE_WRITE_GAS);
+        for arr in READ_ONLY_ARRAYS {
+            vm_program_instance.set_read_only(*arr);
+        }
         Self {
             vm_program_instance,
+            max_order_quantity: u64::MAX,
+            max_storage_entries: u64::MAX,
         }
     }
 
-    pub fn execute(&mut self) {
-        while let Ok(true) = self.vm_program_instance.execute_step() {}
+    pub fn set_risk_limits(&mut self, risk_limits: &RiskLimits) {
+        self.vm_program_instance
+            .set_gas_limit(risk_limits.max_gas_per_execution);
+        self.max_order_quantity = risk_limits.max_order_quantity;
+        self.max_storage_entries = risk_limits.max_storage_entries;
+        self.vm_program_instance
+            .set_memory_limits(vm::MemoryLimits {
+                max_arrays: risk_limits.max_arrays,
+                max_array_entries: risk_limits.max_array_entries,
+            });
+    }
+
+    /// Runs the program until it halts or runs off the end of its code.  A program which exhausts
+    /// its gas, memory or stack, writes to an input, pops from an empty stack, divides by zero or
+    /// leaves too much in storage has its output discarded.
+    pub fn execute(&mut self) -> Result<(), Error> {
+        match self.vm_program_instance.run() {
+            Err(vm::Error::OutOfGas) => return Err(Error::OutOfGas),
+            Err(vm::Error::MemoryLimit) => return Err(Error::MemoryLimit),
+            Err(vm::Error::ReadOnlyArray) => return Err(Error::ReadOnlyArray),
+            Err(vm::Error::StackOverflow) => return Err(Error::StackOverflow),
+            Err(vm::Error::StackUnderflow) => return Err(Error::StackUnderflow),
+            Err(vm::Error::DivideByZero) => return Err(Error::DivideByZero),
+            Ok(()) | Err(_) => {}
+        }
+        if self.storage().len() as u64 > self.max_storage_entries {
+            return Err(Error::StorageLimit);
+        }
+        Ok(())
+    }
+
+    pub fn set_position(&mut self, position: i64) {
+        self.vm_program_instance
+            .state_mut()
+            .array_insert(11, 0, position);
+    }
+
+    /// What the program left in storage last time
+    pub fn set_storage(&mut self, storage: &ProgramStorage) {
+        let state = self.vm_program_instance.state_mut();
+        for (idx, value) in &storage.values {
+            state.array_insert(STORAGE_ARRAY, *idx, *value);
+        }
+    }
+
+    /// What the program has left in storage, to keep for next time
+    pub fn storage(&self) -> ProgramStorage {
+        let values = self
+            .vm_program_instance
+            .state()
+            .iter_touched_values(STORAGE_ARRAY)
+            .filter(|(_idx, value)| *value != 0)
+            .collect();
+        ProgramStorage { values }
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.vm_program_instance.gas_used()
+    }
+
+    /// The program running against the book, for stepping through it in a debugger
+    pub fn vm_program_instance(&self) -> &vm::ProgramInstance {
+        &self.vm_program_instance
+    }
+
+    pub fn vm_program_instance_mut(&mut self) -> &mut vm::ProgramInstance {
+        &mut self.vm_program_instance
     }
 
     pub fn write_result_into_book(
@@ -124,41 +205,29 @@ impl ProgramInstance {
         result_book: &mut Book,
         participant_id: ParticipantId,
     ) -> Result<(), Error> {
-        let mut temp_result_book = Book::new(result_book.product_id);
+        let product_id = result_book.product_id;
+        let mut temp_result_book = Book::new(product_id);
 
         let mut populate_previous_orders =
-            |bounds_accessor: fn(&Book, ParticipantId) -> Option<(Price, Price)>,
-             side,
-             quantity_accessor: fn(&Book, Price, ParticipantId) -> i64| {
-                if let Some((low_price, high_price)) = bounds_accessor(prev_book, participant_id) {
-                    for price in low_price.0..=high_price.0 {
-                        let price = Price(price);
-                        let order = Order {
-                            participant: participant_id,
-                            product_id: ProductId(0),
-                            side,
-                            quantity: quantity_accessor(prev_book, price, participant_id),
-                            price,
-                        };
-                        temp_result_book.insert_order(order);
-                    }
+            |quantities_accessor: fn(&Book, ParticipantId) -> BTreeMap<Price, i64>, side| {
+                for (price, quantity) in quantities_accessor(prev_book, participant_id) {
+                    let order = Order {
+                        participant: participant_id,
+                        product_id,
+                        side,
+                        quantity,
+                        price,
+                    };
+                    temp_result_book.insert_order(order);
                 }
             };
 
         if self.vm_program_instance.state().array_read(9, 0) == 0 {
-            populate_previous_orders(
-                Book::bid_bounds_for_participant,
-                Side::Bid,
-                Book::bid_quantity_at_price_for_participant,
-            );
+            populate_previous_orders(Book::bid_quantities_for_participant, Side::Bid);
         }
 
         if self.vm_program_instance.state().array_read(10, 0) == 0 {
-            populate_previous_orders(
-                Book::offer_bounds_for_participant,
-                Side::Offer,
-                Book::offer_quantity_at_price_for_participant,
-            );
+            populate_previous_orders(Book::offer_quantities_for_participant, Side::Offer);
         }
 
         let mut add_new_orders = |array_idx, side| {
@@ -170,7 +239,7 @@ impl ProgramInstance {
             {
                 let order = Order {
                     participant: participant_id,
-                    product_id: ProductId(0),
+                    product_id,
                     side,
                     quantity: val,
                     price: Price(idx),
@@ -182,6 +251,15 @@ impl ProgramInstance {
         add_new_orders(9, Side::Bid);
         add_new_orders(10, Side::Offer);
 
+        if temp_result_book
+            .levels
+            .values()
+            .flat_map(|level| &level.orders)
+            .any(|order| order.quantity > 0 && order.quantity as u64 > self.max_order_quantity)
+        {
+            return Err(Error::OrderQuantityLimit);
+        }
+
         if let (Some((_min_bid, max_bid)), Some((min_offer, _max_offer))) = (
             temp_result_book.bid_bounds(),
             temp_result_book.offer_bounds(),
@@ -209,6 +287,7 @@ mod tests {
     use super::super::book::Level;
     use super::super::Program;
     use super::*;
+    use crate::ProductId;
 
     #[test]
     fn construct_from_empty_book() {
@@ -372,6 +451,194 @@ mod tests {
         assert_eq!(instance.vm_program_instance.state().array_read(0, 12345), 0);
     }
 
+    #[test]
+    fn construct_with_own_offers_and_position() {
+        let program = vm::Program::from_instructions(&[]);
+        let mut book = Book::new(ProductId(0));
+        for (participant, price) in [(0, 3), (0, 5), (1, 4)].iter() {
+            book.insert_order(Order {
+                participant: ParticipantId(*participant),
+                product_id: ProductId(0),
+                price: Price(*price),
+                quantity: 10,
+                side: Side::Offer,
+            });
+        }
+        let mut instance = ProgramInstance::new(
+            &program,
+            &book,
+            ParticipantId(0),
+            &ParticipantParameters::default(),
+        );
+        instance.set_position(-25);
+        let state = instance.vm_program_instance.state();
+
+        assert_eq!(state.array_read(5, 0), 0);
+        assert_eq!(state.array_read(5, 1), 0);
+        assert_eq!(state.array_read(6, 3), 0);
+
+        assert_eq!(state.array_read(7, 0), 3);
+        assert_eq!(state.array_read(7, 1), 5);
+        assert_eq!(state.array_read(8, 3), 10);
+        assert_eq!(state.array_read(8, 4), 0);
+        assert_eq!(state.array_read(8, 5), 10);
+
+        assert_eq!(state.array_read(11, 0), -25);
+    }
+
+    #[test]
+    fn storage_kept_within_limits() {
+        // arr12[1] = arr12[1] + 1, and arr12[2] = 0
+        let program = vm::Program::try_from_str(
+            "movimm r0 12
+            movimm r1 1
+            movimm r2 2
+            movimm r3 0
+            arrget r4 r0 r1
+            add r4 r4 r1
+            arrins r4 r0 r1
+            arrins r3 r0 r2",
+        )
+        .unwrap();
+        let book = Book::new(ProductId(0));
+        let new_instance = |storage: &ProgramStorage, risk_limits: &RiskLimits| {
+            let mut instance = ProgramInstance::new(
+                &program,
+                &book,
+                ParticipantId(0),
+                &ParticipantParameters::default(),
+            );
+            instance.set_risk_limits(risk_limits);
+            instance.set_storage(storage);
+            instance
+        };
+
+        let mut storage = ProgramStorage::default();
+        storage.values.insert(1, 41);
+        storage.values.insert(2, 5);
+        storage.values.insert(3, 9);
+        let mut instance = new_instance(&storage, &RiskLimits::default());
+        assert_eq!(instance.execute(), Ok(()));
+        // Zeroes aren't kept
+        assert_eq!(instance.storage().len(), 2);
+        assert_eq!(instance.storage().get(1), 42);
+        assert_eq!(instance.storage().get(2), 0);
+        assert_eq!(instance.storage().get(3), 9);
+        // Two writes to storage
+        assert_eq!(instance.gas_used(), 4 + 2 + 1 + 2 * (2 + STORAGE_WRITE_GAS));
+
+        let risk_limits = RiskLimits {
+            max_storage_entries: 1,
+            ..RiskLimits::default()
+        };
+        let mut instance = new_instance(&storage, &risk_limits);
+        assert_eq!(instance.execute(), Err(Error::StorageLimit));
+    }
+
+    #[test]
+    fn memory_limits_enforced() {
+        let book = Book::new(ProductId(0));
+        let execute = |assembly: &str| {
+            let program = vm::Program::try_from_str(assembly).unwrap();
+            let mut instance = ProgramInstance::new(
+                &program,
+                &book,
+                ParticipantId(0),
+                &ParticipantParameters::default(),
+            );
+            instance.set_risk_limits(&RiskLimits {
+                max_arrays: 2,
+                ..RiskLimits::default()
+            });
+            instance.execute()
+        };
+
+        // Writing a bid and an offer, then scratch memory in arr13
+        let two_arrays = "movimm r0 9
+            movimm r1 1
+            arrins r1 r0 r1
+            movimm r0 10
+            arrins r1 r0 r1";
+        assert_eq!(execute(two_arrays), Ok(()));
+        let three_arrays = format!("{}\nmovimm r0 13\narrins r1 r0 r1", two_arrays);
+        assert_eq!(execute(&three_arrays), Err(Error::MemoryLimit));
+        // The parameters
+        assert_eq!(
+            execute("movimm r0 0\narrins r0 r0 r0"),
+            Err(Error::ReadOnlyArray)
+        );
+    }
+
+    #[test]
+    fn result_orders_keep_product_id() {
+        // Orders kept from the book and orders the program places are both for the book's product
+        let book: Book = serde_json::from_str(
+            r#"{"product_id": 7, "orders": [
+                {"participant": 0, "side": "bid", "price": 5, "quantity": 10},
+                {"participant": 0, "side": "offer", "price": 8, "quantity": 10}
+            ]}"#,
+        )
+        .unwrap();
+        let program = vm::Program::from_instructions(&[]);
+        let mut instance = ProgramInstance::new(
+            &program,
+            &book,
+            ParticipantId(0),
+            &ParticipantParameters::default(),
+        );
+        instance
+            .vm_program_instance
+            .state_mut()
+            .array_insert(10, 9, 4);
+
+        let mut result_book = Book::new(ProductId(7));
+        instance
+            .write_result_into_book(&book, &mut result_book, ParticipantId(0))
+            .unwrap();
+        let product_ids: Vec<ProductId> = result_book
+            .levels
+            .values()
+            .flat_map(|level| &level.orders)
+            .map(|order| order.product_id)
+            .collect();
+        assert_eq!(product_ids, vec![ProductId(7); 3]);
+    }
+
+    #[test]
+    fn widely_separated_prices() {
+        let book: Book = serde_json::from_str(
+            r#"{"product_id": 0, "orders": [
+                {"participant": 0, "side": "bid", "price": 1, "quantity": 10},
+                {"participant": 0, "side": "bid", "price": 1099511627776, "quantity": 20}
+            ]}"#,
+        )
+        .unwrap();
+        let program = vm::Program::from_instructions(&[]);
+        let instance = ProgramInstance::new(
+            &program,
+            &book,
+            ParticipantId(0),
+            &ParticipantParameters::default(),
+        );
+        let state = instance.vm_program_instance.state();
+        assert_eq!(state.array_read(1, 1), 1 << 40);
+        for arr in [2, 6] {
+            let mut values: Vec<(u64, i64)> = state.iter_touched_values(arr).collect();
+            values.sort_unstable();
+            assert_eq!(values, vec![(1, 10), (1 << 40, 20)]);
+        }
+
+        let mut result_book = Book::new(ProductId(0));
+        instance
+            .write_result_into_book(&book, &mut result_book, ParticipantId(0))
+            .unwrap();
+        assert_eq!(
+            result_book.bid_quantities_for_participant(ParticipantId(0)),
+            book.bid_quantities_for_participant(ParticipantId(0))
+        );
+        assert_eq!(result_book.levels.len(), 2);
+    }
+
     #[test]
     fn write_result_into_book() {
         let program = vm::Program::from_instructions(&[]);
@@ -1,10 +1,15 @@
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::auction::{Side, Trade};
+use crate::clock::Timestamp;
 use crate::participant::ParticipantId;
 use crate::{Price, ProductId};
 
+/// Serialized as a snapshot of its orders, see `doc/vm.md`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "BookSnapshot", from = "BookSnapshot")]
 pub struct Book {
     pub(super) product_id: ProductId,
     pub(super) levels: HashMap<Price, Level>,
@@ -18,7 +23,11 @@ impl Book {
         }
     }
 
-    pub fn do_matching(&mut self) -> Vec<Trade> {
+    pub fn product_id(&self) -> ProductId {
+        self.product_id
+    }
+
+    pub fn do_matching(&mut self, timestamp: Timestamp) -> Vec<Trade> {
         let bid_bounds = self.bid_bounds();
         let offer_bounds = self.offer_bounds();
         if bid_bounds.is_none() || offer_bounds.is_none() {
@@ -64,6 +73,7 @@ impl Book {
                     product_id: self.product_id,
                     quantity: matched_quantity as u64,
                     side: Side::Bid,
+                    timestamp,
                 });
                 bid_order.quantity -= matched_quantity;
                 bid_quantity_to_exhaust -= matched_quantity;
@@ -88,6 +98,7 @@ impl Book {
                     product_id: self.product_id,
                     quantity: matched_quantity as u64,
                     side: Side::Offer,
+                    timestamp,
                 });
                 offer_order.quantity -= matched_quantity;
                 offer_quantity_to_exhaust -= matched_quantity;
@@ -115,7 +126,7 @@ impl Book {
                 .position(|possible_match| possible_match.participant == order.participant)
         });
         if let Some(existing_order_idx) = found_existing_idx {
-            let mut existing_order = self
+            let existing_order = self
                 .levels
                 .get_mut(&order.price)
                 .unwrap()
@@ -138,6 +149,7 @@ impl Book {
     }
 
     pub fn insert_order(&mut self, order: Order) {
+        debug_assert_eq!(order.product_id, self.product_id);
         self.levels
             .entry(order.price)
             .or_default()
@@ -191,6 +203,25 @@ impl Book {
             .unwrap_or(0)
     }
 
+    /// Total quantity of the orders matching `predicate` at each price they rest at, leaving out
+    /// price 0 as `order_bounds` does
+    fn quantities_by_price<F>(&self, predicate: F) -> BTreeMap<Price, i64>
+    where
+        F: Fn(&&Order) -> bool,
+    {
+        let mut quantities: BTreeMap<Price, i64> = BTreeMap::default();
+        for order in self
+            .levels
+            .values()
+            .flat_map(|level| &level.orders)
+            .filter(&predicate)
+            .filter(|order| order.price != Price(0))
+        {
+            *quantities.entry(order.price).or_default() += order.quantity;
+        }
+        quantities
+    }
+
     pub fn bid_bounds(&self) -> Option<(Price, Price)> {
         self.order_bounds(|order| order.side == Side::Bid)
     }
@@ -240,6 +271,128 @@ impl Book {
             order.side == Side::Offer && order.participant == participant_id
         })
     }
+
+    pub fn bid_quantities(&self) -> BTreeMap<Price, i64> {
+        self.quantities_by_price(|order| order.side == Side::Bid)
+    }
+
+    pub fn offer_quantities(&self) -> BTreeMap<Price, i64> {
+        self.quantities_by_price(|order| order.side == Side::Offer)
+    }
+
+    pub fn bid_quantities_for_participant(
+        &self,
+        participant_id: ParticipantId,
+    ) -> BTreeMap<Price, i64> {
+        self.quantities_by_price(|order| {
+            order.side == Side::Bid && order.participant == participant_id
+        })
+    }
+
+    pub fn offer_quantities_for_participant(
+        &self,
+        participant_id: ParticipantId,
+    ) -> BTreeMap<Price, i64> {
+        self.quantities_by_price(|order| {
+            order.side == Side::Offer && order.participant == participant_id
+        })
+    }
+
+    /// Where the participant's resting quantity differs between this book and `after`, bids
+    /// before offers and in price order
+    pub fn changes_for_participant(
+        &self,
+        after: &Book,
+        participant_id: ParticipantId,
+    ) -> Vec<OrderChange> {
+        let mut quantities: BTreeMap<(Side, Price), (i64, i64)> = BTreeMap::default();
+        for (book, is_after) in [(self, false), (after, true)].iter() {
+            for order in book
+                .levels
+                .values()
+                .flat_map(|level| &level.orders)
+                .filter(|order| order.participant == participant_id)
+            {
+                let (before, after) = quantities.entry((order.side, order.price)).or_default();
+                if *is_after {
+                    *after += order.quantity;
+                } else {
+                    *before += order.quantity;
+                }
+            }
+        }
+        quantities
+            .into_iter()
+            .filter(|(_key, (before, after))| before != after)
+            .map(|((side, price), (before, after))| OrderChange {
+                side,
+                price,
+                before,
+                after,
+            })
+            .collect()
+    }
+}
+
+/// A participant's resting quantity at one price, before and after its program ran
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct OrderChange {
+    pub side: Side,
+    pub price: Price,
+    pub before: i64,
+    pub after: i64,
+}
+
+/// Orders in price order, and in queue order within a level
+#[derive(Serialize, Deserialize)]
+struct BookSnapshot {
+    product_id: ProductId,
+    orders: Vec<OrderSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OrderSnapshot {
+    participant: ParticipantId,
+    side: Side,
+    price: Price,
+    quantity: i64,
+}
+
+impl From<Book> for BookSnapshot {
+    fn from(book: Book) -> Self {
+        let mut levels: Vec<(Price, Level)> = book.levels.into_iter().collect();
+        levels.sort_by_key(|(price, _level)| *price);
+        let orders = levels
+            .into_iter()
+            .flat_map(|(_price, level)| level.orders)
+            .map(|order| OrderSnapshot {
+                participant: order.participant,
+                side: order.side,
+                price: order.price,
+                quantity: order.quantity,
+            })
+            .collect();
+        Self {
+            product_id: book.product_id,
+            orders,
+        }
+    }
+}
+
+impl From<BookSnapshot> for Book {
+    fn from(snapshot: BookSnapshot) -> Self {
+        let mut book = Book::new(snapshot.product_id);
+        for order in snapshot.orders {
+            book.insert_order(Order {
+                participant: order.participant,
+                product_id: snapshot.product_id,
+                side: order.side,
+                quantity: order.quantity,
+                price: order.price,
+            });
+        }
+        book
+    }
 }
 
 #[derive(Clone, Default)]
@@ -247,7 +400,7 @@ pub(super) struct Level {
     pub(super) orders: Vec<Order>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Order {
     pub(super) participant: ParticipantId,
     pub(super) product_id: ProductId,
@@ -574,7 +727,7 @@ mod tests {
             levels,
         };
 
-        let trades = book.do_matching();
+        let trades = book.do_matching(Timestamp(0));
         assert_eq!(trades.len(), 2);
         assert_eq!(trades.get(0).unwrap().quantity, 20);
         assert_eq!(trades.get(0).unwrap().price, Price(1));
@@ -620,7 +773,7 @@ mod tests {
             levels,
         };
 
-        let trades = book.do_matching();
+        let trades = book.do_matching(Timestamp(0));
         assert_eq!(trades.len(), 0);
     }
 
@@ -655,7 +808,7 @@ mod tests {
             levels,
         };
 
-        let trades = book.do_matching();
+        let trades = book.do_matching(Timestamp(0));
         assert_eq!(trades.len(), 2);
         assert_eq!(trades.get(0).unwrap().quantity, 20);
         assert_eq!(trades.get(1).unwrap().quantity, 20);
@@ -699,7 +852,7 @@ mod tests {
             levels,
         };
 
-        let trades = book.do_matching();
+        let trades = book.do_matching(Timestamp(0));
         assert_eq!(trades.len(), 3);
         let buys: Vec<_> = trades.iter().filter(|t| t.side == Side::Bid).collect();
         assert_eq!(buys.len(), 1);
@@ -746,7 +899,7 @@ mod tests {
             levels,
         };
 
-        let trades = book.do_matching();
+        let trades = book.do_matching(Timestamp(0));
         assert_eq!(trades.len(), 2);
         assert!(trades.iter().all(|t| t.quantity == 20));
         assert!(trades.iter().all(|t| t.price == Price(2)));
@@ -788,7 +941,7 @@ mod tests {
             levels,
         };
 
-        let trades = book.do_matching();
+        let trades = book.do_matching(Timestamp(0));
         assert_eq!(
             trades.iter().find(|t| t.side == Side::Bid).unwrap().price,
             Price(3)
@@ -798,4 +951,32 @@ mod tests {
             Price(2)
         );
     }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let json = r#"{
+            "product_id": 3,
+            "orders": [
+                {"participant": 1, "side": "offer", "price": 105, "quantity": 4},
+                {"participant": 0, "side": "bid", "price": 100, "quantity": 10},
+                {"participant": 2, "side": "bid", "price": 100, "quantity": 5}
+            ]
+        }"#;
+        let book: Book = serde_json::from_str(json).unwrap();
+        assert_eq!(book.product_id, ProductId(3));
+        assert_eq!(book.bid_bounds(), Some((Price(100), Price(100))));
+        assert_eq!(book.bid_quantity_at_price(Price(100)), 15);
+        assert_eq!(
+            book.offer_quantity_at_price_for_participant(Price(105), ParticipantId(1)),
+            4
+        );
+
+        let value = serde_json::to_value(&book).unwrap();
+        let orders = &value["orders"];
+        assert_eq!(orders[0]["participant"], 0);
+        assert_eq!(orders[1]["participant"], 2);
+        assert_eq!(orders[2]["side"], "offer");
+        let round_tripped: Book = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&round_tripped).unwrap(), value);
+    }
 }
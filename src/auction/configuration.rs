@@ -1,6 +1,33 @@
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::ProductId;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AuctionConfiguration {
     pub num_bidding_rounds: u64,
     pub auction_interval_seconds: u64,
+    /// Products open for trading.  When empty, a book is created for any product a participant
+    /// submits a program for.
+    pub products: Vec<ProductConfiguration>,
+    pub fees: FeeSchedule,
+    pub risk: RiskLimits,
+    /// Threads running participants' programs each bidding round, by default one per CPU.  The
+    /// books are the same however many there are.
+    pub program_threads: u64,
+}
+
+impl AuctionConfiguration {
+    pub fn auction_interval(&self) -> Duration {
+        Duration::from_secs(self.auction_interval_seconds)
+    }
+
+    pub fn is_product_listed(&self, product_id: ProductId) -> bool {
+        self.products.is_empty() || self.products.iter().any(|p| p.id == product_id)
+    }
 }
 
 impl Default for AuctionConfiguration {
@@ -8,6 +35,54 @@ impl Default for AuctionConfiguration {
         Self {
             num_bidding_rounds: 5,
             auction_interval_seconds: 1,
+            products: Vec::default(),
+            fees: FeeSchedule::default(),
+            risk: RiskLimits::default(),
+            program_threads: thread::available_parallelism().map_or(1, |n| n.get() as u64),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProductConfiguration {
+    pub id: ProductId,
+    pub name: String,
+}
+
+/// Amounts charged to a participant, accumulated by the engine
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub program_submission: u64,
+    pub parameter_update: u64,
+    /// Charged per unit of gas consumed by a participant's program each bidding round
+    pub gas_price: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskLimits {
+    /// Largest quantity a program may leave resting at a single price
+    pub max_order_quantity: u64,
+    /// Gas available to a program each bidding round before it is halted
+    pub max_gas_per_execution: u64,
+    /// Entries other than zero a program may leave in its storage, see `ProgramInstance`
+    pub max_storage_entries: u64,
+    /// Arrays a program may write to, including its outputs and storage
+    pub max_arrays: u64,
+    /// Indexes a program may write in any one array
+    pub max_array_entries: u64,
+}
+
+impl Default for RiskLimits {
+    fn default() -> Self {
+        Self {
+            max_order_quantity: 1_000_000,
+            max_gas_per_execution: 100_000,
+            max_storage_entries: 1024,
+            max_arrays: 16,
+            max_array_entries: 4096,
         }
     }
 }
@@ -2,19 +2,33 @@ mod bidding_program;
 mod book;
 mod configuration;
 
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
+use crate::clock::Timestamp;
 use crate::participant::ParticipantId;
 use crate::protocol::ClientDirective;
 use crate::vm::Program;
 use crate::{Price, ProductId};
 pub use bidding_program::ProgramInstance;
-pub use book::{Book, Order};
-pub use configuration::AuctionConfiguration;
+pub use book::{Book, Order, OrderChange};
+pub use configuration::{AuctionConfiguration, FeeSchedule, ProductConfiguration, RiskLimits};
 
 #[derive(Debug)]
 pub enum Error {
     SelfMatching,
+    RiskLimit,
+    OutOfGas,
+    /// The program did something it may not, such as writing to one of its inputs
+    ProgramFault,
+    UnknownProduct(ProductId),
+    /// A `Join` from a participant which has already joined
+    AlreadyJoined,
+    /// Anything but `Join` from a participant which hasn't joined
+    NotJoined,
 }
 
 impl std::fmt::Display for Error {
@@ -27,41 +41,86 @@ impl From<bidding_program::Error> for Error {
     fn from(e: bidding_program::Error) -> Self {
         match e {
             bidding_program::Error::SelfCrossing => Self::SelfMatching,
+            bidding_program::Error::OrderQuantityLimit
+            | bidding_program::Error::StorageLimit
+            | bidding_program::Error::MemoryLimit
+            | bidding_program::Error::StackOverflow => Self::RiskLimit,
+            bidding_program::Error::ReadOnlyArray
+            | bidding_program::Error::StackUnderflow
+            | bidding_program::Error::DivideByZero => Self::ProgramFault,
+            bidding_program::Error::OutOfGas => Self::OutOfGas,
         }
     }
 }
 
-#[derive(Default, Clone)]
+/// Serialized as a map from parameter index to value
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct ParticipantParameters {
     values: HashMap<u64, i64>,
 }
 
+/// Values a participant's program keeps from one bidding round to the next, in arr12.  Serialized
+/// as a map from index to value.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProgramStorage {
+    values: HashMap<u64, i64>,
+}
+
+impl ProgramStorage {
+    pub fn get(&self, idx: u64) -> i64 {
+        self.values.get(&idx).copied().unwrap_or(0)
+    }
+
+    /// Entries other than zero, which every unwritten index reads as
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
 #[derive(Default)]
 struct ParticipantRecord {
     interested_product_programs: HashMap<ProductId, Program>,
     interested_product_parameters: HashMap<ProductId, ParticipantParameters>,
+    /// Quantity bought minus quantity sold
+    positions: HashMap<ProductId, i64>,
+    /// Cleared when the product's program is replaced
+    storage: HashMap<ProductId, ProgramStorage>,
+    /// Stops at `u64::MAX` rather than wrapping
+    fees_owed: u64,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Trade {
     pub product_id: ProductId,
     pub participant_id: ParticipantId,
     pub side: Side,
     pub price: Price,
     pub quantity: u64,
+    pub timestamp: Timestamp,
 }
 
+/// Books and participants are kept in order of their ids, which is the order books are matched and
+/// participants' orders join each level.  Pro-rata matching rounds in favour of the first orders
+/// at a price, so this makes every run of the same auctions trade the same.
 pub struct Engine {
     configuration: AuctionConfiguration,
-    product_books: HashMap<ProductId, Book>,
-    participants: HashMap<ParticipantId, ParticipantRecord>,
+    product_books: BTreeMap<ProductId, Book>,
+    participants: BTreeMap<ParticipantId, ParticipantRecord>,
 }
 
 impl Engine {
     pub fn new(configuration: AuctionConfiguration) -> Self {
+        let product_books = configuration
+            .products
+            .iter()
+            .map(|product| (product.id, Book::new(product.id)))
+            .collect();
         Self {
             configuration,
-            product_books: HashMap::default(),
-            participants: HashMap::default(),
+            product_books,
+            participants: BTreeMap::default(),
         }
     }
 
@@ -69,106 +128,265 @@ impl Engine {
         &mut self,
         participant_id: ParticipantId,
         directive: &ClientDirective,
-    ) {
+    ) -> Result<(), Error> {
         match directive {
+            ClientDirective::Hello { .. }
+            | ClientDirective::Login { .. }
+            | ClientDirective::Resume { .. } => {
+                // Sessions are handled by the server before directives reach the engine
+            }
             ClientDirective::Join {} => {
+                if self.participants.contains_key(&participant_id) {
+                    return Err(Error::AlreadyJoined);
+                }
                 self.participants
-                    .insert(participant_id, ParticipantRecord::default())
-                    .and_then::<(), _>(|_existing_record| panic!("Multiple joins (TODO)"));
+                    .insert(participant_id, ParticipantRecord::default());
             }
             ClientDirective::Leave {} => {
+                self.participants
+                    .remove(&participant_id)
+                    .ok_or(Error::NotJoined)?;
                 self.remove_participant_orders(participant_id);
-                self.participants.remove(&participant_id).expect("TODO");
             }
             ClientDirective::SubmitProgram {
                 product_id,
                 program,
             } => {
+                if !self.configuration.is_product_listed(*product_id) {
+                    return Err(Error::UnknownProduct(*product_id));
+                }
                 self.product_books
                     .entry(*product_id)
                     .or_insert(Book::new(*product_id));
-                self.participants
+                let participant_record = self
+                    .participants
                     .get_mut(&participant_id)
-                    .expect("TODO")
+                    .ok_or(Error::NotJoined)?;
+                participant_record
                     .interested_product_programs
                     .insert(*product_id, program.clone());
+                participant_record.storage.remove(product_id);
+                participant_record.fees_owed = participant_record
+                    .fees_owed
+                    .saturating_add(self.configuration.fees.program_submission);
             }
             ClientDirective::UpdateParameter {
                 product_id,
                 param_idx,
                 value,
             } => {
-                self.participants
+                if !self.configuration.is_product_listed(*product_id) {
+                    return Err(Error::UnknownProduct(*product_id));
+                }
+                let participant_record = self
+                    .participants
                     .get_mut(&participant_id)
-                    .expect("TODO")
+                    .ok_or(Error::NotJoined)?;
+                participant_record
                     .interested_product_parameters
                     .entry(*product_id)
                     .or_default()
                     .values
                     .insert(*param_idx, *value);
+                participant_record.fees_owed = participant_record
+                    .fees_owed
+                    .saturating_add(self.configuration.fees.parameter_update);
             }
         }
+        Ok(())
+    }
+
+    pub fn fees_owed(&self, participant_id: ParticipantId) -> Option<u64> {
+        self.participants
+            .get(&participant_id)
+            .map(|record| record.fees_owed)
+    }
+
+    pub fn position(&self, participant_id: ParticipantId, product_id: ProductId) -> Option<i64> {
+        self.participants.get(&participant_id).map(|record| {
+            record
+                .positions
+                .get(&product_id)
+                .copied()
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn storage(
+        &self,
+        participant_id: ParticipantId,
+        product_id: ProductId,
+    ) -> Option<ProgramStorage> {
+        self.participants
+            .get(&participant_id)
+            .map(|record| record.storage.get(&product_id).cloned().unwrap_or_default())
     }
 
     pub fn config(&self) -> &AuctionConfiguration {
         &self.configuration
     }
 
-    pub fn match_all_books(&mut self) -> Vec<Trade> {
-        self.product_books
+    pub fn match_all_books(&mut self, timestamp: Timestamp) -> Vec<Trade> {
+        let trades: Vec<Trade> = self
+            .product_books
             .iter_mut()
-            .map(|(_product_id, book)| book.do_matching())
+            .map(|(_product_id, book)| book.do_matching(timestamp))
             .flatten()
-            .collect()
+            .collect();
+        for trade in &trades {
+            if let Some(record) = self.participants.get_mut(&trade.participant_id) {
+                let position = record.positions.entry(trade.product_id).or_default();
+                match trade.side {
+                    Side::Bid => *position += trade.quantity as i64,
+                    Side::Offer => *position -= trade.quantity as i64,
+                }
+            }
+        }
+        trades
     }
 
-    pub fn step_all_books_one_auction(&mut self) {
+    pub fn book(&self, product_id: ProductId) -> Option<&Book> {
+        self.product_books.get(&product_id)
+    }
+
+    /// Returns the programs which failed, each with its first error of the auction
+    pub fn step_all_books_one_auction(&mut self) -> Vec<(ParticipantId, ProductId, Error)> {
+        let mut failures: BTreeMap<(ParticipantId, ProductId), Error> = BTreeMap::default();
         for _ in 0..self.configuration.num_bidding_rounds {
-            for product_id in self.product_books.keys().cloned().collect::<Vec<_>>() {
-                self.step_book_one_round(product_id);
+            for (participant_id, product_id, error) in self.step_all_books_one_round() {
+                failures
+                    .entry((participant_id, product_id))
+                    .or_insert(error);
             }
         }
+        failures
+            .into_iter()
+            .map(|((participant_id, product_id), error)| (participant_id, product_id, error))
+            .collect()
     }
 
-    fn step_book_one_round(&mut self, product_id: ProductId) {
-        let prev_book = self
-            .product_books
-            .remove(&product_id)
-            .expect("Missing product ID, TODO");
-        let mut result_book = Book::new(product_id);
-
-        let interested_participant_ids = self.participants.iter().filter_map(|(id, record)| {
-            if record.interested_product_programs.contains_key(&product_id) {
-                Some(id)
-            } else {
-                None
+    /// Runs every interested participant's program against every book, then replaces the books
+    /// with the orders the programs left.  Programs run on `program_threads` threads, but their
+    /// results are applied in the order they're listed, so the books are the same however many
+    /// threads there are.  Returns the programs which failed, whose orders are dropped.
+    fn step_all_books_one_round(&mut self) -> Vec<(ParticipantId, ProductId, Error)> {
+        let product_ids: Vec<ProductId> = self.product_books.keys().cloned().collect();
+        let programs: Vec<(ProductId, ParticipantId)> = product_ids
+            .iter()
+            .flat_map(|product_id| {
+                self.participants
+                    .iter()
+                    .filter(move |(_id, record)| {
+                        record.interested_product_programs.contains_key(product_id)
+                    })
+                    .map(move |(id, _record)| (*product_id, *id))
+            })
+            .collect();
+        let outcomes = self.run_programs(&programs);
+
+        let mut result_books: BTreeMap<ProductId, Book> = product_ids
+            .iter()
+            .map(|product_id| (*product_id, Book::new(*product_id)))
+            .collect();
+        let mut failures: Vec<(ParticipantId, ProductId, Error)> = Vec::default();
+        for ((product_id, participant_id), (gas_used, result)) in programs.iter().zip(outcomes) {
+            let participant_record = self
+                .participants
+                .get_mut(participant_id)
+                .expect("programs are only listed for participants");
+            // Both come from the configuration file, which doesn't bound their product
+            let gas_fee = gas_used.saturating_mul(self.configuration.fees.gas_price);
+            participant_record.fees_owed = participant_record.fees_owed.saturating_add(gas_fee);
+            match result {
+                Ok((orders, storage)) => {
+                    let result_book = result_books
+                        .get_mut(product_id)
+                        .expect("programs are only listed for books");
+                    for order in orders
+                        .levels
+                        .into_iter()
+                        .flat_map(|(_price, level)| level.orders)
+                    {
+                        result_book.update_or_insert_order(order);
+                    }
+                    participant_record.storage.insert(*product_id, storage);
+                }
+                Err(e) => failures.push((*participant_id, *product_id, e)),
             }
-        });
-        for &participant_id in interested_participant_ids {
-            self.apply_participant_program_to_book(
-                participant_id,
-                product_id,
-                &prev_book,
-                &mut result_book,
-            )
-            .unwrap_or(());
-            // TODO handle self matching
         }
-        self.product_books.insert(product_id, result_book);
+        self.product_books = result_books;
+        failures
     }
 
+    /// The outcome of each program, in the order given, see `apply_participant_program_to_book`.
+    /// A program whose run panics faults, leaving the others' outcomes alone.
+    fn run_programs(
+        &self,
+        programs: &[(ProductId, ParticipantId)],
+    ) -> Vec<(u64, Result<(Book, ProgramStorage), Error>)> {
+        let run = |(product_id, participant_id): &(ProductId, ParticipantId)| {
+            fault_on_panic(|| {
+                let prev_book = self
+                    .product_books
+                    .get(product_id)
+                    .expect("programs are only listed for books");
+                self.apply_participant_program_to_book(*participant_id, *product_id, prev_book)
+            })
+        };
+        let num_threads = (self.configuration.program_threads as usize).min(programs.len());
+        if num_threads <= 1 {
+            return programs.iter().map(run).collect();
+        }
+
+        // Each thread takes the next program until there are none left, so a slow program
+        // doesn't hold up the others
+        let next_program = AtomicUsize::new(0);
+        let mut outcomes: Vec<Option<_>> = programs.iter().map(|_| None).collect();
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut outcomes = Vec::default();
+                        loop {
+                            let idx = next_program.fetch_add(1, Ordering::Relaxed);
+                            match programs.get(idx) {
+                                Some(program) => outcomes.push((idx, run(program))),
+                                None => return outcomes,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            // A worker which died anyway leaves its programs without outcomes, faulting them below
+            for worker_outcomes in workers.into_iter().filter_map(|worker| worker.join().ok()) {
+                for (idx, outcome) in worker_outcomes {
+                    outcomes[idx] = Some(outcome);
+                }
+            }
+        });
+        outcomes
+            .into_iter()
+            .map(|outcome| outcome.unwrap_or((0, Err(Error::ProgramFault))))
+            .collect()
+    }
+
+    /// Returns the gas consumed by the participant's program along with the outcome of applying
+    /// its output, which on success is the participant's orders and the storage to keep for the
+    /// next round
     fn apply_participant_program_to_book(
         &self,
         participant_id: ParticipantId,
         product_id: ProductId,
         prev_book: &Book,
-        result_book: &mut Book,
-    ) -> Result<(), Error> {
-        let participant_record = self.participants.get(&participant_id).expect("TODO");
+    ) -> (u64, Result<(Book, ProgramStorage), Error>) {
+        let participant_record = self
+            .participants
+            .get(&participant_id)
+            .expect("programs are only listed for participants");
         let participant_program = participant_record
             .interested_product_programs
             .get(&product_id)
-            .expect("TODO");
+            .expect("programs are only listed for products the participant has one for");
         let participant_parameters = participant_record
             .interested_product_parameters
             .get(&product_id)
@@ -180,10 +398,25 @@ impl Engine {
             participant_id,
             &participant_parameters,
         );
-        program_instance.execute();
-        program_instance
-            .write_result_into_book(prev_book, result_book, participant_id)
-            .map_err(Error::from)
+        program_instance.set_risk_limits(&self.configuration.risk);
+        program_instance.set_position(
+            participant_record
+                .positions
+                .get(&product_id)
+                .copied()
+                .unwrap_or_default(),
+        );
+        if let Some(storage) = participant_record.storage.get(&product_id) {
+            program_instance.set_storage(storage);
+        }
+        let mut orders = Book::new(product_id);
+        let result = program_instance
+            .execute()
+            .and_then(|()| {
+                program_instance.write_result_into_book(prev_book, &mut orders, participant_id)
+            })
+            .map(|()| (orders, program_instance.storage()));
+        (program_instance.gas_used(), result.map_err(Error::from))
     }
 
     fn remove_participant_orders(&mut self, participant_id: ParticipantId) {
@@ -195,7 +428,8 @@ impl Engine {
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Side {
     Bid,
     Offer,
@@ -206,3 +440,58 @@ impl std::fmt::Display for Side {
         write!(f, "{:?}", self)
     }
 }
+
+/// Runs one participant's program, faulting it instead of taking the engine down if it panics
+fn fault_on_panic<F>(run: F) -> (u64, Result<(Book, ProgramStorage), Error>)
+where
+    F: FnOnce() -> (u64, Result<(Book, ProgramStorage), Error>),
+{
+    panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or((0, Err(Error::ProgramFault)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn membership_misuse_rejected() {
+        let mut engine = Engine::new(AuctionConfiguration::default());
+        let participant_id = ParticipantId(1);
+        let program = Program::from_instructions(&[]);
+        for directive in &[
+            ClientDirective::Leave {},
+            ClientDirective::SubmitProgram {
+                product_id: ProductId(1),
+                program,
+            },
+            ClientDirective::UpdateParameter {
+                product_id: ProductId(1),
+                param_idx: 0,
+                value: 1,
+            },
+        ] {
+            assert!(matches!(
+                engine.apply_participant_directive(participant_id, directive),
+                Err(Error::NotJoined)
+            ));
+        }
+
+        let join = ClientDirective::Join {};
+        assert!(engine
+            .apply_participant_directive(participant_id, &join)
+            .is_ok());
+        assert!(matches!(
+            engine.apply_participant_directive(participant_id, &join),
+            Err(Error::AlreadyJoined)
+        ));
+    }
+
+    #[test]
+    fn panicking_program_faults() {
+        let outcome = fault_on_panic(|| panic!("interpreter bug"));
+        assert!(matches!(outcome, (0, Err(Error::ProgramFault))));
+
+        let outcome = fault_on_panic(|| (3, Err(Error::OutOfGas)));
+        assert!(matches!(outcome, (3, Err(Error::OutOfGas))));
+    }
+}
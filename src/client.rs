@@ -0,0 +1,681 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::participant::{Participant, ParticipantId};
+use crate::protocol::{
+    self, Capability, ClientDirective, ClientNotification, Encoding, PROTOCOL_VERSION,
+};
+use crate::server::framing::{self, FrameDecoder, Framing, DEFAULT_MAX_FRAME_SIZE};
+use crate::vm::{self, Program};
+use crate::ProductId;
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    Net(io::Error),
+    /// The exchange closed the connection
+    Closed,
+    TimedOut,
+    Framing(framing::Error),
+    Protocol(protocol::Error),
+    InvalidProgram(vm::Error),
+    /// The exchange refused the login, with its reason
+    Rejected(String),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Net(e)
+    }
+}
+
+impl From<framing::Error> for Error {
+    fn from(e: framing::Error) -> Self {
+        Error::Framing(e)
+    }
+}
+
+impl From<protocol::Error> for Error {
+    fn from(e: protocol::Error) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+/// How to talk to the exchange, which must match its `ServerConfig::framing`
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub framing: Framing,
+    /// Asked for in the `Hello` sent on connecting.  `Encoding::Binary` needs
+    /// `Framing::LengthPrefixed`, since binary messages may contain newlines.
+    pub encoding: Encoding,
+    pub capabilities: Vec<Capability>,
+    pub max_frame_size: usize,
+    /// How long `login` waits for the exchange, which only handles directives at auction time
+    pub reply_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            framing: Framing::default(),
+            encoding: Encoding::Json,
+            capabilities: vec![Capability::Replay],
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            reply_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A participant's connection to the exchange.
+///
+/// Directives are sent as soon as they are called for and are applied by the exchange at its next
+/// auction.  Notifications are read as they are asked for, with `next_notification`,
+/// `poll_notifications`, `notifications` or `run`, and arrive with their `Sequenced` envelope
+/// removed; `last_sequence` keeps the latest number for `resume` on a later connection.
+pub struct Client {
+    config: ClientConfig,
+    stream: ClientStream,
+    decoder: FrameDecoder,
+    pending_notifications: VecDeque<ClientNotification>,
+    last_sequence: u64,
+    participant_id: Option<ParticipantId>,
+    /// The exchange's answer to our `Hello`, once it has arrived
+    server_hello: Option<(u32, Vec<Capability>)>,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: ClientConfig) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Self::with_stream(ClientStream::Tcp(stream), config)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P, config: ClientConfig) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path)?;
+        Self::with_stream(ClientStream::Unix(stream), config)
+    }
+
+    /// Sends the `Hello` without waiting for the answer: the exchange replies to everything after
+    /// it in the requested encoding anyway
+    fn with_stream(stream: ClientStream, config: ClientConfig) -> Result<Self, Error> {
+        let decoder = FrameDecoder::new(config.framing, config.max_frame_size);
+        let mut client = Self {
+            config,
+            stream,
+            decoder,
+            pending_notifications: VecDeque::default(),
+            last_sequence: 0,
+            participant_id: None,
+            server_hello: None,
+        };
+        client.send(&ClientDirective::Hello {
+            version: PROTOCOL_VERSION,
+            encoding: client.config.encoding,
+            capabilities: client.config.capabilities.clone(),
+        })?;
+        Ok(client)
+    }
+
+    /// Set once logged in, see `login`
+    pub fn participant_id(&self) -> Option<ParticipantId> {
+        self.participant_id
+    }
+
+    /// The number of the latest notification received
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// The protocol version agreed with the exchange, once its `Hello` has been received
+    pub fn protocol_version(&self) -> Option<u32> {
+        self.server_hello
+            .as_ref()
+            .map(|(version, _capabilities)| *version)
+    }
+
+    /// Whether the exchange has agreed to provide `capability`, `false` until its `Hello` has been
+    /// received
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match &self.server_hello {
+            Some((_version, capabilities)) => capabilities.contains(&capability),
+            None => false,
+        }
+    }
+
+    /// Authenticates as the named participant, waiting for the exchange's answer.  Notifications
+    /// received meanwhile are kept for later.
+    pub fn login(&mut self, name: &str, key: &str) -> Result<ParticipantId, Error> {
+        self.send(&ClientDirective::Login {
+            name: name.to_owned(),
+            key: key.to_owned(),
+        })?;
+        let deadline = Instant::now() + self.config.reply_timeout;
+        loop {
+            let reply =
+                self.pending_notifications
+                    .iter()
+                    .position(|notification| match notification {
+                        ClientNotification::LoggedIn { .. }
+                        | ClientNotification::Rejected { .. } => true,
+                        _ => false,
+                    });
+            if let Some(idx) = reply {
+                return match self.pending_notifications.remove(idx).expect("TODO") {
+                    ClientNotification::LoggedIn { participant_id, .. } => Ok(participant_id),
+                    ClientNotification::Rejected { reason } => Err(Error::Rejected(reason)),
+                    _ => unreachable!(),
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::TimedOut);
+            }
+            self.receive(Some(deadline - now))?;
+        }
+    }
+
+    /// Asks for the notifications after `last_sequence` again, typically `last_sequence()` of the
+    /// client whose connection was lost
+    pub fn resume(&mut self, last_sequence: u64) -> Result<(), Error> {
+        self.send(&ClientDirective::Resume { last_sequence })
+    }
+
+    pub fn join(&mut self) -> Result<(), Error> {
+        self.send(&ClientDirective::Join {})
+    }
+
+    pub fn leave(&mut self) -> Result<(), Error> {
+        self.send(&ClientDirective::Leave {})
+    }
+
+    pub fn submit_program(
+        &mut self,
+        product_id: ProductId,
+        program: &Program,
+    ) -> Result<(), Error> {
+        self.send(&ClientDirective::SubmitProgram {
+            product_id,
+            program: program.clone(),
+        })
+    }
+
+    /// Assembles `program` first, see `vm::Program::try_from_str`
+    pub fn submit_program_text(
+        &mut self,
+        product_id: ProductId,
+        program: &str,
+    ) -> Result<(), Error> {
+        let program = Program::try_from_str(program).map_err(Error::InvalidProgram)?;
+        self.send(&ClientDirective::SubmitProgram {
+            product_id,
+            program,
+        })
+    }
+
+    pub fn update_parameter(
+        &mut self,
+        product_id: ProductId,
+        param_idx: u64,
+        value: i64,
+    ) -> Result<(), Error> {
+        self.send(&ClientDirective::UpdateParameter {
+            product_id,
+            param_idx,
+            value,
+        })
+    }
+
+    /// Waits up to `timeout`, or for ever if `None`, for the next notification
+    pub fn next_notification(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ClientNotification>, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(notification) = self.pending_notifications.pop_front() {
+                return Ok(Some(notification));
+            }
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            self.receive(remaining)?;
+        }
+    }
+
+    /// The notifications which have arrived, without waiting for more
+    pub fn poll_notifications(&mut self) -> Result<Vec<ClientNotification>, Error> {
+        loop {
+            let buffered_len = self.decoder.buffered_len();
+            let received = self.receive(Some(Duration::from_secs(0)));
+            match received {
+                Ok(()) if self.decoder.buffered_len() != buffered_len => continue,
+                Ok(()) => break,
+                Err(Error::Closed) if !self.pending_notifications.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.pending_notifications.drain(..).collect())
+    }
+
+    /// Blocks for each notification in turn, ending when the exchange closes the connection
+    pub fn notifications(&mut self) -> Notifications<'_> {
+        Notifications { client: self }
+    }
+
+    /// Hands every notification to `participant` until the exchange closes the connection
+    pub fn run<P: Participant>(&mut self, participant: &mut P) -> Result<(), Error> {
+        for notification in self.notifications() {
+            participant.handle_notification(notification?);
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, directive: &ClientDirective) -> Result<(), Error> {
+        let bytes = self
+            .config
+            .encoding
+            .try_client_directive_to_bytes(directive)?;
+        self.stream.write_all(&self.config.framing.encode(&bytes))?;
+        Ok(())
+    }
+
+    /// Reads once, waiting up to `timeout` (not at all if zero, for ever if `None`), and decodes
+    /// whichever notifications are complete
+    fn receive(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        match timeout {
+            Some(timeout) if timeout == Duration::from_secs(0) => {
+                self.stream.set_nonblocking(true)?
+            }
+            _ => {
+                self.stream.set_nonblocking(false)?;
+                self.stream.set_read_timeout(timeout)?;
+            }
+        }
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => return Err(Error::Closed),
+            Ok(n) => self.decoder.push_bytes(&buffer[..n]),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::Net(e)),
+        }
+        while let Some(frame) = self.decoder.next_frame()? {
+            let notification = self.decode(&frame)?;
+            self.handle_notification(notification);
+        }
+        Ok(())
+    }
+
+    /// An exchange which doesn't know `Hello` answers in its own encoding, JSON by default
+    fn decode(&self, frame: &[u8]) -> Result<ClientNotification, Error> {
+        match self
+            .config
+            .encoding
+            .try_client_notification_from_bytes(frame)
+        {
+            Ok(notification) => Ok(notification),
+            Err(e) => Encoding::Json
+                .try_client_notification_from_bytes(frame)
+                .map_err(|_| Error::Protocol(e)),
+        }
+    }
+}
+
+impl Participant for Client {
+    /// Keeps track of the session and queues the notification for the reading methods
+    fn handle_notification(&mut self, notification: ClientNotification) {
+        match notification {
+            ClientNotification::Sequenced {
+                sequence,
+                notification,
+            } => {
+                self.last_sequence = sequence;
+                self.handle_notification(*notification);
+            }
+            ClientNotification::Hello {
+                version,
+                capabilities,
+                ..
+            } => {
+                self.server_hello = Some((version, capabilities));
+            }
+            ClientNotification::LoggedIn { participant_id, .. } => {
+                self.participant_id = Some(participant_id);
+                self.pending_notifications.push_back(notification);
+            }
+            _ => self.pending_notifications.push_back(notification),
+        }
+    }
+}
+
+/// See `Client::notifications`
+pub struct Notifications<'a> {
+    client: &'a mut Client,
+}
+
+impl<'a> Iterator for Notifications<'a> {
+    type Item = Result<ClientNotification, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.next_notification(None) {
+            Ok(notification) => notification.map(Ok),
+            Err(Error::Closed) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auction::Side;
+    use crate::clock::Timestamp;
+    use crate::participant::ParticipantPool;
+    use crate::protocol::json::JsonProtocol;
+    use crate::server::credentials::{Credentials, ParticipantCredentials};
+    use crate::server::tcp;
+    use crate::server::test_support::pop_directives_until;
+    use crate::server::{Server as ServerTrait, ServerConfig};
+    use crate::vm::Instruction;
+    use crate::Price;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    fn start_server(config: ServerConfig) -> tcp::Server<JsonProtocol> {
+        let mut server = tcp::Server::<JsonProtocol>::new(ServerConfig { port: 0, ..config });
+        server.start_listening().unwrap();
+        server
+    }
+
+    fn connect(server: &tcp::Server<JsonProtocol>, config: ClientConfig) -> Client {
+        Client::connect(server.local_addr().unwrap(), config).unwrap()
+    }
+
+    fn trade(quantity: u64) -> ClientNotification {
+        ClientNotification::Trade {
+            product_id: ProductId(0),
+            side: Side::Bid,
+            price: Price(100),
+            quantity,
+            timestamp: Timestamp(1_000),
+        }
+    }
+
+    /// Pops directives on another thread, as the exchange would, until the returned flag is set
+    fn pump_in_background(
+        mut server: tcp::Server<JsonProtocol>,
+    ) -> (
+        Arc<AtomicBool>,
+        thread::JoinHandle<tcp::Server<JsonProtocol>>,
+    ) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_pumping = stop.clone();
+        let handle = thread::spawn(move || {
+            while !stop_pumping.load(Ordering::SeqCst) {
+                server.pop_all_directives();
+                thread::sleep(Duration::from_millis(1));
+            }
+            server
+        });
+        (stop, handle)
+    }
+
+    #[test]
+    fn directives_sent() {
+        let mut server = start_server(ServerConfig::default());
+        let mut client = connect(&server, ClientConfig::default());
+        let program = Program::from_instructions(&[Instruction::Halt {}]);
+
+        client.join().unwrap();
+        client.submit_program(ProductId(1), &program).unwrap();
+        client.submit_program_text(ProductId(2), "halt").unwrap();
+        client.update_parameter(ProductId(1), 3, -4).unwrap();
+        client.leave().unwrap();
+        assert!(match client.submit_program_text(ProductId(1), "nonsense") {
+            Err(Error::InvalidProgram(_)) => true,
+            _ => false,
+        });
+
+        let directives = pop_directives_until(&mut server, |d| d.len() == 5);
+        let participant_id = ParticipantId(0);
+        assert_eq!(
+            directives,
+            vec![
+                (participant_id, ClientDirective::Join {}),
+                (
+                    participant_id,
+                    ClientDirective::SubmitProgram {
+                        product_id: ProductId(1),
+                        program: program.clone(),
+                    }
+                ),
+                (
+                    participant_id,
+                    ClientDirective::SubmitProgram {
+                        product_id: ProductId(2),
+                        program,
+                    }
+                ),
+                (
+                    participant_id,
+                    ClientDirective::UpdateParameter {
+                        product_id: ProductId(1),
+                        param_idx: 3,
+                        value: -4,
+                    }
+                ),
+                (participant_id, ClientDirective::Leave {}),
+            ]
+        );
+    }
+
+    #[test]
+    fn notifications_unwrapped_and_numbered() {
+        let mut server = start_server(ServerConfig::default());
+        let mut client = connect(&server, ClientConfig::default());
+        client.join().unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        server.push_notifications_to_all(&[(ParticipantId(0), trade(1))]);
+        assert_eq!(client.next_notification(TIMEOUT).unwrap(), Some(trade(1)));
+        assert_eq!(client.last_sequence(), 1);
+        assert_eq!(client.protocol_version(), Some(PROTOCOL_VERSION));
+        assert!(client.has_capability(Capability::Replay));
+        assert!(!client.has_capability(Capability::BookSnapshots));
+
+        assert_eq!(
+            client
+                .next_notification(Some(Duration::from_millis(10)))
+                .unwrap(),
+            None
+        );
+        assert_eq!(client.poll_notifications().unwrap(), vec![]);
+
+        server.push_notifications_to_all(&[
+            (ParticipantId(0), trade(2)),
+            (ParticipantId(0), trade(3)),
+        ]);
+        assert_eq!(client.next_notification(TIMEOUT).unwrap(), Some(trade(2)));
+        assert_eq!(client.next_notification(TIMEOUT).unwrap(), Some(trade(3)));
+        assert_eq!(client.last_sequence(), 3);
+    }
+
+    #[test]
+    fn binary_encoding_negotiated() {
+        let config = ServerConfig {
+            framing: Framing::LengthPrefixed,
+            ..ServerConfig::default()
+        };
+        let mut server = start_server(config);
+        let mut client = connect(
+            &server,
+            ClientConfig {
+                framing: Framing::LengthPrefixed,
+                encoding: Encoding::Binary,
+                ..ClientConfig::default()
+            },
+        );
+        client.join().unwrap();
+        client.submit_program_text(ProductId(0), "halt").unwrap();
+        pop_directives_until(&mut server, |d| d.len() == 2);
+
+        server.push_notifications_to_all(&[(ParticipantId(0), trade(1))]);
+        assert_eq!(client.next_notification(TIMEOUT).unwrap(), Some(trade(1)));
+    }
+
+    #[test]
+    fn login_waits_for_reply() {
+        let credentials = Credentials {
+            participants: vec![ParticipantCredentials {
+                name: "alice".to_owned(),
+                id: ParticipantId(7),
+                key: "secret".to_owned(),
+            }],
+        };
+        let mut server = tcp::Server::<JsonProtocol>::with_credentials(
+            ServerConfig {
+                port: 0,
+                ..ServerConfig::default()
+            },
+            credentials,
+        );
+        server.start_listening().unwrap();
+        let mut client = connect(&server, ClientConfig::default());
+        let (stop, pumping_thread) = pump_in_background(server);
+
+        assert!(match client.login("alice", "wrong") {
+            Err(Error::Rejected(_)) => true,
+            _ => false,
+        });
+        assert_eq!(client.participant_id(), None);
+        assert_eq!(client.login("alice", "secret").unwrap(), ParticipantId(7));
+        assert_eq!(client.participant_id(), Some(ParticipantId(7)));
+
+        stop.store(true, Ordering::SeqCst);
+        let mut server = pumping_thread.join().unwrap();
+        server.push_notifications_to_all(&[(ParticipantId(7), trade(1))]);
+        assert_eq!(client.next_notification(TIMEOUT).unwrap(), Some(trade(1)));
+    }
+
+    #[derive(Default)]
+    struct RecordingParticipant {
+        received_notifications: Vec<ClientNotification>,
+    }
+
+    impl Participant for RecordingParticipant {
+        fn handle_notification(&mut self, notification: ClientNotification) {
+            self.received_notifications.push(notification);
+        }
+    }
+
+    #[test]
+    fn run_until_connection_closed() {
+        let mut server = start_server(ServerConfig::default());
+        let mut client = connect(&server, ClientConfig::default());
+        client.join().unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        server.push_notifications_to_all(&[
+            (ParticipantId(0), trade(1)),
+            (ParticipantId(0), trade(2)),
+        ]);
+        assert_eq!(
+            client
+                .notifications()
+                .take(1)
+                .map(Result::unwrap)
+                .collect::<Vec<ClientNotification>>(),
+            vec![trade(1)]
+        );
+        server.push_notifications_to_all(&[(ParticipantId(0), trade(3))]);
+        server.stop_listening().unwrap();
+
+        let mut participant = RecordingParticipant::default();
+        client.run(&mut participant).unwrap();
+        assert_eq!(participant.received_notifications, vec![trade(2), trade(3)]);
+        assert!(
+            match client.join().and_then(|()| client.poll_notifications()) {
+                Err(_) => true,
+                Ok(_) => false,
+            }
+        );
+    }
+}
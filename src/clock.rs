@@ -0,0 +1,116 @@
+use std::ops::{Add, Sub};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds since the unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn from_duration_since_epoch(duration: Duration) -> Self {
+        Self(duration.as_nanos() as u64)
+    }
+
+    pub fn duration_since(&self, earlier: Timestamp) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Timestamp(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+impl Sub<Timestamp> for Timestamp {
+    type Output = Duration;
+
+    fn sub(self, rhs: Timestamp) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+pub trait Clock {
+    fn now(&self) -> Timestamp;
+}
+
+/// Wall clock time
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp::from_duration_since_epoch(since_epoch)
+    }
+}
+
+/// Clock which only moves when told to.  Clones share the same time, so a test can keep a handle
+/// while the exchange owns another.
+#[derive(Clone, Default)]
+pub struct SimulatedClock {
+    now: Arc<AtomicU64>,
+}
+
+impl SimulatedClock {
+    pub fn new(start: Timestamp) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(start.0)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, timestamp: Timestamp) {
+        self.now.store(timestamp.0, Ordering::SeqCst);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.now.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulated_clock_only_moves_when_advanced() {
+        let clock = SimulatedClock::new(Timestamp(100));
+        assert_eq!(clock.now(), Timestamp(100));
+        assert_eq!(clock.now(), Timestamp(100));
+
+        clock.advance(Duration::from_nanos(23));
+        assert_eq!(clock.now(), Timestamp(123));
+
+        clock.set(Timestamp(5));
+        assert_eq!(clock.now(), Timestamp(5));
+    }
+
+    #[test]
+    fn simulated_clock_clones_share_time() {
+        let clock = SimulatedClock::default();
+        let handle = clock.clone();
+        handle.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), Timestamp(1_000_000_000));
+    }
+
+    #[test]
+    fn timestamp_arithmetic() {
+        let t0 = Timestamp(1_000);
+        let t1 = t0 + Duration::from_micros(1);
+        assert_eq!(t1, Timestamp(2_000));
+        assert_eq!(t1 - t0, Duration::from_micros(1));
+        assert_eq!(t0 - t1, Duration::from_nanos(0));
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::auction::AuctionConfiguration;
+use crate::server::ServerConfig;
+
+/// Everything needed to run an exchange, as read from a TOML or JSON file.
+/// ```toml
+/// [auction]
+/// num_bidding_rounds = 5
+/// auction_interval_seconds = 1
+/// program_threads = 4
+///
+/// [[auction.products]]
+/// id = 1
+/// name = "WIDGET"
+///
+/// [auction.fees]
+/// program_submission = 10
+/// parameter_update = 1
+/// gas_price = 1
+///
+/// [auction.risk]
+/// max_order_quantity = 1000
+/// max_gas_per_execution = 10000
+/// max_storage_entries = 1024
+/// max_arrays = 16
+/// max_array_entries = 4096
+///
+/// [server]
+/// ip = "127.0.0.1"
+/// port = 8080
+/// mode = "evented" # or "threaded", or "websocket" for browser clients
+/// framing = "newline_delimited" # or "length_prefixed"
+/// max_frame_size = 1048576
+/// max_outbound_buffer = 4194304
+/// max_pending_messages = 1024
+/// credentials_file = "credentials.toml" # see `server::credentials`
+/// session_outbox_size = 1024
+/// unix_socket = "/run/vmx.sock" # instead of ip and port, see `server::unix`
+/// unix_socket_permissions = 0o660
+/// ```
+/// Omitted sections and fields take their default values.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Configuration {
+    pub auction: AuctionConfiguration,
+    pub server: ServerConfig,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    UnknownFormat {
+        path: PathBuf,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    Invalid(Vec<String>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read { path, source } => {
+                write!(f, "could not read {}: {}", path.display(), source)
+            }
+            Error::UnknownFormat { path } => {
+                write!(f, "{}: expected a .toml or .json file", path.display())
+            }
+            Error::Parse { path, message } => write!(f, "{}: {}", path.display(), message),
+            Error::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+impl Configuration {
+    /// Reads a configuration file, choosing the format from its extension.  The result is not
+    /// validated, so that overrides can be applied first.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        read_file(path)
+    }
+
+    pub fn from_toml_str(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json_str(s: &str) -> Result<Self, String> {
+        serde_json::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// Checks the values make sense together, reporting every problem found rather than the first
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems: Vec<String> = Vec::default();
+        let auction = &self.auction;
+
+        if auction.num_bidding_rounds == 0 {
+            problems.push("auction.num_bidding_rounds must be at least 1".to_owned());
+        }
+        if auction.auction_interval_seconds == 0 {
+            problems.push("auction.auction_interval_seconds must be at least 1".to_owned());
+        }
+        if auction.program_threads == 0 {
+            problems.push("auction.program_threads must be at least 1".to_owned());
+        }
+
+        let mut product_ids = HashSet::new();
+        let mut product_names = HashSet::new();
+        for product in &auction.products {
+            if !product_ids.insert(product.id) {
+                problems.push(format!(
+                    "auction.products: product id {} is listed more than once",
+                    product.id.0
+                ));
+            }
+            if product.name.trim().is_empty() {
+                problems.push(format!(
+                    "auction.products: product {} has an empty name",
+                    product.id.0
+                ));
+            } else if !product_names.insert(&product.name) {
+                problems.push(format!(
+                    "auction.products: product name \"{}\" is used more than once",
+                    product.name
+                ));
+            }
+        }
+
+        if auction.risk.max_order_quantity == 0 {
+            problems.push("auction.risk.max_order_quantity must be at least 1".to_owned());
+        }
+        if auction.risk.max_gas_per_execution == 0 {
+            problems.push("auction.risk.max_gas_per_execution must be at least 1".to_owned());
+        }
+        if auction.risk.max_arrays == 0 {
+            problems.push("auction.risk.max_arrays must be at least 1".to_owned());
+        }
+        if auction.risk.max_array_entries == 0 {
+            problems.push("auction.risk.max_array_entries must be at least 1".to_owned());
+        }
+        if auction.risk.max_storage_entries > auction.risk.max_array_entries {
+            problems.push(
+                "auction.risk.max_storage_entries can't be more than max_array_entries".to_owned(),
+            );
+        }
+
+        if self.server.max_frame_size == 0 {
+            problems.push("server.max_frame_size must be at least 1".to_owned());
+        }
+        if self.server.max_outbound_buffer == 0 {
+            problems.push("server.max_outbound_buffer must be at least 1".to_owned());
+        }
+        if self.server.max_pending_messages == 0 {
+            problems.push("server.max_pending_messages must be at least 1".to_owned());
+        }
+        if self.server.unix_socket_permissions > 0o777 {
+            problems.push(format!(
+                "server.unix_socket_permissions {:o} is not a file mode, e.g. 0o660",
+                self.server.unix_socket_permissions
+            ));
+        }
+        if self.server.ip.parse::<IpAddr>().is_err() {
+            problems.push(format!(
+                "server.ip \"{}\" is not an IP address",
+                self.server.ip
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid(problems))
+        }
+    }
+}
+
+/// Reads a TOML or JSON file, choosing the format from its extension
+pub(crate) fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let contents = fs::read_to_string(path).map_err(|source| Error::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    let parse_error = |message: String| Error::Parse {
+        path: path.to_owned(),
+        message,
+    };
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| parse_error(e.to_string())),
+        Some("json") => serde_json::from_str(&contents).map_err(|e| parse_error(e.to_string())),
+        _ => Err(Error::UnknownFormat {
+            path: path.to_owned(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::framing::Framing;
+    use crate::server::ServerMode;
+    use crate::ProductId;
+
+    #[test]
+    fn empty_file_is_default() {
+        let configuration = Configuration::from_toml_str("").unwrap();
+        assert_eq!(configuration.auction.num_bidding_rounds, 5);
+        assert_eq!(configuration.server.port, 8080);
+        assert!(configuration.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_toml() {
+        let configuration = Configuration::from_toml_str(
+            r#"
+            [auction]
+            num_bidding_rounds = 3
+            auction_interval_seconds = 10
+            program_threads = 2
+
+            [[auction.products]]
+            id = 1
+            name = "WIDGET"
+
+            [[auction.products]]
+            id = 2
+            name = "GADGET"
+
+            [auction.fees]
+            program_submission = 10
+            gas_price = 2
+
+            [auction.risk]
+            max_order_quantity = 500
+
+            [server]
+            ip = "0.0.0.0"
+            port = 9000
+            mode = "threaded"
+            framing = "length_prefixed"
+            unix_socket = "/tmp/vmx.sock"
+            unix_socket_permissions = 0o660
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(configuration.auction.num_bidding_rounds, 3);
+        assert_eq!(configuration.auction.auction_interval_seconds, 10);
+        assert_eq!(configuration.auction.program_threads, 2);
+        assert_eq!(configuration.auction.products.len(), 2);
+        assert_eq!(configuration.auction.products[1].id, ProductId(2));
+        assert_eq!(configuration.auction.products[1].name, "GADGET");
+        assert_eq!(configuration.auction.fees.program_submission, 10);
+        assert_eq!(configuration.auction.fees.parameter_update, 0);
+        assert_eq!(configuration.auction.fees.gas_price, 2);
+        assert_eq!(configuration.auction.risk.max_order_quantity, 500);
+        assert_eq!(configuration.auction.risk.max_gas_per_execution, 100_000);
+        assert_eq!(configuration.server.ip, "0.0.0.0");
+        assert_eq!(configuration.server.port, 9000);
+        assert_eq!(configuration.server.mode, ServerMode::Threaded);
+        assert_eq!(configuration.server.framing, Framing::LengthPrefixed);
+        assert_eq!(
+            configuration.server.unix_socket,
+            Some(std::path::PathBuf::from("/tmp/vmx.sock"))
+        );
+        assert_eq!(configuration.server.unix_socket_permissions, 0o660);
+        assert!(configuration.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_json() {
+        let configuration = Configuration::from_json_str(
+            r#"{"auction": {"num_bidding_rounds": 2, "products": [{"id": 7, "name": "X"}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(configuration.auction.num_bidding_rounds, 2);
+        assert_eq!(configuration.auction.products[0].id, ProductId(7));
+        assert_eq!(configuration.server.ip, "127.0.0.1");
+    }
+
+    #[test]
+    fn unknown_fields_rejected() {
+        let error = Configuration::from_toml_str("[auction]\nnum_biding_rounds = 3\n").unwrap_err();
+        assert!(error.contains("num_biding_rounds"), "{}", error);
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let configuration = Configuration::from_toml_str(
+            r#"
+            [auction]
+            num_bidding_rounds = 0
+            program_threads = 0
+
+            [[auction.products]]
+            id = 1
+            name = "A"
+
+            [[auction.products]]
+            id = 1
+            name = "A"
+
+            [auction.risk]
+            max_array_entries = 100
+
+            [server]
+            ip = "localhost"
+            unix_socket_permissions = 0o1777
+            "#,
+        )
+        .unwrap();
+
+        match configuration.validate() {
+            Err(Error::Invalid(problems)) => {
+                assert_eq!(problems.len(), 7, "{:?}", problems);
+            }
+            other => panic!("Unexpected validation result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use vmx::auction;
+use vmx::vm::{self, trace, RegIdx};
+
+/// Instructions shown either side of the program counter by `list`
+const LIST_CONTEXT: u64 = 5;
+
+const HELP: &str = "\
+commands:
+  step [N], s [N]       execute N instructions, by default 1
+  continue, c           execute until a breakpoint, or the program stops
+  break PC, b PC        stop before executing the instruction at PC
+  delete PC             remove the breakpoint at PC
+  breakpoints           list breakpoints
+  registers, r          show the registers and stacks
+  array ARR, a ARR      show the values written to an array
+  list, l               show the instructions around the program counter
+  trace FILE            write the trace so far to FILE as JSON
+  help                  show this message
+  quit, q               stop debugging";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Step { count: u64 },
+    Continue,
+    Break { pc: u64 },
+    Delete { pc: u64 },
+    Breakpoints,
+    Registers,
+    Array { arr: u64 },
+    List,
+    Trace { path: String },
+    Help,
+    Quit,
+}
+
+/// A traced program running against a book snapshot
+pub struct Debugger {
+    instance: auction::ProgramInstance,
+    breakpoints: BTreeSet<u64>,
+    /// Why the program stopped, once it has
+    stopped: Option<String>,
+}
+
+impl Debugger {
+    pub fn new(mut instance: auction::ProgramInstance) -> Self {
+        instance.vm_program_instance_mut().enable_tracing();
+        Self {
+            instance,
+            breakpoints: BTreeSet::default(),
+            stopped: None,
+        }
+    }
+
+    pub fn trace(&self) -> &[trace::TraceStep] {
+        self.vm_instance().trace()
+    }
+
+    fn vm_instance(&self) -> &vm::ProgramInstance {
+        self.instance.vm_program_instance()
+    }
+
+    /// Executes up to `count` instructions, or all of them with `stop_at_breakpoints`, printing
+    /// each when `print_steps`
+    fn run(&mut self, count: Option<u64>, stop_at_breakpoints: bool, print_steps: bool) {
+        let mut executed = 0;
+        while self.stopped.is_none() && count.map_or(true, |count| executed < count) {
+            let pc = self.vm_instance().pc();
+            if stop_at_breakpoints && executed > 0 && pc >= 0 {
+                if self.breakpoints.contains(&(pc as u64)) {
+                    println!("breakpoint at {}", pc);
+                    return;
+                }
+            }
+            let result = self.instance.vm_program_instance_mut().execute_step();
+            executed += 1;
+            if print_steps {
+                if let (Ok(_), Some(step)) = (&result, self.trace().last()) {
+                    println!("{}", step);
+                }
+            }
+            match result {
+                Ok(true) => {}
+                Ok(false) => self.stopped = Some("halted".to_owned()),
+                Err(vm::Error::ExecutionError)
+                    if pc as usize >= self.vm_instance().program().instructions().len() =>
+                {
+                    self.stopped = Some("reached the end of the program".to_owned())
+                }
+                Err(e) => self.stopped = Some(format!("stopped at {}: {:?}", pc, e)),
+            }
+        }
+        match &self.stopped {
+            Some(reason) => println!("{}, {} gas used", reason, self.vm_instance().gas_used()),
+            None if !print_steps => self.print_location(),
+            None => {}
+        }
+    }
+
+    fn print_location(&self) {
+        let pc = self.vm_instance().pc();
+        match self.vm_instance().program().instructions().get(pc as usize) {
+            Some(instruction) => println!("{}: {}", pc, instruction),
+            None => println!("{}: end of program", pc),
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Step { count } => {
+                self.check_running()?;
+                self.run(Some(count), false, true);
+            }
+            Command::Continue => {
+                self.check_running()?;
+                self.run(None, true, false);
+            }
+            Command::Break { pc } => {
+                if pc as usize >= self.vm_instance().program().instructions().len() {
+                    return Err(format!("no instruction at {}", pc));
+                }
+                self.breakpoints.insert(pc);
+            }
+            Command::Delete { pc } => {
+                if !self.breakpoints.remove(&pc) {
+                    return Err(format!("no breakpoint at {}", pc));
+                }
+            }
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    println!("no breakpoints");
+                }
+                for pc in &self.breakpoints {
+                    println!("{}", pc);
+                }
+            }
+            Command::Registers => {
+                let state = self.vm_instance().state();
+                for idx in 0..16u8 {
+                    let register = RegIdx(idx);
+                    println!(
+                        "{:>4} {}",
+                        register.to_string(),
+                        state.register_read(register)
+                    );
+                }
+                println!("stack {:?}", state.stack());
+                println!("calls {:?}", state.call_stack());
+                println!("gas used {}", self.vm_instance().gas_used());
+            }
+            Command::Array { arr } => {
+                let mut values: Vec<(u64, i64)> = self
+                    .vm_instance()
+                    .state()
+                    .iter_touched_values(arr)
+                    .collect();
+                values.sort();
+                if values.is_empty() {
+                    println!("arr{} is empty", arr);
+                }
+                for (idx, value) in values {
+                    println!("arr{}[{}] = {}", arr, idx, value);
+                }
+            }
+            Command::List => {
+                let pc = self.vm_instance().pc().max(0) as u64;
+                let instructions = self.vm_instance().program().instructions();
+                let first = pc.saturating_sub(LIST_CONTEXT);
+                for (idx, instruction) in instructions
+                    .iter()
+                    .enumerate()
+                    .skip(first as usize)
+                    .take((2 * LIST_CONTEXT + 1) as usize)
+                {
+                    let idx = idx as u64;
+                    println!(
+                        "{}{} {:>4}: {}",
+                        if idx == pc { ">" } else { " " },
+                        if self.breakpoints.contains(&idx) {
+                            "*"
+                        } else {
+                            " "
+                        },
+                        idx,
+                        instruction
+                    );
+                }
+            }
+            Command::Trace { path } => {
+                write_trace(self.trace(), &path)?;
+                println!("wrote {} steps to {}", self.trace().len(), path);
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Quit => unreachable!("handled by run"),
+        }
+        Ok(())
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        match &self.stopped {
+            Some(reason) => Err(format!("the program has already {}", reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads commands from stdin until it ends or `quit`
+pub fn run(debugger: &mut Debugger) -> Result<(), String> {
+    println!(
+        "{} instructions, type help for commands",
+        debugger.vm_instance().program().instructions().len()
+    );
+    debugger.print_location();
+    let stdin = io::stdin();
+    loop {
+        print!("(vmx) ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut line = String::default();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Ok(());
+        }
+        match parse_command(&line) {
+            Ok(Some(Command::Quit)) => return Ok(()),
+            Ok(Some(command)) => {
+                if let Err(e) = debugger.execute(command) {
+                    println!("error: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+pub fn write_trace(steps: &[trace::TraceStep], path: &str) -> Result<(), String> {
+    fs::write(path, trace::to_json(steps) + "\n").map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words[..] {
+        [] => return Ok(None),
+        ["step"] | ["s"] => Command::Step { count: 1 },
+        ["step", count] | ["s", count] => Command::Step {
+            count: parse_word(count, "count")?,
+        },
+        ["continue"] | ["c"] => Command::Continue,
+        ["break", pc] | ["b", pc] => Command::Break {
+            pc: parse_word(pc, "instruction")?,
+        },
+        ["delete", pc] => Command::Delete {
+            pc: parse_word(pc, "instruction")?,
+        },
+        ["breakpoints"] => Command::Breakpoints,
+        ["registers"] | ["r"] => Command::Registers,
+        ["array", arr] | ["a", arr] => Command::Array {
+            arr: parse_word(arr.trim_start_matches("arr"), "array")?,
+        },
+        ["list"] | ["l"] => Command::List,
+        ["trace", path] => Command::Trace {
+            path: path.to_owned(),
+        },
+        ["help"] => Command::Help,
+        ["quit"] | ["q"] | ["exit"] => Command::Quit,
+        _ => return Err(format!("unknown command \"{}\", try help", line.trim())),
+    };
+    Ok(Some(command))
+}
+
+fn parse_word<T: std::str::FromStr>(word: &str, what: &str) -> Result<T, String> {
+    word.parse::<T>()
+        .map_err(|_| format!("invalid {} \"{}\"", what, word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmx::auction::{Book, ParticipantParameters};
+    use vmx::participant::ParticipantId;
+
+    #[test]
+    fn commands_parsed() {
+        assert_eq!(parse_command("").unwrap(), None);
+        assert_eq!(
+            parse_command("s").unwrap(),
+            Some(Command::Step { count: 1 })
+        );
+        assert_eq!(
+            parse_command("step 20").unwrap(),
+            Some(Command::Step { count: 20 })
+        );
+        assert_eq!(
+            parse_command("b 7").unwrap(),
+            Some(Command::Break { pc: 7 })
+        );
+        assert_eq!(
+            parse_command("array arr9").unwrap(),
+            Some(Command::Array { arr: 9 })
+        );
+        assert_eq!(
+            parse_command("a 10").unwrap(),
+            Some(Command::Array { arr: 10 })
+        );
+        assert_eq!(
+            parse_command("trace out.json").unwrap(),
+            Some(Command::Trace {
+                path: "out.json".to_owned()
+            })
+        );
+
+        assert!(parse_command("step -1").is_err());
+        assert!(parse_command("break").is_err());
+        assert!(parse_command("jump 3").is_err());
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        // Bids the best bid, one at a time, until it has bid 3
+        let program = vm::Program::try_from_str(
+            "movimm r0 1
+            movimm r1 9
+            movimm r2 3
+            movimm r3 0
+            movimm r4 6
+            arrget r5 r0 r0
+            add r3 r3 r0
+            arrins r3 r1 r5
+            jlt r4 r3 r2
+            halt",
+        )
+        .unwrap();
+        let book: Book = serde_json::from_str(
+            r#"{"product_id": 0, "orders": [
+                {"participant": 1, "side": "bid", "price": 100, "quantity": 10}
+            ]}"#,
+        )
+        .unwrap();
+        let mut instance = auction::ProgramInstance::new(
+            &program,
+            &book,
+            ParticipantId(0),
+            &ParticipantParameters::default(),
+        );
+        instance.vm_program_instance_mut().set_gas_limit(1_000);
+        let mut debugger = Debugger::new(instance);
+        let pc = |debugger: &Debugger| debugger.vm_instance().pc();
+
+        debugger.execute(Command::Break { pc: 7 }).unwrap();
+        assert!(debugger.execute(Command::Break { pc: 10 }).is_err());
+        debugger.execute(Command::Continue).unwrap();
+        assert_eq!(pc(&debugger), 7);
+        // Continuing from a breakpoint executes it before looking for the next one
+        debugger.execute(Command::Continue).unwrap();
+        assert_eq!(pc(&debugger), 7);
+        debugger.execute(Command::Step { count: 2 }).unwrap();
+        assert_eq!(pc(&debugger), 6);
+        assert_eq!(
+            debugger.vm_instance().state().array_read(9, 100),
+            2,
+            "two bids written"
+        );
+
+        debugger.execute(Command::Delete { pc: 7 }).unwrap();
+        debugger.execute(Command::Continue).unwrap();
+        assert_eq!(debugger.stopped, Some("halted".to_owned()));
+        assert!(debugger.execute(Command::Step { count: 1 }).is_err());
+        assert_eq!(debugger.vm_instance().state().array_read(9, 100), 3);
+
+        let last = debugger.trace().last().unwrap();
+        assert_eq!(last.pc, 9);
+        assert_eq!(last.step as usize, debugger.trace().len() - 1);
+    }
+}
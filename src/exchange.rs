@@ -1,15 +1,21 @@
+use std::time::Duration;
+
 pub use crate::auction::AuctionConfiguration;
 use crate::auction::{Engine, Trade};
+use crate::clock::{Clock, SystemClock, Timestamp};
 use crate::participant::ParticipantId;
 use crate::participant::ParticipantPool;
 use crate::protocol::ClientNotification;
 
-pub struct Exchange<P>
+pub struct Exchange<P, C = SystemClock>
 where
     P: ParticipantPool,
+    C: Clock,
 {
     engine: Engine,
     participant_pool: P,
+    clock: C,
+    next_auction_time: Timestamp,
 }
 
 impl<P> Default for Exchange<P>
@@ -25,32 +31,92 @@ impl<P> Exchange<P>
 where
     P: ParticipantPool,
 {
-    pub fn participant_pool(&self) -> &P {
-        &self.participant_pool
+    pub fn new(engine_config: AuctionConfiguration, participant_pool: P) -> Self {
+        Self::with_clock(engine_config, participant_pool, SystemClock)
     }
+}
 
-    pub fn new(engine_config: AuctionConfiguration, participant_pool: P) -> Self {
+impl<P, C> Exchange<P, C>
+where
+    P: ParticipantPool,
+    C: Clock,
+{
+    pub fn with_clock(engine_config: AuctionConfiguration, participant_pool: P, clock: C) -> Self {
+        let first_auction_time = clock.now() + engine_config.auction_interval();
         let engine = Engine::new(engine_config);
         Self {
             engine,
             participant_pool,
+            clock,
+            next_auction_time: first_auction_time,
         }
     }
 
+    pub fn participant_pool(&self) -> &P {
+        &self.participant_pool
+    }
+
+    pub fn participant_pool_mut(&mut self) -> &mut P {
+        &mut self.participant_pool
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn next_auction_time(&self) -> Timestamp {
+        self.next_auction_time
+    }
+
+    pub fn time_until_next_auction(&self) -> Duration {
+        self.next_auction_time.duration_since(self.clock.now())
+    }
+
     pub fn apply_participant_directives(&mut self) {
         let pending_client_messages = self.participant_pool.pop_all_directives();
+        let mut rejections: Vec<(ParticipantId, ClientNotification)> = Vec::default();
         for (participant_id, directive) in pending_client_messages {
-            self.engine
-                .apply_participant_directive(participant_id, &directive);
+            if let Err(e) = self
+                .engine
+                .apply_participant_directive(participant_id, &directive)
+            {
+                rejections.push((
+                    participant_id,
+                    ClientNotification::Rejected {
+                        reason: e.to_string(),
+                    },
+                ));
+            }
+        }
+        if !rejections.is_empty() {
+            self.participant_pool
+                .push_notifications_to_all(&rejections[..]);
         }
     }
 
+    /// Tells each participant whose program failed, which leaves it without orders in that book
     pub fn step_all_books_one_auction(&mut self) {
-        self.engine.step_all_books_one_auction();
+        let failures: Vec<(ParticipantId, ClientNotification)> = self
+            .engine
+            .step_all_books_one_auction()
+            .into_iter()
+            .map(|(participant_id, product_id, e)| {
+                let reason = format!("program for {:?} failed: {}", product_id, e);
+                (participant_id, ClientNotification::Rejected { reason })
+            })
+            .collect();
+        if !failures.is_empty() {
+            self.participant_pool
+                .push_notifications_to_all(&failures[..]);
+        }
     }
 
     pub fn match_all_books(&mut self) -> Vec<Trade> {
-        self.engine.match_all_books()
+        self.engine.match_all_books(self.clock.now())
     }
 
     pub fn send_trade_notifications(&mut self, trades: Vec<Trade>) {
@@ -61,6 +127,7 @@ where
                 price: trade.price,
                 quantity: trade.quantity,
                 side: trade.side,
+                timestamp: trade.timestamp,
             };
             notifications.push((trade.participant_id, notification));
         }
@@ -74,10 +141,32 @@ where
 
         self.step_all_books_one_auction();
 
-        let trades = self.engine.match_all_books();
+        let trades = self.match_all_books();
 
         self.send_trade_notifications(trades);
 
         Ok(())
     }
+
+    /// Runs an auction if the clock has reached the scheduled auction time, returning whether one
+    /// ran.  Auctions missed while the caller wasn't polling are skipped rather than run back to
+    /// back, keeping auction times on the original cadence.
+    pub fn step_if_due(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let now = self.clock.now();
+        if now < self.next_auction_time {
+            return Ok(false);
+        }
+
+        self.step()?;
+
+        let interval = self.engine.config().auction_interval();
+        if interval == Duration::from_secs(0) {
+            self.next_auction_time = now;
+            return Ok(true);
+        }
+        while self.next_auction_time <= now {
+            self.next_auction_time = self.next_auction_time + interval;
+        }
+        Ok(true)
+    }
 }
@@ -1,15 +1,22 @@
 #![allow(clippy::all)]
 
 pub mod auction;
+pub mod client;
+pub mod clock;
+pub mod configuration;
 pub mod exchange;
 pub mod participant;
 pub mod protocol;
 pub mod server;
+pub mod strategy;
 pub mod vm;
 
 use num_derive::NumOps;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, NumOps, Clone, Copy, PartialOrd, PartialEq, Hash, Eq, Ord)]
+#[derive(
+    Debug, NumOps, Clone, Copy, PartialOrd, PartialEq, Hash, Eq, Ord, Serialize, Deserialize,
+)]
 pub struct Price(pub u64);
 
 impl From<Price> for i64 {
@@ -24,5 +31,5 @@ impl From<Price> for u64 {
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct ProductId(pub u64);
@@ -1,69 +1,596 @@
 #![allow(clippy::all)]
 
-use clap::{App, Arg, ArgMatches, SubCommand};
+mod debugger;
+mod repl;
 
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use lazy_static::lazy_static;
+
+use vmx::auction::{Book, ParticipantParameters, ProgramInstance, RiskLimits};
+use vmx::client::{Client, ClientConfig};
+use vmx::configuration::Configuration;
 use vmx::exchange::{AuctionConfiguration, Exchange};
-use vmx::server::tcp::{Server, ServerConfig};
-use vmx::server::Server as ServerTrait;
+use vmx::participant::{ParticipantId, ParticipantPool};
+use vmx::protocol::Encoding;
+use vmx::server::credentials::Credentials;
+use vmx::server::framing::Framing;
+#[cfg(unix)]
+use vmx::server::unix;
+use vmx::server::{evented, tcp, websocket, Server, ServerConfig, ServerMode};
+use vmx::strategy;
+use vmx::vm::Program;
+use vmx::ProductId;
+
+/// Upper bound on how long the auction loop waits for clients before checking for shutdown
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    /// `run` and `debug` don't read a configuration, so they start from the built-in limits
+    static ref GAS_LIMIT_HELP: String = format!(
+        "Gas the program may use, by default the built-in {}",
+        RiskLimits::default().max_gas_per_execution
+    );
+}
 
 fn main() {
     let app = App::new("vmx")
         .about("My Exchange")
         .author("Jeremy Schroeder")
-        .subcommands(vec![SubCommand::with_name("serve")
-            .about("Serve the ")
-            .args(&[
-                Arg::with_name("--ip").number_of_values(1).required(false),
-                Arg::with_name("--port").number_of_values(1).required(false),
-            ])]);
-    let user_config = UserConfiguration::from(app.get_matches());
-    let auction_config = AuctionConfiguration::from(&user_config);
-    let server_config = ServerConfig::from(&user_config);
-    let mut server = Server::new(server_config);
-    server.start_listening().expect("TODO");
-
-    let _exchange = Exchange::new(auction_config, server);
-    println!("Starting");
-    todo!();
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands(vec![
+            SubCommand::with_name("serve")
+                .about("Serve the exchange over TCP, WebSocket or a Unix domain socket")
+                .args(&[
+                    Arg::with_name("config")
+                        .long("config")
+                        .takes_value(true)
+                        .help("Configuration file (.toml or .json)"),
+                    Arg::with_name("ip")
+                        .long("ip")
+                        .takes_value(true)
+                        .help("Address to listen on, instead of a configured Unix socket"),
+                    Arg::with_name("port")
+                        .long("port")
+                        .takes_value(true)
+                        .help("Port to listen on, instead of a configured Unix socket"),
+                    Arg::with_name("unix")
+                        .long("unix")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with_all(&["ip", "port"])
+                        .help("Unix domain socket to listen on instead of a TCP port"),
+                    Arg::with_name("bidding-rounds")
+                        .long("bidding-rounds")
+                        .takes_value(true)
+                        .help("Bidding rounds per auction"),
+                    Arg::with_name("auction-interval")
+                        .long("auction-interval")
+                        .takes_value(true)
+                        .help("Seconds between auctions"),
+                ]),
+            SubCommand::with_name("client")
+                .about("Connect to an exchange and send directives interactively")
+                .args(&[
+                    Arg::with_name("connect")
+                        .long("connect")
+                        .takes_value(true)
+                        .value_name("HOST:PORT")
+                        .help("Exchange to connect to, by default the one `vmx serve` starts"),
+                    Arg::with_name("unix")
+                        .long("unix")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("connect")
+                        .help("Unix domain socket to connect to instead"),
+                    Arg::with_name("framing")
+                        .long("framing")
+                        .takes_value(true)
+                        .possible_values(&["newline_delimited", "length_prefixed"])
+                        .help("The exchange's framing"),
+                    Arg::with_name("encoding")
+                        .long("encoding")
+                        .takes_value(true)
+                        .possible_values(&["json", "binary"])
+                        .help("Encoding to ask the exchange for.  Binary needs length_prefixed framing."),
+                ]),
+            SubCommand::with_name("compile")
+                .about("Compile a strategy to VM assembly")
+                .args(&[
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("Strategy source file"),
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Assembly file to write, instead of printing it"),
+                ]),
+            SubCommand::with_name("run")
+                .about("Run a program once against a book snapshot, without an exchange")
+                .args(&program_args()),
+            SubCommand::with_name("debug")
+                .about("Step through a program running against a book snapshot")
+                .args(&program_args())
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Write the execution trace to FILE as JSON on exit"),
+                ),
+            SubCommand::with_name("config")
+                .about("Inspect configuration files")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Validate a configuration file")
+                        .arg(Arg::with_name("file").required(true)),
+                ),
+        ]);
+
+    let matches = app.get_matches();
+    let result = match matches.subcommand() {
+        ("serve", Some(serve_matches)) => {
+            UserConfiguration::try_from(serve_matches).and_then(serve)
+        }
+        ("client", Some(client_matches)) => client(client_matches),
+        ("compile", Some(compile_matches)) => compile(
+            Path::new(compile_matches.value_of("file").unwrap()),
+            compile_matches.value_of("output").map(Path::new),
+        ),
+        ("run", Some(run_matches)) => run_program(run_matches),
+        ("debug", Some(debug_matches)) => debug(debug_matches),
+        ("config", Some(config_matches)) => match config_matches.subcommand() {
+            ("check", Some(check_matches)) => {
+                check_config(Path::new(check_matches.value_of("file").unwrap()))
+            }
+            _ => unreachable!("clap requires a subcommand"),
+        },
+        _ => unreachable!("clap requires a subcommand"),
+    };
+    if let Err(e) = result {
+        eprintln!("vmx: {}", e);
+        process::exit(1);
+    }
 }
 
-struct UserConfiguration {
-    listening_ip: Option<String>,
-    listening_port: Option<u16>,
+fn check_config(path: &Path) -> Result<(), String> {
+    let configuration = Configuration::from_file(path).map_err(|e| e.to_string())?;
+    configuration.validate().map_err(|e| e.to_string())?;
+
+    let auction = &configuration.auction;
+    println!("{}: OK", path.display());
+    println!(
+        "  auction: {} bidding rounds every {}s, programs run on {} threads",
+        auction.num_bidding_rounds, auction.auction_interval_seconds, auction.program_threads
+    );
+    if auction.products.is_empty() {
+        println!("  products: any");
+    } else {
+        for product in &auction.products {
+            println!("  product {}: {}", product.id.0, product.name);
+        }
+    }
+    println!(
+        "  fees: {} per program, {} per parameter update, {} per gas",
+        auction.fees.program_submission, auction.fees.parameter_update, auction.fees.gas_price
+    );
+    println!(
+        "  risk: max order quantity {}, max gas per execution {}, max storage entries {}",
+        auction.risk.max_order_quantity,
+        auction.risk.max_gas_per_execution,
+        auction.risk.max_storage_entries
+    );
+    println!(
+        "  memory: {} arrays of up to {} entries",
+        auction.risk.max_arrays, auction.risk.max_array_entries
+    );
+    println!(
+        "  server: {}:{} ({:?}), {:?} framing, frames up to {} bytes, {} bytes queued per client",
+        configuration.server.ip,
+        configuration.server.port,
+        configuration.server.mode,
+        configuration.server.framing,
+        configuration.server.max_frame_size,
+        configuration.server.max_outbound_buffer
+    );
+    println!(
+        "  up to {} messages read ahead per client",
+        configuration.server.max_pending_messages
+    );
+    if let Some(unix_socket) = &configuration.server.unix_socket {
+        println!(
+            "  unix socket: {} (mode {:o}), instead of the address above",
+            unix_socket.display(),
+            configuration.server.unix_socket_permissions
+        );
+    }
+    match load_credentials(&configuration.server)? {
+        Some(credentials) => println!(
+            "  credentials: {} participants",
+            credentials.participants.len()
+        ),
+        None => println!("  credentials: none, any client may participate"),
+    }
+    Ok(())
 }
 
-impl<'a> From<ArgMatches<'a>> for UserConfiguration {
-    fn from(matches: ArgMatches) -> Self {
-        Self {
-            listening_ip: matches.value_of("ip").map(str::to_owned),
-            listening_port: matches
-                .value_of("port")
-                .map(str::parse::<u16>)
-                .map(Result::unwrap),
+fn compile(path: &Path, output: Option<&Path>) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let program = strategy::compile(&source)
+        .map_err(|e| format!("{}:{}:{}: {}", path.display(), e.line, e.column, e.message))?;
+    let assembly = program.get_string() + "\n";
+    match output {
+        Some(output) => {
+            fs::write(output, assembly).map_err(|e| format!("{}: {}", output.display(), e))
+        }
+        None => {
+            print!("{}", assembly);
+            Ok(())
         }
     }
 }
 
-impl From<&UserConfiguration> for AuctionConfiguration {
-    fn from(_user_configuration: &UserConfiguration) -> Self {
-        // TODO
-        Self::default()
+/// Options shared by `run` and `debug`
+fn program_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("program")
+            .long("program")
+            .takes_value(true)
+            .required(true)
+            .help("Assembly file, such as `vmx compile` writes"),
+        Arg::with_name("book")
+            .long("book")
+            .takes_value(true)
+            .help("Book snapshot (.json), by default an empty book"),
+        Arg::with_name("params")
+            .long("params")
+            .takes_value(true)
+            .help("Parameters (.json) as an object from index to value, by default none"),
+        Arg::with_name("participant")
+            .long("participant")
+            .takes_value(true)
+            .help("Participant whose orders the program sees as its own"),
+        Arg::with_name("gas-limit")
+            .long("gas-limit")
+            .takes_value(true)
+            .help(&GAS_LIMIT_HELP[..]),
+    ]
+}
+
+/// What `run` and `debug` execute a program against
+struct ProgramInputs {
+    program: Program,
+    book: Book,
+    parameters: ParticipantParameters,
+    participant: ParticipantId,
+    risk_limits: RiskLimits,
+}
+
+impl ProgramInputs {
+    fn try_from(matches: &ArgMatches) -> Result<Self, String> {
+        let program_path = matches.value_of("program").unwrap();
+        let assembly =
+            fs::read_to_string(program_path).map_err(|e| format!("{}: {}", program_path, e))?;
+        let program = Program::try_from_str(&assembly)
+            .map_err(|e| format!("{}: invalid program: {}", program_path, e))?;
+        let book = match matches.value_of("book") {
+            Some(path) => read_json(path)?,
+            None => Book::new(ProductId(0)),
+        };
+        let parameters = match matches.value_of("params") {
+            Some(path) => read_json(path)?,
+            None => ParticipantParameters::default(),
+        };
+        let mut risk_limits = RiskLimits::default();
+        if let Some(gas_limit) = parse_option(matches, "gas-limit")? {
+            risk_limits.max_gas_per_execution = gas_limit;
+        }
+        Ok(Self {
+            program,
+            book,
+            parameters,
+            participant: ParticipantId(parse_option(matches, "participant")?.unwrap_or(0)),
+            risk_limits,
+        })
+    }
+
+    fn program_instance(&self) -> ProgramInstance {
+        let mut instance = ProgramInstance::new(
+            &self.program,
+            &self.book,
+            self.participant,
+            &self.parameters,
+        );
+        instance.set_risk_limits(&self.risk_limits);
+        instance
     }
 }
 
-impl From<&UserConfiguration> for ServerConfig {
-    fn from(user_configuration: &UserConfiguration) -> Self {
-        if let UserConfiguration {
-            listening_ip: Some(ip),
-            listening_port: Some(port),
-        } = user_configuration
-        {
-            Self {
-                ip: ip.clone(),
-                port: *port,
-            }
-        } else {
-            Self::default()
+fn read_json<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn run_program(matches: &ArgMatches) -> Result<(), String> {
+    let inputs = ProgramInputs::try_from(matches)?;
+    let mut instance = inputs.program_instance();
+    let mut result_book = Book::new(inputs.book.product_id());
+    let result = instance.execute().and_then(|()| {
+        instance.write_result_into_book(&inputs.book, &mut result_book, inputs.participant)
+    });
+    println!("gas used {}", instance.gas_used());
+    result.map_err(|e| format!("program failed: {}", e))?;
+
+    let changes = inputs
+        .book
+        .changes_for_participant(&result_book, inputs.participant);
+    if changes.is_empty() {
+        println!("no order changes");
+    }
+    for change in changes {
+        println!(
+            "{} {}: {} -> {}",
+            change.side, change.price.0, change.before, change.after
+        );
+    }
+    Ok(())
+}
+
+fn debug(matches: &ArgMatches) -> Result<(), String> {
+    let inputs = ProgramInputs::try_from(matches)?;
+    let mut debugger = debugger::Debugger::new(inputs.program_instance());
+    let result = debugger::run(&mut debugger);
+    if let Some(path) = matches.value_of("trace") {
+        debugger::write_trace(debugger.trace(), path)?;
+    }
+    result
+}
+
+fn client(matches: &ArgMatches) -> Result<(), String> {
+    let mut config = ClientConfig::default();
+    match matches.value_of("framing") {
+        Some("length_prefixed") => config.framing = Framing::LengthPrefixed,
+        Some(_) => config.framing = Framing::NewlineDelimited,
+        None => {}
+    }
+    if let Some("binary") = matches.value_of("encoding") {
+        config.encoding = Encoding::Binary;
+    }
+    if config.encoding == Encoding::Binary && !config.framing.is_binary_safe() {
+        return Err("--encoding binary needs --framing length_prefixed".to_owned());
+    }
+
+    let client = match matches.value_of("unix") {
+        Some(path) => connect_unix(path, config)?,
+        None => {
+            let default_server_config = ServerConfig::default();
+            let address = matches
+                .value_of("connect")
+                .map(str::to_owned)
+                .unwrap_or(format!(
+                    "{}:{}",
+                    default_server_config.ip, default_server_config.port
+                ));
+            Client::connect(&address[..], config)
+                .map_err(|e| format!("could not connect to {}: {}", address, e))?
+        }
+    };
+    repl::run(client)
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str, config: ClientConfig) -> Result<Client, String> {
+    Client::connect_unix(path, config).map_err(|e| format!("could not connect to {}: {}", path, e))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str, _config: ClientConfig) -> Result<Client, String> {
+    Err("Unix domain sockets are not supported on this platform".to_owned())
+}
+
+fn serve(user_config: UserConfiguration) -> Result<(), String> {
+    let configuration = user_config.load()?;
+    let Configuration {
+        auction: auction_config,
+        server: server_config,
+    } = configuration;
+    let address = match &server_config.unix_socket {
+        Some(path) => path.display().to_string(),
+        None => format!("{}:{}", server_config.ip, server_config.port),
+    };
+
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown_requested = shutdown_requested.clone();
+        ctrlc::set_handler(move || shutdown_requested.store(true, Ordering::SeqCst))
+            .map_err(|e| format!("could not install signal handler: {}", e))?;
+    }
+
+    let credentials = load_credentials(&server_config)?;
+    if credentials.is_none() {
+        println!("No credentials file configured, any client may participate");
+    }
+
+    if server_config.unix_socket.is_some() {
+        return serve_unix(
+            auction_config,
+            server_config,
+            credentials,
+            &address,
+            &shutdown_requested,
+        );
+    }
+    match server_config.mode {
+        ServerMode::Evented => {
+            let server: evented::Server = match credentials {
+                Some(credentials) => evented::Server::with_credentials(server_config, credentials),
+                None => evented::Server::new(server_config),
+            };
+            run_exchange(auction_config, server, &address, &shutdown_requested)
+        }
+        ServerMode::Threaded => {
+            let server: tcp::Server = match credentials {
+                Some(credentials) => tcp::Server::with_credentials(server_config, credentials),
+                None => tcp::Server::new(server_config),
+            };
+            run_exchange(auction_config, server, &address, &shutdown_requested)
+        }
+        ServerMode::WebSocket => {
+            let server: websocket::Server = match credentials {
+                Some(credentials) => {
+                    websocket::Server::with_credentials(server_config, credentials)
+                }
+                None => websocket::Server::new(server_config),
+            };
+            run_exchange(auction_config, server, &address, &shutdown_requested)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix(
+    auction_config: AuctionConfiguration,
+    server_config: ServerConfig,
+    credentials: Option<Credentials>,
+    address: &str,
+    shutdown_requested: &AtomicBool,
+) -> Result<(), String> {
+    let server: unix::Server = match credentials {
+        Some(credentials) => unix::Server::with_credentials(server_config, credentials),
+        None => unix::Server::new(server_config),
+    };
+    run_exchange(auction_config, server, address, shutdown_requested)
+}
+
+#[cfg(not(unix))]
+fn serve_unix(
+    _auction_config: AuctionConfiguration,
+    _server_config: ServerConfig,
+    _credentials: Option<Credentials>,
+    _address: &str,
+    _shutdown_requested: &AtomicBool,
+) -> Result<(), String> {
+    Err("Unix domain sockets are not supported on this platform".to_owned())
+}
+
+fn load_credentials(server_config: &ServerConfig) -> Result<Option<Credentials>, String> {
+    server_config
+        .credentials_file
+        .as_ref()
+        .map(|path| {
+            let credentials = Credentials::from_file(path).map_err(|e| e.to_string())?;
+            credentials.validate().map_err(|e| e.to_string())?;
+            Ok(credentials)
+        })
+        .transpose()
+}
+
+fn run_exchange<S>(
+    auction_config: AuctionConfiguration,
+    mut server: S,
+    address: &str,
+    shutdown_requested: &AtomicBool,
+) -> Result<(), String>
+where
+    S: Server + ParticipantPool,
+{
+    server
+        .start_listening()
+        .map_err(|e| format!("could not listen on {}: {}", address, e))?;
+    println!(
+        "Listening on {} ({} bidding rounds every {}s)",
+        address, auction_config.num_bidding_rounds, auction_config.auction_interval_seconds
+    );
+
+    let mut exchange = Exchange::new(auction_config, server);
+    while !shutdown_requested.load(Ordering::SeqCst) {
+        if let Err(e) = exchange.step_if_due() {
+            eprintln!("Auction failed: {}", e);
+        }
+        let timeout = exchange.time_until_next_auction().min(MAX_POLL_INTERVAL);
+        if let Err(e) = exchange.participant_pool_mut().wait(timeout) {
+            eprintln!("Serving clients failed: {}", e);
+            thread::sleep(timeout);
         }
     }
+
+    println!("Shutting down");
+    exchange
+        .participant_pool_mut()
+        .stop_listening()
+        .map_err(|e| format!("could not stop listening: {}", e))
+}
+
+/// Command line options, which take precedence over the configuration file
+struct UserConfiguration {
+    config_path: Option<String>,
+    listening_ip: Option<String>,
+    listening_port: Option<u16>,
+    unix_socket: Option<String>,
+    num_bidding_rounds: Option<u64>,
+    auction_interval_seconds: Option<u64>,
+}
+
+impl UserConfiguration {
+    fn try_from(matches: &ArgMatches) -> Result<Self, String> {
+        Ok(Self {
+            config_path: matches.value_of("config").map(str::to_owned),
+            listening_ip: matches.value_of("ip").map(str::to_owned),
+            listening_port: parse_option(matches, "port")?,
+            unix_socket: matches.value_of("unix").map(str::to_owned),
+            num_bidding_rounds: parse_option(matches, "bidding-rounds")?,
+            auction_interval_seconds: parse_option(matches, "auction-interval")?,
+        })
+    }
+
+    fn load(&self) -> Result<Configuration, String> {
+        let mut configuration = match &self.config_path {
+            Some(path) => Configuration::from_file(Path::new(path)).map_err(|e| e.to_string())?,
+            None => Configuration::default(),
+        };
+
+        if let Some(ip) = &self.listening_ip {
+            configuration.server.ip = ip.clone();
+        }
+        if let Some(port) = self.listening_port {
+            configuration.server.port = port;
+        }
+        // Asking for a TCP address means listening there, not on the file's Unix socket
+        if self.listening_ip.is_some() || self.listening_port.is_some() {
+            configuration.server.unix_socket = None;
+        }
+        if let Some(unix_socket) = &self.unix_socket {
+            configuration.server.unix_socket = Some(unix_socket.into());
+        }
+        if let Some(num_bidding_rounds) = self.num_bidding_rounds {
+            configuration.auction.num_bidding_rounds = num_bidding_rounds;
+        }
+        if let Some(auction_interval_seconds) = self.auction_interval_seconds {
+            configuration.auction.auction_interval_seconds = auction_interval_seconds;
+        }
+
+        configuration.validate().map_err(|e| e.to_string())?;
+        Ok(configuration)
+    }
+}
+
+fn parse_option<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String> {
+    matches
+        .value_of(name)
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|_| format!("invalid --{} \"{}\"", name, value))
+        })
+        .transpose()
 }
@@ -1,6 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 use crate::protocol::{ClientDirective, ClientNotification};
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
 pub struct ParticipantId(pub u64);
 
 pub trait ParticipantPool {
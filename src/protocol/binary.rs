@@ -0,0 +1,662 @@
+use std::convert::TryFrom;
+
+use super::{Capability, ClientDirective, ClientNotification, Encoding, WireProtocol};
+use crate::auction::Side;
+use crate::clock::Timestamp;
+use crate::participant::ParticipantId;
+use crate::vm::Program;
+use crate::{Price, ProductId};
+
+/// Fixed-layout little-endian messages, each a one byte type tag followed by that type's fields
+/// in order.  Strings and programs are a `u32` byte length followed by UTF-8 text or VM bytecode.
+/// See `doc/protocol.md` for the layouts.
+pub struct BinaryProtocol;
+
+mod tag {
+    pub const LOGIN: u8 = 0x01;
+    pub const RESUME: u8 = 0x02;
+    pub const JOIN: u8 = 0x03;
+    pub const LEAVE: u8 = 0x04;
+    pub const UPDATE_PARAMETER: u8 = 0x05;
+    pub const SUBMIT_PROGRAM: u8 = 0x06;
+    pub const HELLO: u8 = 0x07;
+
+    pub const TRADE: u8 = 0x81;
+    pub const LOGGED_IN: u8 = 0x82;
+    pub const REJECTED: u8 = 0x83;
+    pub const SEQUENCED: u8 = 0x84;
+    pub const HELLO_REPLY: u8 = 0x85;
+
+    pub const BID: u8 = 0;
+    pub const OFFER: u8 = 1;
+
+    pub const JSON: u8 = 0;
+    pub const BINARY: u8 = 1;
+
+    pub const BOOK_SNAPSHOTS: u8 = 1;
+    pub const REPLAY: u8 = 2;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The message ended part way through the field at `offset`
+    Truncated {
+        offset: usize,
+    },
+    UnknownTag {
+        offset: usize,
+        tag: u8,
+    },
+    InvalidString {
+        offset: usize,
+    },
+    InvalidProgram {
+        offset: usize,
+    },
+    /// Bytes left over after a complete message
+    TrailingBytes {
+        offset: usize,
+    },
+    /// A `Sequenced` notification inside another
+    NestedSequenced {
+        offset: usize,
+    },
+    /// A string or program too long for its `u32` length, or too many capabilities
+    TooLong,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for Error {}
+
+impl WireProtocol for BinaryProtocol {
+    type Error = Error;
+
+    fn try_client_directive_from_bytes(bytes: &[u8]) -> Result<ClientDirective, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let directive = reader.directive()?;
+        reader.finish()?;
+        Ok(directive)
+    }
+
+    fn try_client_directive_to_bytes(
+        client_directive: &ClientDirective,
+    ) -> Result<Vec<u8>, Self::Error> {
+        let mut writer = Writer::default();
+        writer.directive(client_directive)?;
+        Ok(writer.bytes)
+    }
+
+    fn try_client_notification_from_bytes(bytes: &[u8]) -> Result<ClientNotification, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let notification = reader.notification(false)?;
+        reader.finish()?;
+        Ok(notification)
+    }
+
+    fn try_client_notification_to_bytes(
+        client_notification: &ClientNotification,
+    ) -> Result<Vec<u8>, Self::Error> {
+        let mut writer = Writer::default();
+        writer.notification(client_notification)?;
+        Ok(writer.bytes)
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn length_prefixed(&mut self, value: &[u8]) -> Result<(), Error> {
+        let len = u32::try_from(value.len()).map_err(|_| Error::TooLong)?;
+        self.bytes.extend_from_slice(&len.to_le_bytes());
+        self.bytes.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn hello(
+        &mut self,
+        version: u32,
+        encoding: Encoding,
+        capabilities: &[Capability],
+    ) -> Result<(), Error> {
+        self.u32(version);
+        self.u8(match encoding {
+            Encoding::Json => tag::JSON,
+            Encoding::Binary => tag::BINARY,
+        });
+        self.u8(u8::try_from(capabilities.len()).map_err(|_| Error::TooLong)?);
+        for capability in capabilities {
+            self.u8(match capability {
+                Capability::BookSnapshots => tag::BOOK_SNAPSHOTS,
+                Capability::Replay => tag::REPLAY,
+            });
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &ClientDirective) -> Result<(), Error> {
+        match directive {
+            ClientDirective::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => {
+                self.u8(tag::HELLO);
+                self.hello(*version, *encoding, capabilities)?;
+            }
+            ClientDirective::Login { name, key } => {
+                self.u8(tag::LOGIN);
+                self.length_prefixed(name.as_bytes())?;
+                self.length_prefixed(key.as_bytes())?;
+            }
+            ClientDirective::Resume { last_sequence } => {
+                self.u8(tag::RESUME);
+                self.u64(*last_sequence);
+            }
+            ClientDirective::Join {} => self.u8(tag::JOIN),
+            ClientDirective::Leave {} => self.u8(tag::LEAVE),
+            ClientDirective::UpdateParameter {
+                product_id,
+                param_idx,
+                value,
+            } => {
+                self.u8(tag::UPDATE_PARAMETER);
+                self.u64(product_id.0);
+                self.u64(*param_idx);
+                self.i64(*value);
+            }
+            ClientDirective::SubmitProgram {
+                product_id,
+                program,
+            } => {
+                self.u8(tag::SUBMIT_PROGRAM);
+                self.u64(product_id.0);
+                self.length_prefixed(&program.to_bytecode())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn notification(&mut self, notification: &ClientNotification) -> Result<(), Error> {
+        match notification {
+            ClientNotification::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => {
+                self.u8(tag::HELLO_REPLY);
+                self.hello(*version, *encoding, capabilities)?;
+            }
+            ClientNotification::Trade {
+                product_id,
+                side,
+                price,
+                quantity,
+                timestamp,
+            } => {
+                self.u8(tag::TRADE);
+                self.u64(product_id.0);
+                self.u8(match side {
+                    Side::Bid => tag::BID,
+                    Side::Offer => tag::OFFER,
+                });
+                self.u64(price.0);
+                self.u64(*quantity);
+                self.u64(timestamp.0);
+            }
+            ClientNotification::LoggedIn {
+                participant_id,
+                last_sequence,
+            } => {
+                self.u8(tag::LOGGED_IN);
+                self.u64(participant_id.0);
+                self.u64(*last_sequence);
+            }
+            ClientNotification::Rejected { reason } => {
+                self.u8(tag::REJECTED);
+                self.length_prefixed(reason.as_bytes())?;
+            }
+            ClientNotification::Sequenced {
+                sequence,
+                notification,
+            } => {
+                self.u8(tag::SEQUENCED);
+                self.u64(*sequence);
+                self.notification(notification)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checked reads, which fail rather than panic or allocate beyond the input on malformed messages
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(Error::Truncated {
+                offset: self.offset,
+            })?;
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(value))
+    }
+
+    fn length_prefixed(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let offset = self.offset;
+        let bytes = self.length_prefixed()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::InvalidString { offset })
+    }
+
+    fn program(&mut self) -> Result<Program, Error> {
+        let offset = self.offset;
+        let bytecode = self.length_prefixed()?;
+        Program::try_from_bytecode(bytecode).map_err(|_| Error::InvalidProgram { offset })
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        if self.offset == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(Error::TrailingBytes {
+                offset: self.offset,
+            })
+        }
+    }
+
+    fn directive(&mut self) -> Result<ClientDirective, Error> {
+        let offset = self.offset;
+        let directive = match self.u8()? {
+            tag::HELLO => {
+                let (version, encoding, capabilities) = self.hello()?;
+                ClientDirective::Hello {
+                    version,
+                    encoding,
+                    capabilities,
+                }
+            }
+            tag::LOGIN => ClientDirective::Login {
+                name: self.string()?,
+                key: self.string()?,
+            },
+            tag::RESUME => ClientDirective::Resume {
+                last_sequence: self.u64()?,
+            },
+            tag::JOIN => ClientDirective::Join {},
+            tag::LEAVE => ClientDirective::Leave {},
+            tag::UPDATE_PARAMETER => ClientDirective::UpdateParameter {
+                product_id: ProductId(self.u64()?),
+                param_idx: self.u64()?,
+                value: self.i64()?,
+            },
+            tag::SUBMIT_PROGRAM => ClientDirective::SubmitProgram {
+                product_id: ProductId(self.u64()?),
+                program: self.program()?,
+            },
+            tag => return Err(Error::UnknownTag { offset, tag }),
+        };
+        Ok(directive)
+    }
+
+    fn notification(&mut self, in_sequenced: bool) -> Result<ClientNotification, Error> {
+        let offset = self.offset;
+        let notification = match self.u8()? {
+            tag::HELLO_REPLY => {
+                let (version, encoding, capabilities) = self.hello()?;
+                ClientNotification::Hello {
+                    version,
+                    encoding,
+                    capabilities,
+                }
+            }
+            tag::TRADE => ClientNotification::Trade {
+                product_id: ProductId(self.u64()?),
+                side: self.side()?,
+                price: Price(self.u64()?),
+                quantity: self.u64()?,
+                timestamp: Timestamp(self.u64()?),
+            },
+            tag::LOGGED_IN => ClientNotification::LoggedIn {
+                participant_id: ParticipantId(self.u64()?),
+                last_sequence: self.u64()?,
+            },
+            tag::REJECTED => ClientNotification::Rejected {
+                reason: self.string()?,
+            },
+            tag::SEQUENCED if in_sequenced => return Err(Error::NestedSequenced { offset }),
+            tag::SEQUENCED => ClientNotification::Sequenced {
+                sequence: self.u64()?,
+                notification: Box::new(self.notification(true)?),
+            },
+            tag => return Err(Error::UnknownTag { offset, tag }),
+        };
+        Ok(notification)
+    }
+
+    /// Unknown capabilities are skipped, so that clients can ask for ones only newer exchanges
+    /// provide
+    fn hello(&mut self) -> Result<(u32, Encoding, Vec<Capability>), Error> {
+        let version = self.u32()?;
+        let offset = self.offset;
+        let encoding = match self.u8()? {
+            tag::JSON => Encoding::Json,
+            tag::BINARY => Encoding::Binary,
+            tag => return Err(Error::UnknownTag { offset, tag }),
+        };
+        let count = self.u8()?;
+        let mut capabilities: Vec<Capability> = Vec::default();
+        for _ in 0..count {
+            match self.u8()? {
+                tag::BOOK_SNAPSHOTS => capabilities.push(Capability::BookSnapshots),
+                tag::REPLAY => capabilities.push(Capability::Replay),
+                _ => {}
+            }
+        }
+        Ok((version, encoding, capabilities))
+    }
+
+    fn side(&mut self) -> Result<Side, Error> {
+        let offset = self.offset;
+        match self.u8()? {
+            tag::BID => Ok(Side::Bid),
+            tag::OFFER => Ok(Side::Offer),
+            tag => Err(Error::UnknownTag { offset, tag }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+    use crate::vm::{Instruction, RegIdx};
+
+    fn directives() -> Vec<ClientDirective> {
+        vec![
+            ClientDirective::Login {
+                name: "alice".to_owned(),
+                key: "sécret".to_owned(),
+            },
+            ClientDirective::Resume {
+                last_sequence: u64::MAX,
+            },
+            ClientDirective::Join {},
+            ClientDirective::Leave {},
+            ClientDirective::UpdateParameter {
+                product_id: ProductId(1),
+                param_idx: 2,
+                value: i64::MIN,
+            },
+            ClientDirective::SubmitProgram {
+                product_id: ProductId(3),
+                program: Program::from_instructions(&[
+                    Instruction::MovImm {
+                        dst: RegIdx(0),
+                        imm: -1,
+                    },
+                    Instruction::ArrIns {
+                        val: RegIdx(0),
+                        arr: RegIdx(1),
+                        idx: RegIdx(2),
+                    },
+                    Instruction::Halt {},
+                ]),
+            },
+            ClientDirective::Hello {
+                version: PROTOCOL_VERSION,
+                encoding: Encoding::Binary,
+                capabilities: vec![Capability::Replay, Capability::BookSnapshots],
+            },
+        ]
+    }
+
+    fn notifications() -> Vec<ClientNotification> {
+        let trade = ClientNotification::Trade {
+            product_id: ProductId(1),
+            side: Side::Offer,
+            price: Price(101),
+            quantity: 5,
+            timestamp: Timestamp(1_600_000_000_000_000_000),
+        };
+        vec![
+            trade.clone(),
+            ClientNotification::LoggedIn {
+                participant_id: ParticipantId(7),
+                last_sequence: 3,
+            },
+            ClientNotification::Rejected {
+                reason: String::default(),
+            },
+            ClientNotification::Sequenced {
+                sequence: 4,
+                notification: Box::new(trade),
+            },
+            ClientNotification::Hello {
+                version: PROTOCOL_VERSION,
+                encoding: Encoding::Json,
+                capabilities: vec![],
+            },
+        ]
+    }
+
+    fn encoded_messages() -> Vec<Vec<u8>> {
+        let mut messages: Vec<Vec<u8>> = directives()
+            .iter()
+            .map(|directive| BinaryProtocol::try_client_directive_to_bytes(directive).unwrap())
+            .collect();
+        messages.extend(notifications().iter().map(|notification| {
+            BinaryProtocol::try_client_notification_to_bytes(notification).unwrap()
+        }));
+        messages
+    }
+
+    /// Decodes as both message kinds, which must return rather than panic
+    fn decode_any(bytes: &[u8]) {
+        let _ = BinaryProtocol::try_client_directive_from_bytes(bytes);
+        let _ = BinaryProtocol::try_client_notification_from_bytes(bytes);
+    }
+
+    #[test]
+    fn directives_round_trip() {
+        for directive in directives() {
+            let bytes = BinaryProtocol::try_client_directive_to_bytes(&directive).unwrap();
+            let decoded = BinaryProtocol::try_client_directive_from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, directive);
+        }
+    }
+
+    #[test]
+    fn notifications_round_trip() {
+        for notification in notifications() {
+            let bytes = BinaryProtocol::try_client_notification_to_bytes(&notification).unwrap();
+            let decoded = BinaryProtocol::try_client_notification_from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, notification);
+        }
+    }
+
+    #[test]
+    fn fixed_layout() {
+        let bytes =
+            BinaryProtocol::try_client_directive_to_bytes(&ClientDirective::UpdateParameter {
+                product_id: ProductId(1),
+                param_idx: 2,
+                value: -1,
+            })
+            .unwrap();
+        let mut expected = vec![tag::UPDATE_PARAMETER];
+        expected.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0xff; 8]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn unknown_capabilities_skipped() {
+        let bytes = [tag::HELLO, 1, 0, 0, 0, tag::BINARY, 3, 0xee, tag::REPLAY, 0];
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&bytes),
+            Ok(ClientDirective::Hello {
+                version: 1,
+                encoding: Encoding::Binary,
+                capabilities: vec![Capability::Replay],
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_messages_rejected() {
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&[]),
+            Err(Error::Truncated { offset: 0 })
+        );
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&[0x7f]),
+            Err(Error::UnknownTag {
+                offset: 0,
+                tag: 0x7f
+            })
+        );
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&[tag::JOIN, 0]),
+            Err(Error::TrailingBytes { offset: 1 })
+        );
+        // Length claims more bytes than the message has
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&[
+                tag::LOGIN,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                b'a'
+            ]),
+            Err(Error::Truncated { offset: 5 })
+        );
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&[tag::LOGIN, 1, 0, 0, 0, 0xc3]),
+            Err(Error::InvalidString { offset: 1 })
+        );
+        let mut bad_program = vec![tag::SUBMIT_PROGRAM];
+        bad_program.extend_from_slice(&[0; 8]);
+        bad_program.extend_from_slice(&[1, 0, 0, 0, 0xee]);
+        assert_eq!(
+            BinaryProtocol::try_client_directive_from_bytes(&bad_program),
+            Err(Error::InvalidProgram { offset: 9 })
+        );
+
+        let mut nested = vec![tag::SEQUENCED];
+        nested.extend_from_slice(&[0; 8]);
+        nested.push(tag::SEQUENCED);
+        assert_eq!(
+            BinaryProtocol::try_client_notification_from_bytes(&nested),
+            Err(Error::NestedSequenced { offset: 9 })
+        );
+    }
+
+    #[test]
+    fn truncated_and_mutated_messages_never_panic() {
+        for message in encoded_messages() {
+            for len in 0..message.len() {
+                assert!(BinaryProtocol::try_client_directive_from_bytes(&message[..len]).is_err());
+                assert!(
+                    BinaryProtocol::try_client_notification_from_bytes(&message[..len]).is_err()
+                );
+            }
+            for idx in 0..message.len() {
+                for value in &[0x00, 0x01, 0x7f, 0x80, 0xff] {
+                    let mut mutated = message.clone();
+                    mutated[idx] = *value;
+                    decode_any(&mutated);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        // xorshift, so failures reproduce
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let tags = [
+            tag::LOGIN,
+            tag::SUBMIT_PROGRAM,
+            tag::TRADE,
+            tag::REJECTED,
+            tag::SEQUENCED,
+        ];
+        for _ in 0..20_000 {
+            let len = (next() % 64) as usize;
+            let mut bytes: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Bias towards valid tags so decoding gets past the first byte
+            if let Some(first) = bytes.first_mut() {
+                *first = tags[(next() % tags.len() as u64) as usize];
+            }
+            decode_any(&bytes);
+        }
+    }
+}
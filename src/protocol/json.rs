@@ -1,32 +1,67 @@
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
-use serde_json::{self, error as serde_error};
 
-use super::{ClientDirective, ClientNotification, WireProtocol};
+use super::{Capability, ClientDirective, ClientNotification, Encoding, WireProtocol};
 use crate::auction::Side;
+use crate::clock::Timestamp;
+use crate::participant::ParticipantId;
+use crate::vm::Program;
 use crate::{Price, ProductId};
 
+/// One JSON object per message, tagged by its `"type"`.  See `doc/protocol.md` for the schema.
 pub struct JsonProtocol;
 
 #[derive(Debug)]
 pub enum Error {
-    JsonDeserializeError,
-    Other,
+    /// Not JSON, or not matching the schema.  `line` and `column` are 1-based, or 0 where serde
+    /// can't place the error, which is the case for most schema errors since the `"type"` tag can
+    /// come after the fields it governs.
+    Json {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// A `SubmitProgram` whose program doesn't assemble, at 1-based `line` of the program text
+    InvalidProgram { line: usize, text: String },
 }
 
-impl From<serde_error::Error> for Error {
+impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
-        match e.classify() {
-            serde_error::Category::Data | serde_error::Category::Syntax => {
-                Error::JsonDeserializeError
-            }
-            serde_error::Category::Io | serde_error::Category::Eof => Error::Other,
+        let (line, column) = (e.line(), e.column());
+        let message = e.to_string();
+        // serde_json appends the position, which is kept separately here
+        let location = format!(" at line {} column {}", line, column);
+        let message = match message.strip_suffix(&location) {
+            Some(message) => message.to_owned(),
+            None => message,
+        };
+        Error::Json {
+            line,
+            column,
+            message,
         }
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::Json {
+                line,
+                column,
+                message,
+            } => {
+                if *line == 0 {
+                    write!(f, "{}", message)
+                } else {
+                    write!(f, "line {}, column {}: {}", line, column, message)
+                }
+            }
+            Error::InvalidProgram { line, text } => {
+                write!(f, "program line {}: invalid instruction \"{}\"", line, text)
+            }
+        }
     }
 }
 impl std::error::Error for Error {}
@@ -35,9 +70,7 @@ impl WireProtocol for JsonProtocol {
     type Error = Error;
 
     fn try_client_directive_from_bytes(bytes: &[u8]) -> Result<ClientDirective, Self::Error> {
-        let directive =
-            ClientDirective::from(&serde_json::from_slice::<JsonClientDirective>(bytes)?);
-        Ok(directive)
+        ClientDirective::try_from(serde_json::from_slice::<JsonClientDirective>(bytes)?)
     }
 
     fn try_client_directive_to_bytes(
@@ -49,7 +82,7 @@ impl WireProtocol for JsonProtocol {
 
     fn try_client_notification_from_bytes(bytes: &[u8]) -> Result<ClientNotification, Self::Error> {
         let notification =
-            ClientNotification::from(&serde_json::from_slice::<JsonClientNotification>(bytes)?);
+            ClientNotification::from(serde_json::from_slice::<JsonClientNotification>(bytes)?);
         Ok(notification)
     }
 
@@ -62,7 +95,21 @@ impl WireProtocol for JsonProtocol {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
 enum JsonClientDirective {
+    Hello {
+        version: u32,
+        encoding: Encoding,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Login {
+        name: String,
+        key: String,
+    },
+    Resume {
+        last_sequence: u64,
+    },
     Join {},
     Leave {},
     UpdateParameter {
@@ -72,13 +119,30 @@ enum JsonClientDirective {
     },
     SubmitProgram {
         product_id: u64,
-        program: Vec<u8>,
+        /// Assembly text, one instruction per line
+        program: String,
     },
 }
 
 impl From<&ClientDirective> for JsonClientDirective {
     fn from(directive: &ClientDirective) -> Self {
         match directive {
+            ClientDirective::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => JsonClientDirective::Hello {
+                version: *version,
+                encoding: *encoding,
+                capabilities: capability_names(capabilities),
+            },
+            ClientDirective::Login { name, key } => JsonClientDirective::Login {
+                name: name.clone(),
+                key: key.clone(),
+            },
+            ClientDirective::Resume { last_sequence } => JsonClientDirective::Resume {
+                last_sequence: *last_sequence,
+            },
             ClientDirective::Join {} => JsonClientDirective::Join {},
             ClientDirective::Leave {} => JsonClientDirective::Leave {},
             ClientDirective::SubmitProgram {
@@ -86,7 +150,7 @@ impl From<&ClientDirective> for JsonClientDirective {
                 program,
             } => JsonClientDirective::SubmitProgram {
                 product_id: product_id.0,
-                program: Vec::from(&program.get_string()[..]),
+                program: program.get_string(),
             },
             ClientDirective::UpdateParameter {
                 product_id,
@@ -101,58 +165,477 @@ impl From<&ClientDirective> for JsonClientDirective {
     }
 }
 
-impl From<&JsonClientDirective> for ClientDirective {
-    fn from(_: &JsonClientDirective) -> Self {
-        todo!();
+impl TryFrom<JsonClientDirective> for ClientDirective {
+    type Error = Error;
+
+    fn try_from(directive: JsonClientDirective) -> Result<Self, Self::Error> {
+        let directive = match directive {
+            JsonClientDirective::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => ClientDirective::Hello {
+                version,
+                encoding,
+                capabilities: known_capabilities(&capabilities),
+            },
+            JsonClientDirective::Login { name, key } => ClientDirective::Login { name, key },
+            JsonClientDirective::Resume { last_sequence } => {
+                ClientDirective::Resume { last_sequence }
+            }
+            JsonClientDirective::Join {} => ClientDirective::Join {},
+            JsonClientDirective::Leave {} => ClientDirective::Leave {},
+            JsonClientDirective::UpdateParameter {
+                product_id,
+                param_idx,
+                value,
+            } => ClientDirective::UpdateParameter {
+                product_id: ProductId(product_id),
+                param_idx,
+                value,
+            },
+            JsonClientDirective::SubmitProgram {
+                product_id,
+                program,
+            } => ClientDirective::SubmitProgram {
+                product_id: ProductId(product_id),
+                program: assemble(&program)?,
+            },
+        };
+        Ok(directive)
     }
 }
 
+fn capability_names(capabilities: &[Capability]) -> Vec<String> {
+    capabilities
+        .iter()
+        .filter_map(|capability| match serde_json::to_value(capability) {
+            Ok(serde_json::Value::String(name)) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Capabilities are names rather than `Capability`s in the schema so that unknown ones can be
+/// skipped instead of failing the whole message
+fn known_capabilities(names: &[String]) -> Vec<Capability> {
+    names
+        .iter()
+        .filter_map(|name| serde_json::from_value(serde_json::Value::String(name.clone())).ok())
+        .collect()
+}
+
+fn assemble(program: &str) -> Result<Program, Error> {
+    Program::try_from_str_reporting_line(program).map_err(|line| Error::InvalidProgram {
+        line,
+        text: program.lines().nth(line - 1).unwrap_or("").to_owned(),
+    })
+}
+
 #[derive(Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
 enum JsonClientNotification {
+    Hello {
+        version: u32,
+        encoding: Encoding,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
     Trade {
         product_id: u64,
-        side: String,
+        side: JsonSide,
         price: u64,
         quantity: u64,
+        /// Nanoseconds since the Unix epoch
+        timestamp: u64,
+    },
+    LoggedIn {
+        participant_id: u64,
+        last_sequence: u64,
+    },
+    Rejected {
+        reason: String,
+    },
+    Sequenced {
+        sequence: u64,
+        notification: Box<JsonClientNotification>,
     },
 }
 
+#[derive(Serialize, Deserialize)]
+enum JsonSide {
+    Bid,
+    Offer,
+}
+
 impl From<&ClientNotification> for JsonClientNotification {
     fn from(n: &ClientNotification) -> Self {
         match n {
+            ClientNotification::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => JsonClientNotification::Hello {
+                version: *version,
+                encoding: *encoding,
+                capabilities: capability_names(capabilities),
+            },
             ClientNotification::Trade {
                 product_id,
                 price,
                 quantity,
                 side,
+                timestamp,
             } => JsonClientNotification::Trade {
                 product_id: product_id.0,
                 price: price.0,
                 quantity: *quantity,
-                side: side.to_string(),
+                side: match side {
+                    Side::Bid => JsonSide::Bid,
+                    Side::Offer => JsonSide::Offer,
+                },
+                timestamp: timestamp.0,
+            },
+            ClientNotification::LoggedIn {
+                participant_id,
+                last_sequence,
+            } => JsonClientNotification::LoggedIn {
+                participant_id: participant_id.0,
+                last_sequence: *last_sequence,
+            },
+            ClientNotification::Rejected { reason } => JsonClientNotification::Rejected {
+                reason: reason.clone(),
+            },
+            ClientNotification::Sequenced {
+                sequence,
+                notification,
+            } => JsonClientNotification::Sequenced {
+                sequence: *sequence,
+                notification: Box::new(JsonClientNotification::from(&**notification)),
             },
         }
     }
 }
 
-impl From<&JsonClientNotification> for ClientNotification {
-    fn from(n: &JsonClientNotification) -> Self {
+impl From<JsonClientNotification> for ClientNotification {
+    fn from(n: JsonClientNotification) -> Self {
         match n {
+            JsonClientNotification::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => ClientNotification::Hello {
+                version,
+                encoding,
+                capabilities: known_capabilities(&capabilities),
+            },
             JsonClientNotification::Trade {
                 product_id,
                 side,
                 price,
                 quantity,
+                timestamp,
             } => ClientNotification::Trade {
-                product_id: ProductId(*product_id),
-                side: match &side[..] {
-                    "Bid" => Side::Bid,
-                    "Offer" => Side::Offer,
-                    _ => panic!(),
+                product_id: ProductId(product_id),
+                side: match side {
+                    JsonSide::Bid => Side::Bid,
+                    JsonSide::Offer => Side::Offer,
                 },
-                price: Price(*price),
-                quantity: *quantity,
+                price: Price(price),
+                quantity,
+                timestamp: Timestamp(timestamp),
+            },
+            JsonClientNotification::LoggedIn {
+                participant_id,
+                last_sequence,
+            } => ClientNotification::LoggedIn {
+                participant_id: ParticipantId(participant_id),
+                last_sequence,
+            },
+            JsonClientNotification::Rejected { reason } => ClientNotification::Rejected { reason },
+            JsonClientNotification::Sequenced {
+                sequence,
+                notification,
+            } => ClientNotification::Sequenced {
+                sequence,
+                notification: Box::new(ClientNotification::from(*notification)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+    use crate::vm::{Instruction, RegIdx};
+
+    fn program() -> Program {
+        Program::from_instructions(&[
+            Instruction::MovImm {
+                dst: RegIdx(0),
+                imm: -3,
             },
+            Instruction::ArrIns {
+                val: RegIdx(0),
+                arr: RegIdx(1),
+                idx: RegIdx(2),
+            },
+            Instruction::Halt {},
+        ])
+    }
+
+    fn directives() -> Vec<ClientDirective> {
+        vec![
+            ClientDirective::Login {
+                name: "alice".to_owned(),
+                key: "secret \"quoted\"".to_owned(),
+            },
+            ClientDirective::Resume { last_sequence: 42 },
+            ClientDirective::Join {},
+            ClientDirective::Leave {},
+            ClientDirective::UpdateParameter {
+                product_id: ProductId(1),
+                param_idx: 2,
+                value: -3,
+            },
+            ClientDirective::SubmitProgram {
+                product_id: ProductId(1),
+                program: program(),
+            },
+            ClientDirective::Hello {
+                version: PROTOCOL_VERSION,
+                encoding: Encoding::Binary,
+                capabilities: vec![Capability::Replay, Capability::BookSnapshots],
+            },
+        ]
+    }
+
+    fn notifications() -> Vec<ClientNotification> {
+        let trade = ClientNotification::Trade {
+            product_id: ProductId(1),
+            side: Side::Offer,
+            price: Price(101),
+            quantity: 5,
+            timestamp: Timestamp(1_600_000_000_000_000_000),
+        };
+        vec![
+            trade.clone(),
+            ClientNotification::Trade {
+                product_id: ProductId(2),
+                side: Side::Bid,
+                price: Price(0),
+                quantity: u64::MAX,
+                timestamp: Timestamp(0),
+            },
+            ClientNotification::LoggedIn {
+                participant_id: ParticipantId(7),
+                last_sequence: 3,
+            },
+            ClientNotification::Rejected {
+                reason: "not joined".to_owned(),
+            },
+            ClientNotification::Sequenced {
+                sequence: 4,
+                notification: Box::new(trade),
+            },
+            ClientNotification::Hello {
+                version: PROTOCOL_VERSION,
+                encoding: Encoding::Json,
+                capabilities: vec![],
+            },
+        ]
+    }
+
+    fn decode_directive(json: &str) -> Result<ClientDirective, Error> {
+        JsonProtocol::try_client_directive_from_bytes(json.as_bytes())
+    }
+
+    #[test]
+    fn directives_round_trip() {
+        for directive in directives() {
+            let bytes = JsonProtocol::try_client_directive_to_bytes(&directive).unwrap();
+            let decoded = JsonProtocol::try_client_directive_from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, directive, "{}", String::from_utf8_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn notifications_round_trip() {
+        for notification in notifications() {
+            let bytes = JsonProtocol::try_client_notification_to_bytes(&notification).unwrap();
+            let decoded = JsonProtocol::try_client_notification_from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, notification, "{}", String::from_utf8_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn schema_is_internally_tagged() {
+        let bytes = JsonProtocol::try_client_directive_to_bytes(&ClientDirective::Join {}).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), r#"{"type":"Join"}"#);
+
+        let bytes = JsonProtocol::try_client_notification_to_bytes(&notifications()[4]).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            concat!(
+                r#"{"type":"Sequenced","sequence":4,"notification":"#,
+                r#"{"type":"Trade","product_id":1,"side":"Offer","price":101,"quantity":5,"#,
+                r#""timestamp":1600000000000000000}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn decode_documented_examples() {
+        assert_eq!(
+            decode_directive(
+                r#"{"type": "SubmitProgram", "product_id": 1, "program": "movimm r0 -3\narrins r0 r1 r2\nhalt"}"#
+            )
+            .unwrap(),
+            ClientDirective::SubmitProgram {
+                product_id: ProductId(1),
+                program: program(),
+            }
+        );
+        assert_eq!(
+            decode_directive(
+                r#"{"type": "UpdateParameter", "product_id": 1, "param_idx": 0, "value": 100}"#
+            )
+            .unwrap(),
+            ClientDirective::UpdateParameter {
+                product_id: ProductId(1),
+                param_idx: 0,
+                value: 100,
+            }
+        );
+        assert_eq!(
+            decode_directive(r#"{"type": "Login", "name": "alice", "key": "secret"}"#).unwrap(),
+            ClientDirective::Login {
+                name: "alice".to_owned(),
+                key: "secret".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_capabilities_skipped() {
+        assert_eq!(
+            decode_directive(
+                r#"{"type": "Hello", "version": 1, "encoding": "json", "capabilities": ["teleport", "replay"]}"#
+            )
+            .unwrap(),
+            ClientDirective::Hello {
+                version: 1,
+                encoding: Encoding::Json,
+                capabilities: vec![Capability::Replay],
+            }
+        );
+        assert_eq!(
+            decode_directive(r#"{"type": "Hello", "version": 1, "encoding": "binary"}"#).unwrap(),
+            ClientDirective::Hello {
+                version: 1,
+                encoding: Encoding::Binary,
+                capabilities: vec![],
+            }
+        );
+        assert!(decode_directive(r#"{"type": "Hello", "version": 1, "encoding": "xml"}"#).is_err());
+    }
+
+    #[test]
+    fn errors_carry_position_and_message() {
+        match decode_directive("{\"type\": \"Join\",\n \"extra\" 1}") {
+            Err(Error::Json {
+                line,
+                column,
+                message,
+            }) => {
+                assert_eq!((line, column), (2, 10));
+                assert!(message.contains("expected `:`"), "{}", message);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let error = decode_directive("\n\n{\"type\": \"Join\"").unwrap_err();
+        assert!(
+            error.to_string().starts_with("line 3, column "),
+            "{}",
+            error
+        );
+
+        for (json, expected) in &[
+            (r#"{"type": "Join", "extra": 1}"#, "extra"),
+            (r#"{"type": "Dance"}"#, "Dance"),
+            (r#"{"product_id": 1}"#, "type"),
+            (r#"{"type": "Resume", "last_sequence": -1}"#, "-1"),
+        ] {
+            match decode_directive(json) {
+                Err(Error::Json { message, .. }) => {
+                    assert!(message.contains(expected), "{}: {}", json, message)
+                }
+                other => panic!("Unexpected result {:?} for {}", other, json),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_side_is_an_error() {
+        let result = JsonProtocol::try_client_notification_from_bytes(
+            br#"{"type": "Trade", "product_id": 1, "side": "Sideways", "price": 1, "quantity": 1, "timestamp": 0}"#,
+        );
+        assert!(matches!(result, Err(Error::Json { .. })));
+    }
+
+    #[test]
+    fn labelled_program_round_trip() {
+        let assembly = concat!(
+            "movimm r1 100\nmovimm r0 quote\ncall r0\nhalt\n\n",
+            "quote:\n    movimm r2 1\n    movimm r0 9\n    arrins r2 r0 r1\n    ret",
+        );
+        let json = serde_json::json!({
+            "type": "SubmitProgram",
+            "product_id": 1,
+            "program": assembly,
+        })
+        .to_string();
+        let directive = decode_directive(&json).unwrap();
+        assert_eq!(
+            directive,
+            ClientDirective::SubmitProgram {
+                product_id: ProductId(1),
+                program: Program::try_from_str(assembly).unwrap(),
+            }
+        );
+
+        let bytes = JsonProtocol::try_client_directive_to_bytes(&directive).unwrap();
+        assert_eq!(
+            JsonProtocol::try_client_directive_from_bytes(&bytes).unwrap(),
+            directive
+        );
+    }
+
+    #[test]
+    fn unknown_label_reports_line() {
+        match decode_directive(
+            r#"{"type": "SubmitProgram", "product_id": 1, "program": "start:\nmovimm r0 finish\njmp r0"}"#,
+        ) {
+            Err(Error::InvalidProgram { line, text }) => {
+                assert_eq!(line, 2);
+                assert_eq!(text, "movimm r0 finish");
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_program_reports_line() {
+        match decode_directive(
+            r#"{"type": "SubmitProgram", "product_id": 1, "program": "halt\n\nmov r0 r99"}"#,
+        ) {
+            Err(Error::InvalidProgram { line, text }) => {
+                assert_eq!(line, 3);
+                assert_eq!(text, "mov r0 r99");
+            }
+            other => panic!("Unexpected result {:?}", other),
         }
     }
 }
@@ -1,6 +1,11 @@
+pub mod binary;
 pub mod json;
 
+use serde::{Deserialize, Serialize};
+
 use crate::auction::Side;
+use crate::clock::Timestamp;
+use crate::participant::ParticipantId;
 use crate::vm::Program;
 use crate::{Price, ProductId};
 
@@ -16,8 +21,129 @@ pub trait WireProtocol {
     ) -> Result<Vec<u8>, Self::Error>;
 }
 
+/// The version of the messages below, which a client and the exchange agree on with `Hello`.
+/// Clients which never send `Hello` are treated as speaking version 1.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The `WireProtocol` a connection uses, chosen by the client's `Hello`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    Binary,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 2] = [Encoding::Json, Encoding::Binary];
+
+    pub fn try_client_directive_from_bytes(&self, bytes: &[u8]) -> Result<ClientDirective, Error> {
+        match self {
+            Encoding::Json => Ok(json::JsonProtocol::try_client_directive_from_bytes(bytes)?),
+            Encoding::Binary => Ok(binary::BinaryProtocol::try_client_directive_from_bytes(
+                bytes,
+            )?),
+        }
+    }
+
+    pub fn try_client_directive_to_bytes(
+        &self,
+        client_directive: &ClientDirective,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Json => Ok(json::JsonProtocol::try_client_directive_to_bytes(
+                client_directive,
+            )?),
+            Encoding::Binary => Ok(binary::BinaryProtocol::try_client_directive_to_bytes(
+                client_directive,
+            )?),
+        }
+    }
+
+    pub fn try_client_notification_from_bytes(
+        &self,
+        bytes: &[u8],
+    ) -> Result<ClientNotification, Error> {
+        match self {
+            Encoding::Json => Ok(json::JsonProtocol::try_client_notification_from_bytes(
+                bytes,
+            )?),
+            Encoding::Binary => Ok(binary::BinaryProtocol::try_client_notification_from_bytes(
+                bytes,
+            )?),
+        }
+    }
+
+    pub fn try_client_notification_to_bytes(
+        &self,
+        client_notification: &ClientNotification,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Json => Ok(json::JsonProtocol::try_client_notification_to_bytes(
+                client_notification,
+            )?),
+            Encoding::Binary => Ok(binary::BinaryProtocol::try_client_notification_to_bytes(
+                client_notification,
+            )?),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Json(json::Error),
+    Binary(binary::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Binary(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl From<json::Error> for Error {
+    fn from(e: json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+impl From<binary::Error> for Error {
+    fn from(e: binary::Error) -> Self {
+        Error::Binary(e)
+    }
+}
+
+/// Optional features a client can ask for in its `Hello`.  Decoders skip capabilities they don't
+/// know, so a client may ask for ones only newer exchanges provide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Snapshots of the order books
+    BookSnapshots,
+    /// Replaying missed notifications with `Resume`
+    Replay,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ClientDirective {
+    /// Asks for a protocol `version`, the `encoding` of every later message on the connection and
+    /// some optional `capabilities`, which the exchange answers with its own `Hello`
+    Hello {
+        version: u32,
+        encoding: Encoding,
+        capabilities: Vec<Capability>,
+    },
+    /// Authenticates the connection as the named participant, see `server::credentials`
+    Login {
+        name: String,
+        key: String,
+    },
+    /// Asks for the notifications numbered after `last_sequence` to be sent again, after
+    /// logging in on a new connection
+    Resume {
+        last_sequence: u64,
+    },
     Join {},
     Leave {},
     UpdateParameter {
@@ -33,10 +159,30 @@ pub enum ClientDirective {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ClientNotification {
+    /// The protocol `version` and `encoding` in use from here on, and those of the requested
+    /// `capabilities` which the exchange provides
+    Hello {
+        version: u32,
+        encoding: Encoding,
+        capabilities: Vec<Capability>,
+    },
     Trade {
         product_id: ProductId,
         side: Side,
         price: Price,
         quantity: u64,
+        timestamp: Timestamp,
+    },
+    /// `last_sequence` numbers the latest notification sent to the participant, on any connection
+    LoggedIn {
+        participant_id: ParticipantId,
+        last_sequence: u64,
+    },
+    /// A directive which was not applied
+    Rejected { reason: String },
+    /// A notification from the exchange, numbered consecutively per participant from 1
+    Sequenced {
+        sequence: u64,
+        notification: Box<ClientNotification>,
     },
 }
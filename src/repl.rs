@@ -0,0 +1,280 @@
+use std::fs;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use chrono::TimeZone;
+
+use vmx::auction::Side;
+use vmx::client::{self, Client};
+use vmx::protocol::ClientNotification;
+use vmx::ProductId;
+
+/// How long to wait for input before checking for notifications again
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const HELP: &str = "\
+commands:
+  login NAME KEY               authenticate, if the exchange has credentials
+  join                         join the auction
+  leave                        leave the auction
+  submit PRODUCT FILE          submit the program in FILE for a product
+  set PRODUCT PARAM VALUE      update one of a product's parameters
+  resume [SEQUENCE]            replay notifications after SEQUENCE, by default the last received
+  help                         show this message
+  quit                         disconnect";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Login {
+        name: String,
+        key: String,
+    },
+    Join,
+    Leave,
+    Submit {
+        product_id: ProductId,
+        path: String,
+    },
+    Set {
+        product_id: ProductId,
+        param_idx: u64,
+        value: i64,
+    },
+    Resume {
+        last_sequence: Option<u64>,
+    },
+    Help,
+    Quit,
+}
+
+/// Reads commands from stdin and prints notifications as they arrive, until stdin ends, `quit` or
+/// the exchange closes the connection
+pub fn run(mut client: Client) -> Result<(), String> {
+    let (line_sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line_sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    println!("Connected, type help for commands");
+
+    loop {
+        match lines.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => match parse_command(&line) {
+                Ok(Some(Command::Quit)) => return Ok(()),
+                Ok(Some(command)) => {
+                    if let Err(e) = execute(&mut client, command) {
+                        println!("error: {}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => println!("error: {}", e),
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        match client.poll_notifications() {
+            Ok(notifications) => {
+                for notification in notifications {
+                    println!("{}", describe_notification(&notification));
+                }
+            }
+            Err(client::Error::Closed) => {
+                println!("The exchange closed the connection");
+                return Ok(());
+            }
+            Err(e) => return Err(format!("connection failed: {}", e)),
+        }
+    }
+}
+
+fn execute(client: &mut Client, command: Command) -> Result<(), String> {
+    match command {
+        Command::Login { name, key } => {
+            let participant_id = client.login(&name, &key).map_err(|e| e.to_string())?;
+            println!("Logged in as participant {}", participant_id.0);
+            return Ok(());
+        }
+        Command::Join => client.join(),
+        Command::Leave => client.leave(),
+        Command::Submit { product_id, path } => {
+            let program = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            client.submit_program_text(product_id, &program)
+        }
+        Command::Set {
+            product_id,
+            param_idx,
+            value,
+        } => client.update_parameter(product_id, param_idx, value),
+        Command::Resume { last_sequence } => {
+            client.resume(last_sequence.unwrap_or_else(|| client.last_sequence()))
+        }
+        Command::Help => {
+            println!("{}", HELP);
+            return Ok(());
+        }
+        Command::Quit => unreachable!("handled by run"),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words[..] {
+        [] => return Ok(None),
+        ["login", name, key] => Command::Login {
+            name: name.to_owned(),
+            key: key.to_owned(),
+        },
+        ["join"] => Command::Join,
+        ["leave"] => Command::Leave,
+        ["submit", product_id, path] => Command::Submit {
+            product_id: ProductId(parse_word(product_id, "product")?),
+            path: path.to_owned(),
+        },
+        ["set", product_id, param_idx, value] => Command::Set {
+            product_id: ProductId(parse_word(product_id, "product")?),
+            param_idx: parse_word(param_idx, "parameter")?,
+            value: parse_word(value, "value")?,
+        },
+        ["resume"] => Command::Resume {
+            last_sequence: None,
+        },
+        ["resume", last_sequence] => Command::Resume {
+            last_sequence: Some(parse_word(last_sequence, "sequence")?),
+        },
+        ["help"] => Command::Help,
+        ["quit"] | ["exit"] => Command::Quit,
+        _ => return Err(format!("unknown command \"{}\", try help", line.trim())),
+    };
+    Ok(Some(command))
+}
+
+fn parse_word<T: std::str::FromStr>(word: &str, what: &str) -> Result<T, String> {
+    word.parse::<T>()
+        .map_err(|_| format!("invalid {} \"{}\"", what, word))
+}
+
+fn describe_notification(notification: &ClientNotification) -> String {
+    match notification {
+        ClientNotification::Trade {
+            product_id,
+            side,
+            price,
+            quantity,
+            timestamp,
+        } => format!(
+            "{} product {}: {} {} @ {}",
+            chrono::Utc
+                .timestamp_nanos(timestamp.0 as i64)
+                .format("%H:%M:%S%.3f"),
+            product_id.0,
+            match side {
+                Side::Bid => "bought",
+                Side::Offer => "sold",
+            },
+            quantity,
+            price.0
+        ),
+        ClientNotification::LoggedIn {
+            participant_id,
+            last_sequence,
+        } => format!(
+            "logged in as participant {}, latest notification {}",
+            participant_id.0, last_sequence
+        ),
+        ClientNotification::Rejected { reason } => format!("rejected: {}", reason),
+        // The client keeps these to itself, but describe them anyway
+        ClientNotification::Hello {
+            version, encoding, ..
+        } => format!("protocol version {}, {:?} encoding", version, encoding),
+        ClientNotification::Sequenced {
+            sequence,
+            notification,
+        } => format!("#{} {}", sequence, describe_notification(notification)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmx::clock::Timestamp;
+    use vmx::Price;
+
+    #[test]
+    fn commands_parsed() {
+        assert_eq!(parse_command("  ").unwrap(), None);
+        assert_eq!(parse_command("join").unwrap(), Some(Command::Join));
+        assert_eq!(
+            parse_command("login alice secret").unwrap(),
+            Some(Command::Login {
+                name: "alice".to_owned(),
+                key: "secret".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_command("submit 1 strategies/mm.vmx").unwrap(),
+            Some(Command::Submit {
+                product_id: ProductId(1),
+                path: "strategies/mm.vmx".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_command("set 1 2 -30").unwrap(),
+            Some(Command::Set {
+                product_id: ProductId(1),
+                param_idx: 2,
+                value: -30
+            })
+        );
+        assert_eq!(
+            parse_command("resume").unwrap(),
+            Some(Command::Resume {
+                last_sequence: None
+            })
+        );
+        assert_eq!(
+            parse_command("resume 12").unwrap(),
+            Some(Command::Resume {
+                last_sequence: Some(12)
+            })
+        );
+        assert_eq!(parse_command("quit").unwrap(), Some(Command::Quit));
+
+        assert!(parse_command("set 1 x 3").is_err());
+        assert!(parse_command("submit 1").is_err());
+        assert!(parse_command("buy 100").is_err());
+    }
+
+    #[test]
+    fn notifications_described() {
+        let trade = ClientNotification::Trade {
+            product_id: ProductId(3),
+            side: Side::Offer,
+            price: Price(101),
+            quantity: 20,
+            timestamp: Timestamp(3_723_004_000_000),
+        };
+        assert_eq!(
+            describe_notification(&trade),
+            "01:02:03.004 product 3: sold 20 @ 101"
+        );
+        assert_eq!(
+            describe_notification(&ClientNotification::Sequenced {
+                sequence: 4,
+                notification: Box::new(ClientNotification::Rejected {
+                    reason: "not joined".to_owned()
+                }),
+            }),
+            "#4 rejected: not joined"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::configuration::{self, Error};
+use crate::participant::ParticipantId;
+
+/// Participants allowed to log in, as read from a TOML or JSON file.  Each participant keeps its
+/// `id` across connections and restarts.
+/// ```toml
+/// [[participants]]
+/// name = "alice"
+/// id = 1
+/// key = "a long random pre-shared key"
+/// ```
+/// Keys are stored as given, so the file should only be readable by the exchange.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    pub participants: Vec<ParticipantCredentials>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParticipantCredentials {
+    pub name: String,
+    pub id: ParticipantId,
+    pub key: String,
+}
+
+impl Credentials {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        configuration::read_file(path)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems: Vec<String> = Vec::default();
+        let mut names = HashSet::new();
+        let mut ids = HashSet::new();
+        for participant in &self.participants {
+            if participant.name.is_empty() {
+                problems.push(format!(
+                    "participant {} has an empty name",
+                    participant.id.0
+                ));
+            } else if !names.insert(&participant.name) {
+                problems.push(format!(
+                    "participant name \"{}\" is used more than once",
+                    participant.name
+                ));
+            }
+            if !ids.insert(participant.id) {
+                problems.push(format!(
+                    "participant id {} is used more than once",
+                    participant.id.0
+                ));
+            }
+            if participant.key.is_empty() {
+                problems.push(format!(
+                    "participant \"{}\" has an empty key",
+                    participant.name
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid(problems))
+        }
+    }
+
+    pub fn authenticate(&self, name: &str, key: &str) -> Option<ParticipantId> {
+        self.participants
+            .iter()
+            .find(|participant| participant.name == name)
+            .filter(|participant| constant_time_eq(participant.key.as_bytes(), key.as_bytes()))
+            .map(|participant| participant.id)
+    }
+}
+
+/// Compares without returning early, so response times don't reveal how much of a key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> Credentials {
+        toml::from_str(
+            r#"
+            [[participants]]
+            name = "alice"
+            id = 7
+            key = "alice-key"
+
+            [[participants]]
+            name = "bob"
+            id = 8
+            key = "bob-key"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn authenticate() {
+        let credentials = credentials();
+        assert!(credentials.validate().is_ok());
+        assert_eq!(
+            credentials.authenticate("alice", "alice-key"),
+            Some(ParticipantId(7))
+        );
+        assert_eq!(
+            credentials.authenticate("bob", "bob-key"),
+            Some(ParticipantId(8))
+        );
+        assert_eq!(credentials.authenticate("alice", "bob-key"), None);
+        assert_eq!(credentials.authenticate("alice", "alice-ke"), None);
+        assert_eq!(credentials.authenticate("carol", "alice-key"), None);
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let mut credentials = credentials();
+        credentials.participants.push(ParticipantCredentials {
+            name: "alice".to_owned(),
+            id: ParticipantId(7),
+            key: String::default(),
+        });
+        match credentials.validate() {
+            Err(Error::Invalid(problems)) => assert_eq!(problems.len(), 3, "{:?}", problems),
+            other => panic!("Unexpected validation result {:?}", other),
+        }
+    }
+}
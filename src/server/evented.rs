@@ -0,0 +1,864 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+
+use super::credentials::Credentials;
+use super::framing::FrameDecoder;
+use super::session::Sessions;
+use super::{ClientId, IncomingMessage, OutgoingMessage, Server as ServerTrait, ServerConfig};
+use crate::participant::{ParticipantId, ParticipantPool};
+use crate::protocol::json::JsonProtocol;
+use crate::protocol::{ClientDirective, ClientNotification, WireProtocol};
+
+const LISTENER: Token = Token(usize::MAX);
+const EVENTS_CAPACITY: usize = 1024;
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// Reads from one client per readiness event, so a client sending faster than it's read can't
+/// keep the others waiting
+const MAX_READS_PER_EVENT: usize = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    Net,
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for Error {}
+
+/// Single-threaded TCP server multiplexing every connection over one readiness poll.  Sockets
+/// are only serviced from `poll`, which `drain_pending_messages` calls without blocking and
+/// `wait` calls blocking, so the server does nothing between calls.  `wait` also answers session
+/// directives such as `Hello` and `Login` straight away, keeping the others until they're popped.
+///
+/// Notifications which can't be written straight away are queued per client.  A client whose
+/// queue would grow past `ServerConfig::max_outbound_buffer` is too slow to keep up with the
+/// auctions and is disconnected, rather than holding up everyone else.
+///
+/// Likewise each client is read from at most `MAX_READS_PER_EVENT` times per poll, and not at
+/// all once `ServerConfig::max_pending_messages` of its messages are waiting to be handled.
+/// What's left stays on its socket, so a client sending too fast is held back by TCP rather than
+/// by the server's memory, and is read again by a later poll.
+pub struct Server<W = JsonProtocol>
+where
+    W: WireProtocol,
+{
+    config: ServerConfig,
+    poll: Option<Poll>,
+    events: Events,
+    listener: Option<TcpListener>,
+    local_addr: Option<SocketAddr>,
+    next_client_id: u64,
+    connections: HashMap<ClientId, Connection>,
+    pending_messages: Vec<IncomingMessage>,
+    /// Clients which stopped being read before their socket was empty.  Readiness is edge
+    /// triggered, so they won't be reported again until they send more.
+    unread_clients: Vec<ClientId>,
+    disconnected_clients: Vec<ClientId>,
+    sessions: Sessions,
+    /// Decoded by `wait` and not yet popped
+    pending_directives: Vec<(ParticipantId, ClientDirective)>,
+    protocol: PhantomData<W>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    frame_decoder: FrameDecoder,
+    outbound: Vec<u8>,
+    awaiting_writable: bool,
+    /// Messages in `Server::pending_messages` from this client
+    num_pending_messages: usize,
+}
+
+impl<W> Server<W>
+where
+    W: WireProtocol,
+{
+    pub fn new(config: ServerConfig) -> Self {
+        let sessions = Sessions::new(
+            None,
+            config.session_outbox_size,
+            config.framing.is_binary_safe(),
+        );
+        Self::with_sessions(config, sessions)
+    }
+
+    /// Only participants listed in `credentials` may log in and act
+    pub fn with_credentials(config: ServerConfig, credentials: Credentials) -> Self {
+        let sessions = Sessions::new(
+            Some(credentials),
+            config.session_outbox_size,
+            config.framing.is_binary_safe(),
+        );
+        Self::with_sessions(config, sessions)
+    }
+
+    fn with_sessions(config: ServerConfig, sessions: Sessions) -> Self {
+        Self {
+            config,
+            poll: None,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            listener: None,
+            local_addr: None,
+            next_client_id: 0,
+            connections: HashMap::default(),
+            pending_messages: Vec::default(),
+            unread_clients: Vec::default(),
+            disconnected_clients: Vec::default(),
+            sessions,
+            pending_directives: Vec::default(),
+            protocol: PhantomData,
+        }
+    }
+
+    /// Address actually bound, which differs from the configured one when listening on port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn connected_clients(&self) -> Vec<ClientId> {
+        let mut client_ids: Vec<ClientId> = self.connections.keys().copied().collect();
+        client_ids.sort_by_key(|client_id| client_id.0);
+        client_ids
+    }
+
+    /// Bytes queued for `client_id` which the socket hasn't accepted yet
+    pub fn outbound_len(&self, client_id: ClientId) -> Option<usize> {
+        self.connections
+            .get(&client_id)
+            .map(|connection| connection.outbound.len())
+    }
+
+    /// Reads what's arrived, replying to session directives and keeping the others to be popped
+    fn decode_directives(&mut self) {
+        let messages = self.drain_pending_messages();
+        let disconnected_clients = self.disconnected_clients.drain(..).collect();
+        let (directives, replies) = self
+            .sessions
+            .decode_directives::<W>(messages, disconnected_clients);
+        self.send_notifications(&replies[..]).unwrap_or(());
+        self.pending_directives.extend(directives);
+    }
+
+    /// Waits up to `timeout` for socket readiness, then accepts new connections, reads complete
+    /// messages into the pending queue and writes queued notifications.  A `None` timeout blocks
+    /// until something happens.  Clients left unread by an earlier poll are read again without
+    /// waiting.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        let poll = match &mut self.poll {
+            Some(poll) => poll,
+            None => return Ok(()),
+        };
+        let max_pending_messages = self.config.max_pending_messages;
+        let connections = &self.connections;
+        let unread_clients_readable = self.unread_clients.iter().any(|client_id| {
+            connections.get(client_id).map_or(false, |connection| {
+                connection.num_pending_messages < max_pending_messages
+            })
+        });
+        let timeout = if unread_clients_readable {
+            Some(Duration::from_secs(0))
+        } else {
+            timeout
+        };
+        match poll.poll(&mut self.events, timeout) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(_) => return Err(Error::Net),
+        }
+
+        let ready: Vec<(Token, bool, bool)> = self
+            .events
+            .iter()
+            .map(|event| {
+                let readable = event.is_readable() || event.is_read_closed() || event.is_error();
+                (event.token(), readable, event.is_writable())
+            })
+            .collect();
+        let mut readable_clients: Vec<ClientId> = self.unread_clients.drain(..).collect();
+        for (token, readable, writable) in ready {
+            if token == LISTENER {
+                self.accept_clients();
+                continue;
+            }
+            let client_id = ClientId(token.0 as u64);
+            if readable && !readable_clients.contains(&client_id) {
+                readable_clients.push(client_id);
+            }
+            if writable {
+                self.flush_client(client_id);
+            }
+        }
+        for client_id in readable_clients {
+            self.read_from_client(client_id);
+        }
+        Ok(())
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            let listener = match &self.listener {
+                Some(listener) => listener,
+                None => return,
+            };
+            let mut stream = match listener.accept() {
+                Ok((stream, _peer_addr)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Accepting a client failed: {}", e);
+                    return;
+                }
+            };
+
+            let client_id = ClientId(self.next_client_id);
+            self.next_client_id += 1;
+            let registered = self.poll.as_ref().map(|poll| {
+                poll.registry().register(
+                    &mut stream,
+                    Token(client_id.0 as usize),
+                    Interest::READABLE,
+                )
+            });
+            if let Some(Ok(())) = registered {
+                let connection = Connection {
+                    stream,
+                    frame_decoder: FrameDecoder::new(
+                        self.config.framing,
+                        self.config.max_frame_size,
+                    ),
+                    outbound: Vec::default(),
+                    awaiting_writable: false,
+                    num_pending_messages: 0,
+                };
+                self.connections.insert(client_id, connection);
+            } else if let Some(Err(e)) = registered {
+                eprintln!("Connecting client {} failed: {}", client_id.0, e);
+            }
+        }
+    }
+
+    /// Reads until the socket is empty, unless the client has sent more than can be taken this
+    /// poll, in which case it's left for the next one
+    fn read_from_client(&mut self, client_id: ClientId) {
+        let connection = match self.connections.get_mut(&client_id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+        let mut num_reads = 0;
+        let still_open = 'reading: loop {
+            // Frames already buffered come first, so they're taken even when the socket isn't
+            loop {
+                if connection.num_pending_messages >= self.config.max_pending_messages {
+                    self.unread_clients.push(client_id);
+                    break 'reading true;
+                }
+                match connection.frame_decoder.next_frame() {
+                    Ok(Some(bytes)) => {
+                        self.pending_messages
+                            .push(IncomingMessage { client_id, bytes });
+                        connection.num_pending_messages += 1;
+                    }
+                    Ok(None) => break,
+                    // The stream can't be resynchronised after a bad frame
+                    Err(_) => break 'reading false,
+                }
+            }
+            if num_reads == MAX_READS_PER_EVENT {
+                self.unread_clients.push(client_id);
+                break true;
+            }
+            let len = match connection.stream.read(&mut buf) {
+                Ok(0) => break false,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break false,
+            };
+            num_reads += 1;
+            connection.frame_decoder.push_bytes(&buf[0..len]);
+        };
+        if !still_open {
+            self.disconnect_client(client_id);
+        }
+    }
+
+    /// Writes as much of the client's queue as the socket takes, asking to be woken when it can
+    /// take more
+    fn flush_client(&mut self, client_id: ClientId) {
+        let connection = match self.connections.get_mut(&client_id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mut written = 0;
+        let mut failed = false;
+        while written < connection.outbound.len() {
+            match connection.stream.write(&connection.outbound[written..]) {
+                Ok(0) => {
+                    failed = true;
+                    break;
+                }
+                Ok(len) => written += len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        connection.outbound.drain(..written);
+
+        let awaiting_writable = !connection.outbound.is_empty();
+        if !failed && awaiting_writable != connection.awaiting_writable {
+            let interest = if awaiting_writable {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            failed = match &self.poll {
+                Some(poll) => poll
+                    .registry()
+                    .reregister(
+                        &mut connection.stream,
+                        Token(client_id.0 as usize),
+                        interest,
+                    )
+                    .is_err(),
+                None => true,
+            };
+            connection.awaiting_writable = awaiting_writable;
+        }
+        if failed {
+            self.disconnect_client(client_id);
+        }
+    }
+
+    fn disconnect_client(&mut self, client_id: ClientId) {
+        if let Some(mut connection) = self.connections.remove(&client_id) {
+            if let Some(poll) = &self.poll {
+                poll.registry()
+                    .deregister(&mut connection.stream)
+                    .unwrap_or(());
+            }
+            connection.stream.shutdown(Shutdown::Both).unwrap_or(());
+            self.disconnected_clients.push(client_id);
+        }
+    }
+}
+
+impl<W> ServerTrait for Server<W>
+where
+    W: WireProtocol,
+{
+    type Error = self::Error;
+
+    fn start_listening(&mut self) -> Result<(), Self::Error> {
+        assert!(self.listener.is_none());
+        if self.poll.is_none() {
+            self.poll = Some(Poll::new().map_err(|_| Error::Net)?);
+        }
+        let std_listener =
+            std::net::TcpListener::bind(format!("{}:{}", &self.config.ip, self.config.port))
+                .map_err(|_| Error::Net)?;
+        std_listener.set_nonblocking(true).map_err(|_| Error::Net)?;
+        let mut listener = TcpListener::from_std(std_listener);
+        self.local_addr = Some(listener.local_addr().map_err(|_| Error::Net)?);
+        if let Some(poll) = &self.poll {
+            poll.registry()
+                .register(&mut listener, LISTENER, Interest::READABLE)
+                .map_err(|_| Error::Net)?;
+        }
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    /// Closes the listening socket and every client connection.  Clients are reported as
+    /// disconnected by the next `pop_all_directives`.
+    fn stop_listening(&mut self) -> Result<(), Self::Error> {
+        let mut listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        if let Some(poll) = &self.poll {
+            poll.registry().deregister(&mut listener).unwrap_or(());
+        }
+        for client_id in self.connected_clients() {
+            self.disconnect_client(client_id);
+        }
+        self.local_addr = None;
+        Ok(())
+    }
+
+    fn drain_pending_messages(&mut self) -> Vec<IncomingMessage> {
+        self.poll(Some(Duration::from_secs(0))).unwrap_or(());
+        for connection in self.connections.values_mut() {
+            connection.num_pending_messages = 0;
+        }
+        self.pending_messages.drain(..).collect()
+    }
+
+    /// Queues notifications and writes as much as each socket takes without blocking.
+    /// Notifications for clients which have disconnected are dropped.
+    fn send_notifications(&mut self, notifications: &[OutgoingMessage]) -> Result<(), Self::Error> {
+        let mut touched_clients: Vec<ClientId> = Vec::default();
+        for notification in notifications {
+            let connection = match self.connections.get_mut(&notification.client_id) {
+                Some(connection) => connection,
+                None => continue,
+            };
+            let frame = self.config.framing.encode(&notification.bytes[..]);
+            if connection.outbound.len() + frame.len() > self.config.max_outbound_buffer {
+                self.disconnect_client(notification.client_id);
+                continue;
+            }
+            connection.outbound.extend_from_slice(&frame);
+            if !touched_clients.contains(&notification.client_id) {
+                touched_clients.push(notification.client_id);
+            }
+        }
+        for client_id in touched_clients {
+            self.flush_client(client_id);
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), Self::Error> {
+        self.poll(Some(timeout))?;
+        self.decode_directives();
+        Ok(())
+    }
+}
+
+impl<W> ParticipantPool for Server<W>
+where
+    W: WireProtocol,
+{
+    fn pop_all_directives(&mut self) -> Vec<(ParticipantId, ClientDirective)> {
+        self.decode_directives();
+        self.pending_directives.drain(..).collect()
+    }
+
+    fn push_notifications_to_all(&mut self, notifications: &[(ParticipantId, ClientNotification)]) {
+        let outgoing_messages = self.sessions.encode_notifications::<W>(notifications);
+        self.send_notifications(&outgoing_messages[..])
+            .unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auction::Side;
+    use crate::clock::Timestamp;
+    use crate::protocol::Encoding;
+    use crate::server::framing::Framing;
+    use crate::server::test_support::{pop_directives_until, TestProtocol};
+    use crate::server::ServerMode;
+    use crate::{Price, ProductId};
+    use std::thread;
+    use std::time::Instant;
+
+    fn listening_server(config: ServerConfig) -> Server<TestProtocol> {
+        let mut server = Server::<TestProtocol>::new(ServerConfig {
+            port: 0,
+            mode: ServerMode::Evented,
+            ..config
+        });
+        server.start_listening().unwrap();
+        server
+    }
+
+    fn connect_client(server: &mut Server<TestProtocol>) -> std::net::TcpStream {
+        let num_clients = server.connected_clients().len();
+        let client = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.connected_clients().len() == num_clients {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for connection"
+            );
+            server.poll(Some(Duration::from_millis(10))).unwrap();
+        }
+        client
+    }
+
+    fn wait_for_disconnect(server: &mut Server<TestProtocol>, client_id: ClientId) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.connected_clients().contains(&client_id) {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for disconnect"
+            );
+            server.poll(Some(Duration::from_millis(10))).unwrap();
+        }
+    }
+
+    #[test]
+    fn directives_from_many_clients() {
+        let mut server = listening_server(ServerConfig::default());
+        let mut clients: Vec<std::net::TcpStream> =
+            (0..20).map(|_| connect_client(&mut server)).collect();
+        for client in &mut clients {
+            client.write_all(b"join\n").unwrap();
+        }
+
+        let mut directives = pop_directives_until(&mut server, |d| d.len() == 20);
+        directives.sort_by_key(|(participant_id, _directive)| participant_id.0);
+        let expected: Vec<(ParticipantId, ClientDirective)> = (0..20)
+            .map(|id| (ParticipantId(id), ClientDirective::Join {}))
+            .collect();
+        assert_eq!(directives, expected);
+    }
+
+    #[test]
+    fn wait_serves_clients() {
+        let mut server = listening_server(ServerConfig::default());
+        let mut client = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"join\n").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.pending_directives.is_empty() {
+            assert!(Instant::now() < deadline, "Timed out waiting for directive");
+            server.wait(Duration::from_millis(10)).unwrap();
+        }
+        assert_eq!(
+            server.pop_all_directives(),
+            vec![(ParticipantId(0), ClientDirective::Join {})]
+        );
+    }
+
+    #[test]
+    fn fragmented_frames() {
+        let mut server = listening_server(ServerConfig {
+            framing: Framing::LengthPrefixed,
+            ..ServerConfig::default()
+        });
+        let mut client = connect_client(&mut server);
+
+        let mut stream_bytes = Framing::LengthPrefixed.encode(b"join");
+        stream_bytes.extend(Framing::LengthPrefixed.encode(b"leave"));
+        let (first, rest) = stream_bytes.split_at(6);
+        client.write_all(first).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(server.pop_all_directives().is_empty());
+        client.write_all(rest).unwrap();
+
+        let directives = pop_directives_until(&mut server, |d| d.len() == 2);
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(0), ClientDirective::Join {}),
+                (ParticipantId(0), ClientDirective::Leave {}),
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_encoding_refused_with_newline_framing() {
+        let mut server = listening_server(ServerConfig::default());
+        let mut client = connect_client(&mut server);
+        let hello = ClientDirective::Hello {
+            version: 1,
+            encoding: Encoding::Binary,
+            capabilities: vec![],
+        };
+        let bytes = Encoding::Json
+            .try_client_directive_to_bytes(&hello)
+            .unwrap();
+        client
+            .write_all(&Framing::NewlineDelimited.encode(&bytes))
+            .unwrap();
+
+        let rejected = ClientNotification::Rejected {
+            reason: "binary encoding needs length-prefixed framing".to_owned(),
+        };
+        let expected = Framing::NewlineDelimited.encode(format!("{:?}", rejected).as_bytes());
+        let mut received: Vec<u8> = Vec::default();
+        client.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < expected.len() {
+            assert!(Instant::now() < deadline, "Timed out waiting for reply");
+            server.wait(Duration::from_millis(10)).unwrap();
+            let mut buf = [0u8; 256];
+            if let Ok(len) = client.read(&mut buf) {
+                received.extend_from_slice(&buf[..len]);
+            }
+        }
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn notifications_encoded_to_participant_connection() {
+        let mut server = listening_server(ServerConfig::default());
+        let mut client = connect_client(&mut server);
+
+        let notification = ClientNotification::Trade {
+            product_id: ProductId(1),
+            side: Side::Offer,
+            price: Price(100),
+            quantity: 10,
+            timestamp: Timestamp(0),
+        };
+        server.push_notifications_to_all(&[
+            (ParticipantId(0), notification.clone()),
+            (ParticipantId(123), notification.clone()),
+        ]);
+
+        let sequenced = ClientNotification::Sequenced {
+            sequence: 1,
+            notification: Box::new(notification),
+        };
+        let expected = Framing::NewlineDelimited.encode(format!("{:?}", sequenced).as_bytes());
+        let mut received = vec![0u8; expected.len()];
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn disconnect_leaves_joined_participant() {
+        let mut server = listening_server(ServerConfig::default());
+        let mut client = connect_client(&mut server);
+        client.write_all(b"join\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        drop(client);
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Leave {})]
+        );
+        assert!(server.connected_clients().is_empty());
+    }
+
+    #[test]
+    fn oversized_frame_disconnects_client() {
+        let mut server = listening_server(ServerConfig {
+            max_frame_size: 8,
+            ..ServerConfig::default()
+        });
+        let mut client = connect_client(&mut server);
+        client.write_all(b"0123456789").unwrap();
+        wait_for_disconnect(&mut server, ClientId(0));
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_per_poll_capped() {
+        let mut server = listening_server(ServerConfig::default());
+        let client = connect_client(&mut server);
+
+        // Enough to take several polls, written from another thread as the socket fills up
+        let frame = Framing::NewlineDelimited.encode(&[b'x'; 1023]);
+        // A poll may also finish a frame begun by the last one
+        let most_per_poll = MAX_READS_PER_EVENT * READ_BUFFER_SIZE / frame.len() + 1;
+        let num_frames = 4 * most_per_poll;
+        let writer = thread::spawn(move || {
+            let mut client = client;
+            for _ in 0..num_frames {
+                client.write_all(&frame).unwrap();
+            }
+            client
+        });
+
+        let mut num_received = 0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while num_received < num_frames {
+            assert!(Instant::now() < deadline, "Timed out waiting for messages");
+            let messages = server.drain_pending_messages();
+            assert!(messages.len() <= most_per_poll);
+            num_received += messages.len();
+        }
+        let _client = writer.join().unwrap();
+        assert_eq!(num_received, num_frames);
+    }
+
+    #[test]
+    fn reading_paused_at_max_pending_messages() {
+        let mut server = listening_server(ServerConfig {
+            max_pending_messages: 10,
+            ..ServerConfig::default()
+        });
+        let mut client = connect_client(&mut server);
+        client.write_all(&b"join\n".repeat(25)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.pending_messages.len() < 10 {
+            assert!(Instant::now() < deadline, "Timed out waiting for messages");
+            server.poll(Some(Duration::from_millis(10))).unwrap();
+        }
+        server.poll(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(server.pending_messages.len(), 10);
+
+        // Handling them lets the rest be read, though the client hasn't sent anything since
+        let mut num_received = 0;
+        while num_received < 25 {
+            assert!(Instant::now() < deadline, "Timed out waiting for messages");
+            let messages = server.drain_pending_messages();
+            assert!(messages.len() <= 10);
+            num_received += messages.len();
+        }
+        assert_eq!(num_received, 25);
+        assert!(server.connected_clients().contains(&ClientId(0)));
+    }
+
+    #[test]
+    fn slow_consumer_disconnected() {
+        let mut server = listening_server(ServerConfig {
+            max_outbound_buffer: 64 * 1024,
+            ..ServerConfig::default()
+        });
+        let _slow_client = connect_client(&mut server);
+        let mut fast_client = connect_client(&mut server);
+
+        // Neither client reads, so the socket buffers fill and the server starts queueing
+        let notification = OutgoingMessage {
+            client_id: ClientId(0),
+            bytes: vec![b'x'; 1023],
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.connected_clients().contains(&ClientId(0)) {
+            assert!(Instant::now() < deadline, "Slow client never disconnected");
+            server.send_notifications(&[notification.clone()]).unwrap();
+            assert!(server.outbound_len(ClientId(0)).unwrap_or(0) <= 64 * 1024);
+        }
+        assert_eq!(server.connected_clients(), vec![ClientId(1)]);
+
+        server
+            .send_notifications(&[OutgoingMessage {
+                client_id: ClientId(1),
+                bytes: b"hello".to_vec(),
+            }])
+            .unwrap();
+        let mut received = [0u8; 6];
+        fast_client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        fast_client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello\n");
+    }
+
+    #[test]
+    fn queued_notifications_flushed_when_client_reads() {
+        let mut server = listening_server(ServerConfig::default());
+        let mut client = connect_client(&mut server);
+
+        let notification = OutgoingMessage {
+            client_id: ClientId(0),
+            bytes: vec![b'x'; 1023],
+        };
+        let mut sent = 0;
+        while server.outbound_len(ClientId(0)).unwrap() == 0 {
+            server.send_notifications(&[notification.clone()]).unwrap();
+            sent += 1024;
+        }
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = vec![0u8; sent];
+        let mut total = 0;
+        while total < sent {
+            total += client.read(&mut received[total..]).unwrap();
+            server.poll(Some(Duration::from_millis(1))).unwrap();
+        }
+        assert!(received.iter().all(|&b| b == b'x' || b == b'\n'));
+        assert_eq!(server.outbound_len(ClientId(0)), Some(0));
+    }
+
+    #[test]
+    fn stop_listening_closes_connections() {
+        let mut server = listening_server(ServerConfig::default());
+        let address = server.local_addr().unwrap();
+        let mut client = connect_client(&mut server);
+        client.write_all(b"join\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        server.stop_listening().unwrap();
+        assert!(server.connected_clients().is_empty());
+        assert_eq!(
+            server.pop_all_directives(),
+            vec![(ParticipantId(0), ClientDirective::Leave {})]
+        );
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(std::net::TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn login_required_with_credentials() {
+        let credentials = Credentials {
+            participants: vec![crate::server::credentials::ParticipantCredentials {
+                name: "alice".to_owned(),
+                id: ParticipantId(7),
+                key: "secret".to_owned(),
+            }],
+        };
+        let mut server = Server::<TestProtocol>::with_credentials(
+            ServerConfig {
+                port: 0,
+                ..ServerConfig::default()
+            },
+            credentials,
+        );
+        server.start_listening().unwrap();
+        let mut client = connect_client(&mut server);
+
+        client
+            .write_all(b"join\nlogin alice secret\njoin\n")
+            .unwrap();
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(7), ClientDirective::Join {})]
+        );
+
+        let mut expected = Framing::NewlineDelimited.encode(
+            format!(
+                "{:?}",
+                ClientNotification::Rejected {
+                    reason: "not logged in".to_owned()
+                }
+            )
+            .as_bytes(),
+        );
+        expected.extend(
+            Framing::NewlineDelimited.encode(
+                format!(
+                    "{:?}",
+                    ClientNotification::LoggedIn {
+                        participant_id: ParticipantId(7),
+                        last_sequence: 0,
+                    }
+                )
+                .as_bytes(),
+            ),
+        );
+        let mut received = vec![0u8; expected.len()];
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,353 @@
+use serde::Deserialize;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// How messages are delimited on a byte stream
+/// - `LengthPrefixed`: a big-endian `u32` payload length, then the payload
+/// - `NewlineDelimited`: the payload followed by `\n`, which the payload must not contain.  Empty
+///   lines are ignored and a trailing `\r` is stripped, so the stream can be typed by hand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    LengthPrefixed,
+    NewlineDelimited,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::NewlineDelimited
+    }
+}
+
+impl Framing {
+    /// Whether a frame can hold any payload, as `Encoding::Binary` needs
+    pub fn is_binary_safe(&self) -> bool {
+        *self == Framing::LengthPrefixed
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        match self {
+            Framing::LengthPrefixed => {
+                let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+                frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                frame.extend_from_slice(payload);
+                frame
+            }
+            Framing::NewlineDelimited => {
+                let mut frame = Vec::with_capacity(payload.len() + 1);
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+                frame
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    FrameTooLarge { size: usize, max_frame_size: usize },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for Error {}
+
+/// Reassembles frames from arbitrarily split reads of one connection.  Frames are taken from the
+/// front of the buffer without moving the rest, which is only shifted down once no complete frame
+/// is left, and a partial line is only searched for its newline where it has grown.
+pub struct FrameDecoder {
+    framing: Framing,
+    max_frame_size: usize,
+    buffer: Vec<u8>,
+    /// Bytes at the front of `buffer` already taken as frames
+    consumed: usize,
+    /// Bytes after `consumed` known not to contain a newline
+    scanned: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            framing,
+            max_frame_size,
+            buffer: Vec::default(),
+            consumed: 0,
+            scanned: 0,
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Takes the payload of the next complete frame, if one has been received.  After an error
+    /// the stream can't be resynchronised, so the connection should be dropped.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let next_frame = match self.framing {
+                Framing::LengthPrefixed => self.next_length_prefixed_frame()?,
+                Framing::NewlineDelimited => self.next_newline_delimited_frame()?,
+            };
+            match next_frame {
+                Some((frame, frame_len)) => {
+                    self.consumed += frame_len;
+                    self.scanned = 0;
+                    if frame.is_some() {
+                        return Ok(frame);
+                    }
+                }
+                None => {
+                    self.buffer.drain(..self.consumed);
+                    self.consumed = 0;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Bytes received which are not yet part of a complete frame
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len() - self.consumed
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), Error> {
+        if size > self.max_frame_size {
+            Err(Error::FrameTooLarge {
+                size,
+                max_frame_size: self.max_frame_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn next_length_prefixed_frame(&self) -> Result<Option<(Option<Vec<u8>>, usize)>, Error> {
+        let bytes = &self.buffer[self.consumed..];
+        if bytes.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        prefix.copy_from_slice(&bytes[..LENGTH_PREFIX_SIZE]);
+        let payload_len = u32::from_be_bytes(prefix) as usize;
+        self.check_size(payload_len)?;
+
+        let frame_len = LENGTH_PREFIX_SIZE + payload_len;
+        if bytes.len() < frame_len {
+            return Ok(None);
+        }
+        Ok(Some((
+            Some(bytes[LENGTH_PREFIX_SIZE..frame_len].to_vec()),
+            frame_len,
+        )))
+    }
+
+    fn next_newline_delimited_frame(&mut self) -> Result<Option<(Option<Vec<u8>>, usize)>, Error> {
+        let bytes = &self.buffer[self.consumed..];
+        let newline_idx = match bytes[self.scanned..].iter().position(|&b| b == b'\n') {
+            Some(idx) => self.scanned + idx,
+            None => {
+                self.scanned = bytes.len();
+                self.check_size(bytes.len())?;
+                return Ok(None);
+            }
+        };
+        self.check_size(newline_idx)?;
+
+        let mut payload = &bytes[..newline_idx];
+        if payload.last() == Some(&b'\r') {
+            payload = &payload[..payload.len() - 1];
+        }
+        let frame = if payload.is_empty() {
+            None
+        } else {
+            Some(payload.to_vec())
+        };
+        Ok(Some((frame, newline_idx + 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_and_decode(decoder: &mut FrameDecoder, bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        decoder.push_bytes(bytes);
+        let mut frames: Vec<Vec<u8>> = Vec::default();
+        while let Some(frame) = decoder.next_frame()? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    fn payloads() -> Vec<Vec<u8>> {
+        vec![
+            b"{\"type\": \"Join\"}".to_vec(),
+            b"x".to_vec(),
+            vec![b'a'; 3000],
+            b"{\"type\": \"Leave\"}".to_vec(),
+        ]
+    }
+
+    fn encode_all(framing: Framing, payloads: &[Vec<u8>]) -> Vec<u8> {
+        payloads
+            .iter()
+            .flat_map(|payload| framing.encode(payload))
+            .collect()
+    }
+
+    fn check_fragmented(framing: Framing, chunk_size: usize) {
+        let payloads = payloads();
+        let stream = encode_all(framing, &payloads);
+        let mut decoder = FrameDecoder::new(framing, DEFAULT_MAX_FRAME_SIZE);
+        let mut decoded: Vec<Vec<u8>> = Vec::default();
+        for chunk in stream.chunks(chunk_size) {
+            decoded.extend(push_and_decode(&mut decoder, chunk).unwrap());
+        }
+        assert_eq!(decoded, payloads);
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[test]
+    fn length_prefixed_fragmented() {
+        for chunk_size in &[1, 2, 3, 5, 7, 2048] {
+            check_fragmented(Framing::LengthPrefixed, *chunk_size);
+        }
+    }
+
+    #[test]
+    fn newline_delimited_fragmented() {
+        for chunk_size in &[1, 2, 3, 5, 7, 2048] {
+            check_fragmented(Framing::NewlineDelimited, *chunk_size);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_concatenated() {
+        let payloads = payloads();
+        let mut decoder = FrameDecoder::new(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+        let decoded = push_and_decode(
+            &mut decoder,
+            &encode_all(Framing::LengthPrefixed, &payloads),
+        )
+        .unwrap();
+        assert_eq!(decoded, payloads);
+    }
+
+    #[test]
+    fn newline_delimited_concatenated() {
+        let payloads = payloads();
+        let mut decoder = FrameDecoder::new(Framing::NewlineDelimited, DEFAULT_MAX_FRAME_SIZE);
+        let decoded = push_and_decode(
+            &mut decoder,
+            &encode_all(Framing::NewlineDelimited, &payloads),
+        )
+        .unwrap();
+        assert_eq!(decoded, payloads);
+    }
+
+    #[test]
+    fn length_prefixed_empty_payload() {
+        let mut decoder = FrameDecoder::new(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(
+            push_and_decode(&mut decoder, &[0, 0, 0, 0, 0, 0, 0, 1, b'a']).unwrap(),
+            vec![vec![], vec![b'a']]
+        );
+    }
+
+    #[test]
+    fn newline_delimited_skips_blank_lines_and_carriage_returns() {
+        let mut decoder = FrameDecoder::new(Framing::NewlineDelimited, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(
+            push_and_decode(&mut decoder, b"\n\r\nabc\r\n\ndef\ngh").unwrap(),
+            vec![b"abc".to_vec(), b"def".to_vec()]
+        );
+        assert_eq!(decoder.buffered_len(), 2);
+    }
+
+    #[test]
+    fn length_prefixed_too_large() {
+        let mut decoder = FrameDecoder::new(Framing::LengthPrefixed, 4);
+        assert_eq!(
+            push_and_decode(&mut decoder, &[0, 0, 0, 4, 1, 2, 3])
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(
+            push_and_decode(&mut decoder, &[4]).unwrap(),
+            vec![vec![1, 2, 3, 4]]
+        );
+        // Rejected as soon as the length is known, before the payload arrives
+        assert_eq!(
+            push_and_decode(&mut decoder, &[0, 0, 0, 5]),
+            Err(Error::FrameTooLarge {
+                size: 5,
+                max_frame_size: 4
+            })
+        );
+    }
+
+    #[test]
+    fn newline_delimited_too_large() {
+        let mut decoder = FrameDecoder::new(Framing::NewlineDelimited, 4);
+        assert_eq!(
+            push_and_decode(&mut decoder, b"1234\n").unwrap(),
+            vec![b"1234".to_vec()]
+        );
+        assert_eq!(push_and_decode(&mut decoder, b"1234").unwrap().len(), 0);
+        // Rejected without waiting for a newline which may never come
+        assert_eq!(
+            push_and_decode(&mut decoder, b"5"),
+            Err(Error::FrameTooLarge {
+                size: 5,
+                max_frame_size: 4
+            })
+        );
+    }
+
+    #[test]
+    fn consumed_frames_compacted_once_none_are_left() {
+        for framing in &[Framing::LengthPrefixed, Framing::NewlineDelimited] {
+            let mut decoder = FrameDecoder::new(*framing, DEFAULT_MAX_FRAME_SIZE);
+            let mut stream = encode_all(*framing, &payloads());
+            let partial = framing.encode(b"partial");
+            let partial = &partial[..partial.len() - 1];
+            stream.extend_from_slice(partial);
+            decoder.push_bytes(&stream);
+
+            let first_frame_len = framing.encode(&payloads()[0]).len();
+            assert_eq!(decoder.next_frame().unwrap(), Some(payloads()[0].clone()));
+            // Taking a frame leaves the bytes after it where they are
+            assert_eq!(decoder.buffer.len(), stream.len());
+            assert_eq!(decoder.buffered_len(), stream.len() - first_frame_len);
+
+            for payload in &payloads()[1..] {
+                assert_eq!(decoder.next_frame().unwrap().as_ref(), Some(payload));
+            }
+            assert_eq!(decoder.next_frame().unwrap(), None);
+            assert_eq!(decoder.buffer, partial);
+            assert_eq!(decoder.buffered_len(), partial.len());
+
+            decoder.push_bytes(&framing.encode(b"partial")[partial.len()..]);
+            assert_eq!(decoder.next_frame().unwrap(), Some(b"partial".to_vec()));
+        }
+    }
+
+    #[test]
+    fn partial_line_only_scanned_where_it_grew() {
+        let mut decoder = FrameDecoder::new(Framing::NewlineDelimited, DEFAULT_MAX_FRAME_SIZE);
+        for _ in 0..4 {
+            decoder.push_bytes(&[b'a'; 4096]);
+            assert_eq!(decoder.next_frame().unwrap(), None);
+            assert_eq!(decoder.scanned, decoder.buffered_len());
+        }
+        decoder.push_bytes(b"\n");
+        assert_eq!(decoder.next_frame().unwrap(), Some(vec![b'a'; 4 * 4096]));
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+}
@@ -1,4 +1,21 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use self::framing::{Framing, DEFAULT_MAX_FRAME_SIZE};
+
+pub mod credentials;
+pub mod evented;
+pub mod framing;
+mod session;
 pub mod tcp;
+#[cfg(test)]
+pub(crate) mod test_support;
+#[cfg(unix)]
+pub mod unix;
+pub mod websocket;
 
 pub trait Server {
     type Error: std::error::Error;
@@ -10,9 +27,16 @@ pub trait Server {
     fn drain_pending_messages(&mut self) -> Vec<IncomingMessage>;
 
     fn send_notifications(&mut self, notifications: &[OutgoingMessage]) -> Result<(), Self::Error>;
+
+    /// Waits up to `timeout`, serving clients meanwhile if the server does that on the caller's
+    /// thread.  Servers with threads of their own just sleep.
+    fn wait(&mut self, timeout: Duration) -> Result<(), Self::Error> {
+        thread::sleep(timeout);
+        Ok(())
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct ClientId(pub u64);
 
 #[derive(Clone)]
@@ -26,3 +50,78 @@ pub struct OutgoingMessage {
     pub client_id: ClientId,
     pub bytes: Vec<u8>,
 }
+
+/// Which `Server` implementation to run
+/// - `Evented`: one thread multiplexing every connection, see `evented::Server`
+/// - `Threaded`: a thread per connection, see `tcp::Server`
+/// - `WebSocket`: WebSocket connections for browser clients, see `websocket::Server`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    Evented,
+    Threaded,
+    #[serde(rename = "websocket")]
+    WebSocket,
+}
+
+impl Default for ServerMode {
+    fn default() -> Self {
+        ServerMode::Evented
+    }
+}
+
+pub const DEFAULT_MAX_OUTBOUND_BUFFER: usize = 4 << 20;
+pub const DEFAULT_MAX_PENDING_MESSAGES: usize = 1024;
+pub const DEFAULT_SESSION_OUTBOX_SIZE: usize = 1024;
+/// Owner only
+pub const DEFAULT_UNIX_SOCKET_PERMISSIONS: u32 = 0o600;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub ip: String,
+    pub port: u16,
+    pub mode: ServerMode,
+    /// How messages are delimited on the stream.  Not used by `ServerMode::WebSocket`, where each
+    /// WebSocket message is one message.
+    pub framing: Framing,
+    /// Largest message payload accepted, in bytes.  Clients exceeding it are disconnected.
+    pub max_frame_size: usize,
+    /// Most bytes queued for a client which isn't reading its notifications, in bytes.  Clients
+    /// exceeding it are disconnected.  Only used by `ServerMode::Evented`; the threaded server
+    /// blocks on slow clients instead.
+    pub max_outbound_buffer: usize,
+    /// Most messages read from one client and not yet handled.  Reading from a client stops at
+    /// this many until they're handled, leaving the rest on its socket.  Only used by
+    /// `ServerMode::Evented`.
+    pub max_pending_messages: usize,
+    /// Participants allowed to log in.  Without one, any connection may join as a participant
+    /// identified by its connection.
+    pub credentials_file: Option<PathBuf>,
+    /// Most recent notifications kept per participant, for replaying to a resumed session
+    pub session_outbox_size: usize,
+    /// Listen on this Unix domain socket with the threaded server, instead of `ip` and `port`
+    /// with the server chosen by `mode`
+    pub unix_socket: Option<PathBuf>,
+    /// File mode of `unix_socket`, which limits who may connect to it, e.g. `0o660` to allow
+    /// the socket's group as well as its owner
+    pub unix_socket_permissions: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            ip: "127.0.0.1".to_owned(),
+            port: 8080,
+            mode: ServerMode::default(),
+            framing: Framing::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_outbound_buffer: DEFAULT_MAX_OUTBOUND_BUFFER,
+            max_pending_messages: DEFAULT_MAX_PENDING_MESSAGES,
+            credentials_file: None,
+            session_outbox_size: DEFAULT_SESSION_OUTBOX_SIZE,
+            unix_socket: None,
+            unix_socket_permissions: DEFAULT_UNIX_SOCKET_PERMISSIONS,
+        }
+    }
+}
@@ -0,0 +1,821 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::credentials::Credentials;
+use super::{ClientId, IncomingMessage, OutgoingMessage};
+use crate::participant::ParticipantId;
+use crate::protocol::{
+    Capability, ClientDirective, ClientNotification, Encoding, WireProtocol, PROTOCOL_VERSION,
+};
+
+/// The `Hello` capabilities this exchange provides
+const SUPPORTED_CAPABILITIES: [Capability; 1] = [Capability::Replay];
+
+/// Connection-to-participant bookkeeping shared by the `Server` implementations, which only move
+/// bytes.
+///
+/// With `Credentials`, a connection acts for nobody until it sends a `Login`, after which it is
+/// bound to that participant's stable id.  Logging in again from another connection takes the
+/// session over, and a participant stays joined while disconnected so it can carry on after
+/// reconnecting.
+///
+/// Without `Credentials` each connection is its own participant, identified by its `ClientId`,
+/// and leaves the auction when it disconnects since nothing could resume it.
+///
+/// Each connection speaks the server's `WireProtocol` until it sends a `Hello`, which may arrive in
+/// any `Encoding` and chooses the one for everything after it.  `Encoding::Binary` is refused
+/// unless the transport's frames may hold any byte, which newline framing's can't.
+///
+/// Notifications from the exchange are numbered per participant and the latest `outbox_size`
+/// kept, so that a client which lost its connection can `Resume` from the last one it saw.
+pub(crate) struct Sessions {
+    credentials: Option<Credentials>,
+    outbox_size: usize,
+    client_participants: HashMap<ClientId, ParticipantId>,
+    participant_clients: HashMap<ParticipantId, ClientId>,
+    joined_participants: HashSet<ParticipantId>,
+    outboxes: HashMap<ParticipantId, Outbox>,
+    client_encodings: HashMap<ClientId, Encoding>,
+    binary_safe: bool,
+}
+
+#[derive(Default)]
+struct Outbox {
+    last_sequence: u64,
+    notifications: VecDeque<(u64, ClientNotification)>,
+}
+
+impl Outbox {
+    fn push(&mut self, notification: ClientNotification, outbox_size: usize) -> ClientNotification {
+        self.last_sequence += 1;
+        let sequenced = ClientNotification::Sequenced {
+            sequence: self.last_sequence,
+            notification: Box::new(notification),
+        };
+        self.notifications
+            .push_back((self.last_sequence, sequenced.clone()));
+        while self.notifications.len() > outbox_size {
+            self.notifications.pop_front();
+        }
+        sequenced
+    }
+
+    /// Notifications numbered after `last_sequence`, after a rejection if some of them have
+    /// already been dropped
+    fn replay_after(&self, last_sequence: u64) -> Vec<ClientNotification> {
+        if last_sequence > self.last_sequence {
+            return vec![rejected(&format!(
+                "nothing after {} has been sent yet",
+                self.last_sequence
+            ))];
+        }
+        let mut replay: Vec<ClientNotification> = Vec::default();
+        let first_retained = self
+            .notifications
+            .front()
+            .map(|(sequence, _notification)| *sequence)
+            .unwrap_or(self.last_sequence + 1);
+        if first_retained > last_sequence + 1 {
+            replay.push(rejected(&format!(
+                "notifications {} to {} are no longer available",
+                last_sequence + 1,
+                first_retained - 1
+            )));
+        }
+        replay.extend(
+            self.notifications
+                .iter()
+                .filter(|(sequence, _notification)| *sequence > last_sequence)
+                .map(|(_sequence, notification)| notification.clone()),
+        );
+        replay
+    }
+}
+
+impl Sessions {
+    pub fn new(credentials: Option<Credentials>, outbox_size: usize, binary_safe: bool) -> Self {
+        Self {
+            credentials,
+            outbox_size,
+            client_participants: HashMap::default(),
+            participant_clients: HashMap::default(),
+            joined_participants: HashSet::default(),
+            outboxes: HashMap::default(),
+            client_encodings: HashMap::default(),
+            binary_safe,
+        }
+    }
+
+    pub fn participant_for_client(&self, client_id: ClientId) -> Option<ParticipantId> {
+        match &self.credentials {
+            Some(_) => self.client_participants.get(&client_id).copied(),
+            None => Some(ParticipantId(client_id.0)),
+        }
+    }
+
+    pub fn client_for_participant(&self, participant_id: ParticipantId) -> Option<ClientId> {
+        match &self.credentials {
+            Some(_) => self.participant_clients.get(&participant_id).copied(),
+            None => Some(ClientId(participant_id.0)),
+        }
+    }
+
+    /// Directives decoded from `messages` which the engine should apply, including a `Leave` for
+    /// anonymous participants whose connection has closed, and replies to send for any which
+    /// were handled or refused here
+    pub fn decode_directives<W: WireProtocol>(
+        &mut self,
+        messages: Vec<IncomingMessage>,
+        disconnected_clients: Vec<ClientId>,
+    ) -> (Vec<(ParticipantId, ClientDirective)>, Vec<OutgoingMessage>) {
+        let mut directives: Vec<(ParticipantId, ClientDirective)> = Vec::default();
+        let mut outgoing_replies: Vec<OutgoingMessage> = Vec::default();
+
+        for message in messages {
+            let client_id = message.client_id;
+            let mut replies: Vec<(ClientId, ClientNotification)> = Vec::default();
+            match self.decode_directive::<W>(client_id, &message.bytes[..]) {
+                Ok(directive) => {
+                    self.apply_directive(client_id, directive, &mut directives, &mut replies)
+                }
+                Err(reason) => replies.push((client_id, rejected(&reason))),
+            }
+            // Encoded straight away, since a `Hello` changes the encoding for what follows
+            outgoing_replies.extend(self.encode_replies::<W>(replies));
+        }
+
+        for client_id in disconnected_clients {
+            self.client_encodings.remove(&client_id);
+            match &self.credentials {
+                Some(_) => {
+                    if let Some(participant_id) = self.client_participants.remove(&client_id) {
+                        self.participant_clients.remove(&participant_id);
+                    }
+                }
+                None => {
+                    let participant_id = ParticipantId(client_id.0);
+                    self.outboxes.remove(&participant_id);
+                    if self.joined_participants.remove(&participant_id) {
+                        directives.push((participant_id, ClientDirective::Leave {}));
+                    }
+                }
+            }
+        }
+
+        (directives, outgoing_replies)
+    }
+
+    /// Decodes in the connection's negotiated encoding, or before a `Hello` in `W`, or failing
+    /// that as a `Hello` in any encoding.  Fails with why the bytes aren't a directive in the
+    /// connection's encoding, or in `W` before a `Hello`.
+    fn decode_directive<W: WireProtocol>(
+        &self,
+        client_id: ClientId,
+        bytes: &[u8],
+    ) -> Result<ClientDirective, String> {
+        if let Some(encoding) = self.client_encodings.get(&client_id) {
+            return encoding
+                .try_client_directive_from_bytes(bytes)
+                .map_err(|e| e.to_string());
+        }
+        let error = match W::try_client_directive_from_bytes(bytes) {
+            Ok(directive) => return Ok(directive),
+            Err(e) => e.to_string(),
+        };
+        Encoding::ALL
+            .iter()
+            .find_map(
+                |encoding| match encoding.try_client_directive_from_bytes(bytes) {
+                    Ok(directive @ ClientDirective::Hello { .. }) => Some(directive),
+                    _ => None,
+                },
+            )
+            .ok_or(error)
+    }
+
+    fn apply_directive(
+        &mut self,
+        client_id: ClientId,
+        directive: ClientDirective,
+        directives: &mut Vec<(ParticipantId, ClientDirective)>,
+        replies: &mut Vec<(ClientId, ClientNotification)>,
+    ) {
+        match &directive {
+            ClientDirective::Hello {
+                version,
+                encoding,
+                capabilities,
+            } => {
+                replies.push((
+                    client_id,
+                    self.hello(client_id, *version, *encoding, capabilities),
+                ));
+                return;
+            }
+            ClientDirective::Login { name, key } => {
+                self.login(client_id, name, key, replies);
+                return;
+            }
+            _ => {}
+        }
+        let participant_id = match self.participant_for_client(client_id) {
+            Some(participant_id) => participant_id,
+            None => {
+                replies.push((client_id, rejected("not logged in")));
+                return;
+            }
+        };
+        if let ClientDirective::Resume { last_sequence } = directive {
+            let replay = match self.outboxes.get(&participant_id) {
+                Some(outbox) => outbox.replay_after(last_sequence),
+                None => Outbox::default().replay_after(last_sequence),
+            };
+            replies.extend(
+                replay
+                    .into_iter()
+                    .map(|notification| (client_id, notification)),
+            );
+            return;
+        }
+        match self.check_membership(participant_id, &directive) {
+            Ok(()) => directives.push((participant_id, directive)),
+            Err(reason) => replies.push((client_id, rejected(reason))),
+        }
+    }
+
+    /// Settles the connection's version and encoding, answering with the newest version both
+    /// sides speak and the requested capabilities this exchange provides
+    fn hello(
+        &mut self,
+        client_id: ClientId,
+        version: u32,
+        encoding: Encoding,
+        capabilities: &[Capability],
+    ) -> ClientNotification {
+        if self.client_encodings.contains_key(&client_id) {
+            return rejected("hello already received");
+        }
+        if version == 0 {
+            return rejected(&format!(
+                "unsupported protocol version {}, expected 1 to {}",
+                version, PROTOCOL_VERSION
+            ));
+        }
+        if encoding == Encoding::Binary && !self.binary_safe {
+            return rejected("binary encoding needs length-prefixed framing");
+        }
+        self.client_encodings.insert(client_id, encoding);
+        ClientNotification::Hello {
+            version: version.min(PROTOCOL_VERSION),
+            encoding,
+            capabilities: capabilities
+                .iter()
+                .filter(|capability| SUPPORTED_CAPABILITIES.contains(capability))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Numbers and keeps each notification, encoding it for the participant's connection if it
+    /// has one
+    pub fn encode_notifications<W: WireProtocol>(
+        &mut self,
+        notifications: &[(ParticipantId, ClientNotification)],
+    ) -> Vec<OutgoingMessage> {
+        let mut outgoing_messages: Vec<OutgoingMessage> = Vec::default();
+        for (participant_id, notification) in notifications {
+            let sequenced = self
+                .outboxes
+                .entry(*participant_id)
+                .or_default()
+                .push(notification.clone(), self.outbox_size);
+            let client_id = match self.client_for_participant(*participant_id) {
+                Some(client_id) => client_id,
+                None => continue,
+            };
+            if let Some(bytes) = self.encode_notification::<W>(client_id, &sequenced) {
+                outgoing_messages.push(OutgoingMessage { client_id, bytes });
+            }
+        }
+        outgoing_messages
+    }
+
+    fn encode_replies<W: WireProtocol>(
+        &self,
+        replies: Vec<(ClientId, ClientNotification)>,
+    ) -> Vec<OutgoingMessage> {
+        replies
+            .into_iter()
+            .filter_map(|(client_id, notification)| {
+                let bytes = self.encode_notification::<W>(client_id, &notification)?;
+                Some(OutgoingMessage { client_id, bytes })
+            })
+            .collect()
+    }
+
+    fn encode_notification<W: WireProtocol>(
+        &self,
+        client_id: ClientId,
+        notification: &ClientNotification,
+    ) -> Option<Vec<u8>> {
+        match self.client_encodings.get(&client_id) {
+            Some(encoding) => encoding.try_client_notification_to_bytes(notification).ok(),
+            None => W::try_client_notification_to_bytes(notification).ok(),
+        }
+    }
+
+    fn login(
+        &mut self,
+        client_id: ClientId,
+        name: &str,
+        key: &str,
+        replies: &mut Vec<(ClientId, ClientNotification)>,
+    ) {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials,
+            None => {
+                replies.push((client_id, rejected("authentication is not enabled")));
+                return;
+            }
+        };
+        let participant_id = match credentials.authenticate(name, key) {
+            Some(participant_id) => participant_id,
+            None => {
+                replies.push((client_id, rejected("unknown participant or wrong key")));
+                return;
+            }
+        };
+
+        if let Some(previous_participant_id) = self.client_participants.remove(&client_id) {
+            self.participant_clients.remove(&previous_participant_id);
+        }
+        if let Some(previous_client_id) = self.participant_clients.insert(participant_id, client_id)
+        {
+            self.client_participants.remove(&previous_client_id);
+            replies.push((
+                previous_client_id,
+                rejected("logged in from another connection"),
+            ));
+        }
+        self.client_participants.insert(client_id, participant_id);
+        let last_sequence = self
+            .outboxes
+            .get(&participant_id)
+            .map(|outbox| outbox.last_sequence)
+            .unwrap_or(0);
+        replies.push((
+            client_id,
+            ClientNotification::LoggedIn {
+                participant_id,
+                last_sequence,
+            },
+        ));
+    }
+
+    /// Keeps the engine from seeing a participant join twice, or act without having joined
+    fn check_membership(
+        &mut self,
+        participant_id: ParticipantId,
+        directive: &ClientDirective,
+    ) -> Result<(), &'static str> {
+        let joined = self.joined_participants.contains(&participant_id);
+        match directive {
+            ClientDirective::Join {} if joined => Err("already joined"),
+            ClientDirective::Join {} => {
+                self.joined_participants.insert(participant_id);
+                Ok(())
+            }
+            _ if !joined => Err("not joined"),
+            ClientDirective::Leave {} => {
+                self.joined_participants.remove(&participant_id);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn rejected(reason: &str) -> ClientNotification {
+    ClientNotification::Rejected {
+        reason: reason.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::json::JsonProtocol;
+    use crate::server::credentials::ParticipantCredentials;
+    use crate::server::test_support::TestProtocol;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            participants: vec![ParticipantCredentials {
+                name: "alice".to_owned(),
+                id: ParticipantId(7),
+                key: "secret".to_owned(),
+            }],
+        }
+    }
+
+    fn message(client_id: u64, text: &str) -> IncomingMessage {
+        IncomingMessage {
+            client_id: ClientId(client_id),
+            bytes: text.as_bytes().to_vec(),
+        }
+    }
+
+    fn replies_text(replies: &[OutgoingMessage]) -> Vec<(ClientId, String)> {
+        replies
+            .iter()
+            .map(|reply| {
+                (
+                    reply.client_id,
+                    String::from_utf8(reply.bytes.clone()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn rejected_text(reason: &str) -> String {
+        format!("{:?}", rejected(reason))
+    }
+
+    #[test]
+    fn anonymous_participants_leave_on_disconnect() {
+        let mut sessions = Sessions::new(None, 4, true);
+        let (directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![message(3, "join"), message(4, "join")],
+            vec![],
+        );
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(3), ClientDirective::Join {}),
+                (ParticipantId(4), ClientDirective::Join {}),
+            ]
+        );
+        assert!(replies.is_empty());
+
+        let (directives, _replies) =
+            sessions.decode_directives::<TestProtocol>(vec![], vec![ClientId(3)]);
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(3), ClientDirective::Leave {})]
+        );
+    }
+
+    #[test]
+    fn directives_require_login() {
+        let mut sessions = Sessions::new(Some(credentials()), 4, true);
+        let (directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![message(0, "join"), message(0, "login alice wrong")],
+            vec![],
+        );
+        assert!(directives.is_empty());
+        assert_eq!(
+            replies_text(&replies),
+            vec![
+                (ClientId(0), rejected_text("not logged in")),
+                (
+                    ClientId(0),
+                    rejected_text("unknown participant or wrong key")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn session_survives_reconnect() {
+        let mut sessions = Sessions::new(Some(credentials()), 4, true);
+        let (directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![message(0, "login alice secret"), message(0, "join")],
+            vec![],
+        );
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(7), ClientDirective::Join {})]
+        );
+        assert_eq!(
+            replies_text(&replies),
+            vec![(
+                ClientId(0),
+                format!(
+                    "{:?}",
+                    ClientNotification::LoggedIn {
+                        participant_id: ParticipantId(7),
+                        last_sequence: 0,
+                    }
+                )
+            )]
+        );
+
+        // Still joined while disconnected, under the same id after reconnecting
+        let (directives, _replies) =
+            sessions.decode_directives::<TestProtocol>(vec![], vec![ClientId(0)]);
+        assert!(directives.is_empty());
+        assert_eq!(sessions.client_for_participant(ParticipantId(7)), None);
+
+        let (directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![
+                message(5, "login alice secret"),
+                message(5, "join"),
+                message(5, "leave"),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(7), ClientDirective::Leave {})]
+        );
+        assert_eq!(
+            replies_text(&replies)[1],
+            (ClientId(5), rejected_text("already joined"))
+        );
+        assert_eq!(
+            sessions.client_for_participant(ParticipantId(7)),
+            Some(ClientId(5))
+        );
+    }
+
+    #[test]
+    fn second_login_takes_over_session() {
+        let mut sessions = Sessions::new(Some(credentials()), 4, true);
+        sessions.decode_directives::<TestProtocol>(
+            vec![message(0, "login alice secret"), message(0, "join")],
+            vec![],
+        );
+        let (directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![message(1, "login alice secret"), message(0, "leave")],
+            vec![],
+        );
+        assert!(directives.is_empty());
+        let replies = replies_text(&replies);
+        assert_eq!(
+            replies[0],
+            (
+                ClientId(0),
+                rejected_text("logged in from another connection")
+            )
+        );
+        assert_eq!(replies[2], (ClientId(0), rejected_text("not logged in")));
+
+        let notifications =
+            sessions.encode_notifications::<TestProtocol>(&[(ParticipantId(7), rejected("test"))]);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].client_id, ClientId(1));
+
+        // The old connection closing doesn't end the new session
+        sessions.decode_directives::<TestProtocol>(vec![], vec![ClientId(0)]);
+        assert_eq!(
+            sessions.participant_for_client(ClientId(1)),
+            Some(ParticipantId(7))
+        );
+    }
+
+    fn trade(quantity: u64) -> ClientNotification {
+        ClientNotification::Trade {
+            product_id: crate::ProductId(1),
+            side: crate::auction::Side::Bid,
+            price: crate::Price(100),
+            quantity,
+            timestamp: crate::clock::Timestamp(0),
+        }
+    }
+
+    fn sequenced_text(sequence: u64, notification: ClientNotification) -> String {
+        format!(
+            "{:?}",
+            ClientNotification::Sequenced {
+                sequence,
+                notification: Box::new(notification),
+            }
+        )
+    }
+
+    #[test]
+    fn notifications_numbered_per_participant() {
+        let mut sessions = Sessions::new(None, 4, true);
+        let outgoing = sessions.encode_notifications::<TestProtocol>(&[
+            (ParticipantId(0), trade(1)),
+            (ParticipantId(1), trade(2)),
+            (ParticipantId(0), trade(3)),
+        ]);
+        assert_eq!(
+            replies_text(&outgoing),
+            vec![
+                (ClientId(0), sequenced_text(1, trade(1))),
+                (ClientId(1), sequenced_text(1, trade(2))),
+                (ClientId(0), sequenced_text(2, trade(3))),
+            ]
+        );
+    }
+
+    #[test]
+    fn resume_replays_missed_notifications() {
+        let mut sessions = Sessions::new(Some(credentials()), 4, true);
+        sessions.decode_directives::<TestProtocol>(vec![message(0, "login alice secret")], vec![]);
+        sessions.encode_notifications::<TestProtocol>(&[(ParticipantId(7), trade(1))]);
+        sessions.decode_directives::<TestProtocol>(vec![], vec![ClientId(0)]);
+
+        // Sent while disconnected
+        let outgoing = sessions.encode_notifications::<TestProtocol>(&[
+            (ParticipantId(7), trade(2)),
+            (ParticipantId(7), trade(3)),
+        ]);
+        assert!(outgoing.is_empty());
+
+        let (_directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![message(1, "login alice secret"), message(1, "resume 1")],
+            vec![],
+        );
+        assert_eq!(
+            replies_text(&replies),
+            vec![
+                (
+                    ClientId(1),
+                    format!(
+                        "{:?}",
+                        ClientNotification::LoggedIn {
+                            participant_id: ParticipantId(7),
+                            last_sequence: 3,
+                        }
+                    )
+                ),
+                (ClientId(1), sequenced_text(2, trade(2))),
+                (ClientId(1), sequenced_text(3, trade(3))),
+            ]
+        );
+    }
+
+    #[test]
+    fn resume_reports_dropped_notifications() {
+        let mut sessions = Sessions::new(Some(credentials()), 2, true);
+        sessions.decode_directives::<TestProtocol>(vec![message(0, "login alice secret")], vec![]);
+        let trades: Vec<(ParticipantId, ClientNotification)> =
+            (1..=5).map(|i| (ParticipantId(7), trade(i))).collect();
+        sessions.encode_notifications::<TestProtocol>(&trades);
+
+        let (_directives, replies) = sessions.decode_directives::<TestProtocol>(
+            vec![
+                message(0, "resume 1"),
+                message(0, "resume 5"),
+                message(0, "resume 6"),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            replies_text(&replies),
+            vec![
+                (
+                    ClientId(0),
+                    rejected_text("notifications 2 to 3 are no longer available")
+                ),
+                (ClientId(0), sequenced_text(4, trade(4))),
+                (ClientId(0), sequenced_text(5, trade(5))),
+                (
+                    ClientId(0),
+                    rejected_text("nothing after 5 has been sent yet")
+                ),
+            ]
+        );
+    }
+
+    fn hello(version: u32, encoding: Encoding) -> ClientDirective {
+        ClientDirective::Hello {
+            version,
+            encoding,
+            capabilities: vec![Capability::BookSnapshots, Capability::Replay],
+        }
+    }
+
+    fn encoded(encoding: Encoding, client_id: u64, directive: &ClientDirective) -> IncomingMessage {
+        IncomingMessage {
+            client_id: ClientId(client_id),
+            bytes: encoding.try_client_directive_to_bytes(directive).unwrap(),
+        }
+    }
+
+    #[test]
+    fn hello_chooses_connection_encoding() {
+        let mut sessions = Sessions::new(None, 4, true);
+        let (directives, replies) = sessions.decode_directives::<JsonProtocol>(
+            vec![
+                encoded(
+                    Encoding::Json,
+                    0,
+                    &hello(PROTOCOL_VERSION + 1, Encoding::Binary),
+                ),
+                encoded(Encoding::Binary, 0, &ClientDirective::Join {}),
+                // Now undecodable on this connection
+                encoded(Encoding::Json, 0, &ClientDirective::Leave {}),
+                encoded(Encoding::Json, 1, &ClientDirective::Join {}),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(0), ClientDirective::Join {}),
+                (ParticipantId(1), ClientDirective::Join {}),
+            ]
+        );
+        assert_eq!(replies.len(), 2);
+        assert_eq!(
+            Encoding::Binary
+                .try_client_notification_from_bytes(&replies[0].bytes)
+                .unwrap(),
+            ClientNotification::Hello {
+                version: PROTOCOL_VERSION,
+                encoding: Encoding::Binary,
+                capabilities: vec![Capability::Replay],
+            }
+        );
+        assert!(matches!(
+            Encoding::Binary.try_client_notification_from_bytes(&replies[1].bytes),
+            Ok(ClientNotification::Rejected { .. })
+        ));
+
+        let outgoing = sessions.encode_notifications::<JsonProtocol>(&[
+            (ParticipantId(0), trade(1)),
+            (ParticipantId(1), trade(1)),
+        ]);
+        let sequenced = ClientNotification::Sequenced {
+            sequence: 1,
+            notification: Box::new(trade(1)),
+        };
+        assert_eq!(
+            Encoding::Binary
+                .try_client_notification_from_bytes(&outgoing[0].bytes)
+                .unwrap(),
+            sequenced
+        );
+        assert_eq!(
+            Encoding::Json
+                .try_client_notification_from_bytes(&outgoing[1].bytes)
+                .unwrap(),
+            sequenced
+        );
+    }
+
+    #[test]
+    fn hello_accepted_in_any_encoding() {
+        let mut sessions = Sessions::new(None, 4, true);
+        let (_directives, replies) = sessions.decode_directives::<JsonProtocol>(
+            vec![encoded(Encoding::Binary, 0, &hello(1, Encoding::Binary))],
+            vec![],
+        );
+        assert!(matches!(
+            Encoding::Binary.try_client_notification_from_bytes(&replies[0].bytes),
+            Ok(ClientNotification::Hello { .. })
+        ));
+    }
+
+    #[test]
+    fn bad_or_repeated_hello_rejected() {
+        let mut sessions = Sessions::new(None, 4, true);
+        let (_directives, replies) = sessions.decode_directives::<JsonProtocol>(
+            vec![
+                encoded(Encoding::Json, 0, &hello(0, Encoding::Binary)),
+                encoded(Encoding::Json, 0, &hello(1, Encoding::Json)),
+                encoded(Encoding::Json, 0, &hello(1, Encoding::Binary)),
+            ],
+            vec![],
+        );
+        let replies: Vec<ClientNotification> = replies
+            .iter()
+            .map(|reply| {
+                Encoding::Json
+                    .try_client_notification_from_bytes(&reply.bytes)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            replies[0],
+            rejected("unsupported protocol version 0, expected 1 to 1")
+        );
+        assert!(matches!(replies[1], ClientNotification::Hello { .. }));
+        assert_eq!(replies[2], rejected("hello already received"));
+    }
+
+    #[test]
+    fn binary_hello_rejected_without_length_prefixes() {
+        let mut sessions = Sessions::new(None, 4, false);
+        let (directives, replies) = sessions.decode_directives::<JsonProtocol>(
+            vec![
+                encoded(Encoding::Json, 0, &hello(1, Encoding::Binary)),
+                encoded(Encoding::Json, 0, &ClientDirective::Join {}),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Join {})]
+        );
+        assert_eq!(
+            Encoding::Json
+                .try_client_notification_from_bytes(&replies[0].bytes)
+                .unwrap(),
+            rejected("binary encoding needs length-prefixed framing")
+        );
+    }
+}
@@ -1,10 +1,23 @@
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use super::{ClientId, IncomingMessage, OutgoingMessage, Server as ServerTrait};
-use crate::participant::ParticipantPool;
+use super::credentials::Credentials;
+use super::framing::FrameDecoder;
+use super::session::Sessions;
+use super::{ClientId, IncomingMessage, OutgoingMessage, Server as ServerTrait, ServerConfig};
+use crate::participant::{ParticipantId, ParticipantPool};
+use crate::protocol::json::JsonProtocol;
+use crate::protocol::{ClientDirective, ClientNotification, WireProtocol};
+
+/// How often the listening thread checks whether it has been asked to stop
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 pub enum Error {
@@ -18,144 +31,740 @@ impl std::fmt::Display for Error {
 }
 impl std::error::Error for Error {}
 
-pub struct Server {
+/// Where the threaded server accepts connections from, a TCP port unless `L` is another
+/// `Listener` such as `unix::UnixSocketListener`
+pub trait Listener: Send + Sized + 'static {
+    type Stream: Stream;
+    type Address: Clone + Debug;
+
+    fn bind(config: &ServerConfig) -> io::Result<Self>;
+    fn local_addr(&self) -> io::Result<Self::Address>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn accept(&self) -> io::Result<Self::Stream>;
+}
+
+/// A connection accepted by a `Listener`
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Address = SocketAddr;
+
+    fn bind(config: &ServerConfig) -> io::Result<Self> {
+        TcpListener::bind(format!("{}:{}", &config.ip, config.port))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _peer_addr)| stream)
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Thread-per-connection TCP server.  As a `ParticipantPool`, each connection acts as one
+/// participant, with messages encoded by the `WireProtocol` `W` unless the connection's `Hello`
+/// chooses another.
+pub struct Server<W = JsonProtocol, L = TcpListener>
+where
+    W: WireProtocol,
+    L: Listener,
+{
     config: ServerConfig,
+    local_addr: Option<L::Address>,
     listening_thread: Option<JoinHandle<()>>,
-    client_records: Vec<(ClientRecord, JoinHandle<()>)>,
-    task_channels: (Sender<ServerTask>, Receiver<ServerTask>),
+    stop_requested: Arc<AtomicBool>,
+    next_client_id: u64,
+    client_records: Vec<(ClientRecord<L::Stream>, JoinHandle<()>)>,
+    task_channels: (
+        Sender<ServerTask<L::Stream>>,
+        Receiver<ServerTask<L::Stream>>,
+    ),
+    pending_messages: Vec<IncomingMessage>,
+    disconnected_clients: Vec<ClientId>,
+    sessions: Sessions,
+    protocol: PhantomData<W>,
 }
 
-impl Server {
+impl<W, L> Server<W, L>
+where
+    W: WireProtocol,
+    L: Listener,
+{
     pub fn new(config: ServerConfig) -> Self {
+        let sessions = Sessions::new(
+            None,
+            config.session_outbox_size,
+            config.framing.is_binary_safe(),
+        );
+        Self::with_sessions(config, sessions)
+    }
+
+    /// Only participants listed in `credentials` may log in and act
+    pub fn with_credentials(config: ServerConfig, credentials: Credentials) -> Self {
+        let sessions = Sessions::new(
+            Some(credentials),
+            config.session_outbox_size,
+            config.framing.is_binary_safe(),
+        );
+        Self::with_sessions(config, sessions)
+    }
+
+    fn with_sessions(config: ServerConfig, sessions: Sessions) -> Self {
         Self {
             config,
+            local_addr: None,
             listening_thread: None,
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            next_client_id: 0,
             client_records: vec![],
             task_channels: mpsc::channel(),
+            pending_messages: Vec::default(),
+            disconnected_clients: Vec::default(),
+            sessions,
+            protocol: PhantomData,
+        }
+    }
+
+    /// Address actually bound, which differs from the configured one when listening on port 0
+    pub fn local_addr(&self) -> Option<L::Address> {
+        self.local_addr.clone()
+    }
+
+    pub fn connected_clients(&self) -> Vec<ClientId> {
+        self.client_records
+            .iter()
+            .map(|(client_record, _join_handle)| client_record.client_id)
+            .collect()
+    }
+
+    fn process_tasks(&mut self) {
+        while let Ok(task) = self.task_channels.1.try_recv() {
+            self.handle_task(task);
         }
     }
 
-    fn handle_task(&mut self, task: ServerTask) -> Option<IncomingMessage> {
+    fn handle_task(&mut self, task: ServerTask<L::Stream>) {
         match task {
-            ServerTask::NewClient(mut stream) => {
-                let client_id = ClientId(0); // TODO
-                let record = ClientRecord {
-                    client_id: client_id,
-                    stream: stream.try_clone().expect("TODO"),
+            ServerTask::NewClient(stream) => {
+                if self.listening_thread.is_none() {
+                    // Accepted just before stop_listening
+                    stream.shutdown(Shutdown::Both).unwrap_or(());
+                    return;
+                }
+                if let Err(e) = self.add_client(stream) {
+                    eprintln!("Connecting a client failed: {}", e);
+                }
+            }
+            ServerTask::IncomingMessage(message) => self.pending_messages.push(message),
+            ServerTask::Disconnected(client_id) => {
+                if let Some(idx) = self
+                    .client_records
+                    .iter()
+                    .position(|(client_record, _join_handle)| client_record.client_id == client_id)
+                {
+                    let (_client_record, join_handle) = self.client_records.remove(idx);
+                    join_handle.join().unwrap_or(());
+                    self.disconnected_clients.push(client_id);
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, mut stream: L::Stream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        let client_id = ClientId(self.next_client_id);
+        self.next_client_id += 1;
+        let record = ClientRecord {
+            client_id,
+            stream: stream.try_clone()?,
+        };
+
+        let send_channel = self.task_channels.0.clone();
+        let mut frame_decoder = FrameDecoder::new(self.config.framing, self.config.max_frame_size);
+        let join_handle = thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            'reading: loop {
+                let len = match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break 'reading,
+                    Ok(len) => len,
                 };
-                let send_channel = self.task_channels.0.clone();
-                let join_handle = thread::spawn(move || {
-                    let mut buf = [0u8; 2048];
-                    while let Ok(len) = stream.read(&mut buf) {
-                        send_channel
-                            .send(ServerTask::IncomingMessage(IncomingMessage {
-                                client_id,
-                                bytes: Vec::from(&buf[0..len]),
-                            }))
-                            .expect("TODO");
+                frame_decoder.push_bytes(&buf[0..len]);
+                loop {
+                    match frame_decoder.next_frame() {
+                        Ok(Some(bytes)) => {
+                            let message = IncomingMessage { client_id, bytes };
+                            if send_channel
+                                .send(ServerTask::IncomingMessage(message))
+                                .is_err()
+                            {
+                                // The server has been dropped
+                                break 'reading;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(_) => {
+                            // The stream can't be resynchronised after a bad frame
+                            stream.shutdown(Shutdown::Both).unwrap_or(());
+                            break 'reading;
+                        }
                     }
-                });
-                self.client_records.push((record, join_handle));
-                None
+                }
             }
-            ServerTask::IncomingMessage(message) => Some(message),
-        }
+            send_channel
+                .send(ServerTask::Disconnected(client_id))
+                .unwrap_or(());
+        });
+        self.client_records.push((record, join_handle));
+        Ok(())
     }
 }
 
-impl ServerTrait for Server {
+impl<W, L> ServerTrait for Server<W, L>
+where
+    W: WireProtocol,
+    L: Listener,
+{
     type Error = self::Error;
 
     fn start_listening(&mut self) -> Result<(), Self::Error> {
         assert!(self.listening_thread.is_none());
-        let listener =
-            TcpListener::bind(format!("{}:{}", &self.config.ip, self.config.port)).expect("TODO");
+        let listener = L::bind(&self.config).map_err(|_| Error::Net)?;
+        listener.set_nonblocking(true).map_err(|_| Error::Net)?;
+        self.local_addr = Some(listener.local_addr().map_err(|_| Error::Net)?);
+        self.stop_requested.store(false, Ordering::SeqCst);
+
         let listener_sending_channel = self.task_channels.0.clone();
+        let stop_requested = self.stop_requested.clone();
         self.listening_thread = thread::spawn(move || {
-            while let Ok((stream, _todo)) = listener.accept() {
-                listener_sending_channel
-                    .send(ServerTask::NewClient(stream))
-                    .expect("TODO");
+            while !stop_requested.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok(stream) => {
+                        if listener_sending_channel
+                            .send(ServerTask::NewClient(stream))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_POLL_INTERVAL)
+                    }
+                    Err(e) => {
+                        eprintln!("Accepting clients failed, no longer listening: {}", e);
+                        break;
+                    }
+                }
             }
         })
         .into();
         Ok(())
     }
 
+    /// Closes the listening socket and every client connection, waiting for their threads to
+    /// finish.  Clients are reported as disconnected by the next `pop_all_directives`.
     fn stop_listening(&mut self) -> Result<(), Self::Error> {
-        todo!();
-    }
+        let listening_thread = match self.listening_thread.take() {
+            Some(listening_thread) => listening_thread,
+            None => return Ok(()),
+        };
+        self.stop_requested.store(true, Ordering::SeqCst);
+        let mut result = listening_thread.join().map_err(|_| Error::Thread);
 
-    fn drain_pending_messages(&mut self) -> Vec<IncomingMessage> {
-        let mut result: Vec<IncomingMessage> = Vec::default();
-        while let Ok(task) = self.task_channels.1.try_recv() {
-            if let Some(message) = self.handle_task(task) {
-                result.push(message);
+        for (client_record, join_handle) in self.client_records.drain(..) {
+            client_record.stream.shutdown(Shutdown::Both).unwrap_or(());
+            if join_handle.join().is_err() {
+                result = Err(Error::Thread);
             }
+            self.disconnected_clients.push(client_record.client_id);
         }
+        // Leftover tasks from the joined threads, including their disconnections
+        self.process_tasks();
+        self.local_addr = None;
         result
     }
 
+    fn drain_pending_messages(&mut self) -> Vec<IncomingMessage> {
+        self.process_tasks();
+        self.pending_messages.drain(..).collect()
+    }
+
+    /// Notifications for clients which have disconnected are dropped
     fn send_notifications(&mut self, notifications: &[OutgoingMessage]) -> Result<(), Self::Error> {
+        let mut result = Ok(());
         for notification in notifications {
-            let stream = self
-                .client_records
-                .iter_mut()
-                .find_map(|(client_record, _join_handle)| {
-                    if client_record.client_id == notification.client_id {
-                        Some(&mut client_record.stream)
-                    } else {
-                        None
-                    }
-                })
-                .expect("TODO");
-            stream.write_all(&notification.bytes[..]).expect("TODO");
+            let stream =
+                self.client_records
+                    .iter_mut()
+                    .find_map(|(client_record, _join_handle)| {
+                        if client_record.client_id == notification.client_id {
+                            Some(&mut client_record.stream)
+                        } else {
+                            None
+                        }
+                    });
+            if let Some(stream) = stream {
+                if stream
+                    .write_all(&self.config.framing.encode(&notification.bytes[..]))
+                    .is_err()
+                {
+                    // The reading thread will see the connection close
+                    result = Err(Error::Net);
+                }
+            }
         }
-        Ok(())
+        result
     }
 }
 
-impl ParticipantPool for Server {
-    fn pop_all_directives(
-        &mut self,
-    ) -> Vec<(
-        crate::participant::ParticipantId,
-        crate::protocol::ClientDirective,
-    )> {
-        todo!();
-    }
-
-    fn push_notifications_to_all(
-        &mut self,
-        _notifications: &[(
-            crate::participant::ParticipantId,
-            crate::protocol::ClientNotification,
-        )],
-    ) {
-        todo!();
+impl<W, L> Drop for Server<W, L>
+where
+    W: WireProtocol,
+    L: Listener,
+{
+    fn drop(&mut self) {
+        self.stop_listening().unwrap_or(());
     }
 }
 
-pub struct ServerConfig {
-    pub ip: String,
-    pub port: u16,
-}
+impl<W, L> ParticipantPool for Server<W, L>
+where
+    W: WireProtocol,
+    L: Listener,
+{
+    fn pop_all_directives(&mut self) -> Vec<(ParticipantId, ClientDirective)> {
+        let messages = self.drain_pending_messages();
+        let disconnected_clients = self.disconnected_clients.drain(..).collect();
+        let (directives, replies) = self
+            .sessions
+            .decode_directives::<W>(messages, disconnected_clients);
+        self.send_notifications(&replies[..]).unwrap_or(());
+        directives
+    }
 
-impl Default for ServerConfig {
-    fn default() -> Self {
-        Self {
-            ip: "127.0.0.1".to_owned(),
-            port: 8080,
-        }
+    fn push_notifications_to_all(&mut self, notifications: &[(ParticipantId, ClientNotification)]) {
+        let outgoing_messages = self.sessions.encode_notifications::<W>(notifications);
+        self.send_notifications(&outgoing_messages[..])
+            .unwrap_or(());
     }
 }
 
-struct ClientRecord {
+struct ClientRecord<S> {
     client_id: ClientId,
-    stream: TcpStream,
+    stream: S,
 }
 
-enum ServerTask {
-    NewClient(TcpStream),
+enum ServerTask<S> {
+    NewClient(S),
     IncomingMessage(IncomingMessage),
+    Disconnected(ClientId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auction::Side;
+    use crate::clock::Timestamp;
+    use crate::server::framing::{Framing, DEFAULT_MAX_FRAME_SIZE};
+    use crate::server::test_support::{pop_directives_until, TestProtocol};
+    use crate::server::ServerMode;
+    use crate::{Price, ProductId};
+    use std::time::Instant;
+
+    fn listening_server() -> Server<TestProtocol> {
+        listening_server_with_framing(Framing::NewlineDelimited, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    fn listening_server_with_framing(
+        framing: Framing,
+        max_frame_size: usize,
+    ) -> Server<TestProtocol> {
+        let mut server = Server::<TestProtocol>::new(ServerConfig {
+            port: 0,
+            mode: ServerMode::Threaded,
+            framing,
+            max_frame_size,
+            ..ServerConfig::default()
+        });
+        server.start_listening().unwrap();
+        server
+    }
+
+    fn connect_client(server: &mut Server<TestProtocol>) -> TcpStream {
+        let client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.client_records.is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for connection"
+            );
+            assert!(server.drain_pending_messages().is_empty());
+            thread::sleep(Duration::from_millis(1));
+        }
+        client
+    }
+
+    #[test]
+    fn directives_decoded_from_connection() {
+        let mut server = listening_server();
+        let mut client = connect_client(&mut server);
+
+        client.write_all(b"join\n").unwrap();
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Join {})]
+        );
+    }
+
+    #[test]
+    fn undecodable_messages_rejected() {
+        let mut server = listening_server();
+        let mut client = connect_client(&mut server);
+
+        client.write_all(b"garbage\n").unwrap();
+        client.write_all(b"join\n").unwrap();
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Join {})]
+        );
+
+        let rejected = ClientNotification::Rejected {
+            reason: "TestProtocolError".to_owned(),
+        };
+        let expected = Framing::NewlineDelimited.encode(format!("{:?}", rejected).as_bytes());
+        let mut received = vec![0u8; expected.len()];
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn malformed_json_rejected_with_position() {
+        let mut server = Server::<JsonProtocol>::new(ServerConfig {
+            port: 0,
+            mode: ServerMode::Threaded,
+            ..ServerConfig::default()
+        });
+        server.start_listening().unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"{\"type\": \"Join\" \"x\"}\n").unwrap();
+
+        client
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut received: Vec<u8> = Vec::default();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !received.ends_with(b"\n") {
+            assert!(Instant::now() < deadline, "Timed out waiting for reply");
+            assert!(server.pop_all_directives().is_empty());
+            let mut buf = [0u8; 256];
+            if let Ok(len) = client.read(&mut buf) {
+                received.extend_from_slice(&buf[..len]);
+            }
+        }
+        let reply =
+            JsonProtocol::try_client_notification_from_bytes(&received[..received.len() - 1]);
+        match reply {
+            Ok(ClientNotification::Rejected { reason }) => {
+                assert!(reason.starts_with("line 1, column 17: "), "{}", reason)
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn notifications_encoded_to_participant_connection() {
+        let mut server = listening_server();
+        let mut client = connect_client(&mut server);
+        client.write_all(b"join\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        let notification = ClientNotification::Trade {
+            product_id: ProductId(1),
+            side: Side::Bid,
+            price: Price(100),
+            quantity: 10,
+            timestamp: Timestamp(0),
+        };
+        server.push_notifications_to_all(&[
+            (ParticipantId(0), notification.clone()),
+            (ParticipantId(123), notification.clone()),
+        ]);
+
+        let sequenced = ClientNotification::Sequenced {
+            sequence: 1,
+            notification: Box::new(notification),
+        };
+        let expected = Framing::NewlineDelimited.encode(format!("{:?}", sequenced).as_bytes());
+        let mut received = vec![0u8; expected.len()];
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn fragmented_and_concatenated_frames() {
+        let mut server = listening_server_with_framing(Framing::LengthPrefixed, 16);
+        let mut client = connect_client(&mut server);
+
+        let mut stream_bytes = Framing::LengthPrefixed.encode(b"join");
+        stream_bytes.extend(Framing::LengthPrefixed.encode(b"leave"));
+        stream_bytes.extend(Framing::LengthPrefixed.encode(b"join"));
+        let (first, rest) = stream_bytes.split_at(6);
+        client.write_all(first).unwrap();
+        client.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(server.pop_all_directives().is_empty());
+        client.write_all(rest).unwrap();
+
+        let directives = pop_directives_until(&mut server, |d| d.len() == 3);
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(0), ClientDirective::Join {}),
+                (ParticipantId(0), ClientDirective::Leave {}),
+                (ParticipantId(0), ClientDirective::Join {}),
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_frame_disconnects_client() {
+        let mut server = listening_server_with_framing(Framing::NewlineDelimited, 8);
+        let mut client = connect_client(&mut server);
+
+        client.write_all(b"join\n0123456789").unwrap();
+        let directives = pop_directives_until(&mut server, |d| d.len() == 2);
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(0), ClientDirective::Join {}),
+                (ParticipantId(0), ClientDirective::Leave {}),
+            ]
+        );
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn clients_get_unique_ids() {
+        let mut server = listening_server();
+        let mut first = connect_client(&mut server);
+        first.write_all(b"join\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+        drop(first);
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        let mut second = connect_client(&mut server);
+        let mut third = connect_client(&mut server);
+        second.write_all(b"join\n").unwrap();
+        third.write_all(b"join\n").unwrap();
+        let mut directives = pop_directives_until(&mut server, |d| d.len() == 2);
+        directives.sort_by_key(|(participant_id, _directive)| participant_id.0);
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(1), ClientDirective::Join {}),
+                (ParticipantId(2), ClientDirective::Join {}),
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnect_leaves_joined_participant() {
+        let mut server = listening_server();
+        let mut client = connect_client(&mut server);
+        client.write_all(b"join\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        drop(client);
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Leave {})]
+        );
+        assert!(server.connected_clients().is_empty());
+        thread::sleep(Duration::from_millis(20));
+        assert!(server.pop_all_directives().is_empty());
+    }
+
+    #[test]
+    fn disconnect_without_join_is_silent() {
+        let mut server = listening_server();
+        let mut client = connect_client(&mut server);
+        client.write_all(b"join\nleave\n").unwrap();
+        pop_directives_until(&mut server, |d| d.len() == 2);
+
+        drop(client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.connected_clients().is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for disconnect"
+            );
+            assert!(server.pop_all_directives().is_empty());
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(server.pop_all_directives().is_empty());
+    }
+
+    #[test]
+    fn stop_listening_closes_connections() {
+        let mut server = listening_server();
+        let address = server.local_addr().unwrap();
+        let mut client = connect_client(&mut server);
+        client.write_all(b"join\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        server.stop_listening().unwrap();
+        assert!(server.connected_clients().is_empty());
+        assert_eq!(
+            server.pop_all_directives(),
+            vec![(ParticipantId(0), ClientDirective::Leave {})]
+        );
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(TcpStream::connect(address).is_err());
+
+        // Stopping twice is harmless
+        server.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn notifications_to_disconnected_participants_dropped() {
+        let mut server = listening_server();
+        let client = connect_client(&mut server);
+        drop(client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.connected_clients().is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for disconnect"
+            );
+            server.pop_all_directives();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let messages = vec![OutgoingMessage {
+            client_id: ClientId(0),
+            bytes: b"hello".to_vec(),
+        }];
+        assert!(server.send_notifications(&messages).is_ok());
+    }
+
+    #[test]
+    fn resume_after_reconnect() {
+        let credentials = Credentials {
+            participants: vec![crate::server::credentials::ParticipantCredentials {
+                name: "alice".to_owned(),
+                id: ParticipantId(7),
+                key: "secret".to_owned(),
+            }],
+        };
+        let mut server = Server::<TestProtocol>::with_credentials(
+            ServerConfig {
+                port: 0,
+                mode: ServerMode::Threaded,
+                ..ServerConfig::default()
+            },
+            credentials,
+        );
+        server.start_listening().unwrap();
+
+        let mut client = connect_client(&mut server);
+        client.write_all(b"login alice secret\njoin\n").unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+        drop(client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.connected_clients().is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for disconnect"
+            );
+            assert!(server.pop_all_directives().is_empty());
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let missed = ClientNotification::Rejected {
+            reason: "missed".to_owned(),
+        };
+        server.push_notifications_to_all(&[(ParticipantId(7), missed.clone())]);
+
+        let mut client = connect_client(&mut server);
+        client.write_all(b"login alice secret\nresume 0\n").unwrap();
+        let mut expected = Framing::NewlineDelimited.encode(
+            format!(
+                "{:?}",
+                ClientNotification::LoggedIn {
+                    participant_id: ParticipantId(7),
+                    last_sequence: 1,
+                }
+            )
+            .as_bytes(),
+        );
+        expected.extend(
+            Framing::NewlineDelimited.encode(
+                format!(
+                    "{:?}",
+                    ClientNotification::Sequenced {
+                        sequence: 1,
+                        notification: Box::new(missed),
+                    }
+                )
+                .as_bytes(),
+            ),
+        );
+        let deadline = Instant::now() + Duration::from_secs(5);
+        client
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut received: Vec<u8> = Vec::default();
+        while received.len() < expected.len() {
+            assert!(Instant::now() < deadline, "Timed out, got {:?}", received);
+            // Replies are sent as the directives are popped
+            assert!(server.pop_all_directives().is_empty());
+            let mut buf = [0u8; 256];
+            if let Ok(len) = client.read(&mut buf) {
+                received.extend_from_slice(&buf[..len]);
+            }
+        }
+        assert_eq!(received, expected);
+    }
 }
@@ -0,0 +1,77 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::participant::{ParticipantId, ParticipantPool};
+use crate::protocol::{ClientDirective, ClientNotification, WireProtocol};
+
+#[derive(Debug)]
+pub struct TestProtocolError;
+impl std::fmt::Display for TestProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for TestProtocolError {}
+
+/// Bare-bones encoding so server tests only exercise the server
+pub struct TestProtocol;
+impl WireProtocol for TestProtocol {
+    type Error = TestProtocolError;
+
+    fn try_client_directive_from_bytes(bytes: &[u8]) -> Result<ClientDirective, Self::Error> {
+        match bytes {
+            b"join" => Ok(ClientDirective::Join {}),
+            b"leave" => Ok(ClientDirective::Leave {}),
+            _ => {
+                // "login <name> <key>" or "resume <last sequence>"
+                let text = std::str::from_utf8(bytes).map_err(|_| TestProtocolError)?;
+                match text.split(' ').collect::<Vec<&str>>()[..] {
+                    ["login", name, key] => Ok(ClientDirective::Login {
+                        name: name.to_owned(),
+                        key: key.to_owned(),
+                    }),
+                    ["resume", last_sequence] => Ok(ClientDirective::Resume {
+                        last_sequence: last_sequence.parse().map_err(|_| TestProtocolError)?,
+                    }),
+                    _ => Err(TestProtocolError),
+                }
+            }
+        }
+    }
+
+    fn try_client_directive_to_bytes(
+        _client_directive: &ClientDirective,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Err(TestProtocolError)
+    }
+
+    fn try_client_notification_from_bytes(
+        _bytes: &[u8],
+    ) -> Result<ClientNotification, Self::Error> {
+        Err(TestProtocolError)
+    }
+
+    fn try_client_notification_to_bytes(
+        client_notification: &ClientNotification,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Ok(format!("{:?}", client_notification).into_bytes())
+    }
+}
+
+pub fn pop_directives_until<P, F>(
+    server: &mut P,
+    predicate: F,
+) -> Vec<(ParticipantId, ClientDirective)>
+where
+    P: ParticipantPool,
+    F: Fn(&Vec<(ParticipantId, ClientDirective)>) -> bool,
+{
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut directives = Vec::default();
+    while !predicate(&directives) {
+        assert!(Instant::now() < deadline, "Timed out, got {:?}", directives);
+        directives.extend(server.pop_all_directives());
+        thread::sleep(Duration::from_millis(1));
+    }
+    directives
+}
@@ -0,0 +1,221 @@
+use std::fs::{self, DirBuilder, File, Permissions};
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use super::tcp::{self, Listener, Stream};
+use super::ServerConfig;
+use crate::protocol::json::JsonProtocol;
+
+/// The threaded server listening on `ServerConfig::unix_socket`, for clients on the exchange's
+/// host.  Who may connect is controlled by the socket file's permissions,
+/// `ServerConfig::unix_socket_permissions`.
+pub type Server<W = JsonProtocol> = tcp::Server<W, UnixSocketListener>;
+
+/// Binds the socket inside a new directory only the exchange can enter, sets its permissions and
+/// then links it into place, so other users can't connect before the permissions apply.  A socket
+/// left behind by a previous exchange is replaced, but not one which is still being listened on,
+/// nor anything which appears at the path meanwhile.  Exchanges binding in the same directory
+/// take turns, holding a lock on it.  The socket file is removed when the listener is dropped.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Listener for UnixSocketListener {
+    type Stream = UnixStream;
+    type Address = PathBuf;
+
+    fn bind(config: &ServerConfig) -> io::Result<Self> {
+        let path = config.unix_socket.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no unix_socket configured")
+        })?;
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let directory = File::open(parent)?;
+        directory.lock()?;
+        remove_if_stale(&path)?;
+
+        let mut private_name = path.clone().into_os_string();
+        private_name.push(format!(".{}.tmp", std::process::id()));
+        let private_directory = PathBuf::from(private_name);
+        fs::remove_dir_all(&private_directory).unwrap_or(());
+        DirBuilder::new().mode(0o700).create(&private_directory)?;
+        let temporary_path = private_directory.join("socket");
+        let placed = UnixListener::bind(&temporary_path).and_then(|listener| {
+            fs::set_permissions(
+                &temporary_path,
+                Permissions::from_mode(config.unix_socket_permissions),
+            )?;
+            // Unlike a rename, fails rather than replacing whatever is at `path`
+            fs::hard_link(&temporary_path, &path)?;
+            Ok(listener)
+        });
+        fs::remove_dir_all(&private_directory).unwrap_or(());
+        let listener = placed?;
+        Ok(Self { listener, path })
+    }
+
+    fn local_addr(&self) -> io::Result<PathBuf> {
+        Ok(self.path.clone())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.listener.set_nonblocking(nonblocking)
+    }
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        self.listener.accept().map(|(stream, _peer_addr)| stream)
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).unwrap_or(());
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Clears `path` of a socket which nobody is listening on any more, refusing if anything else is
+/// there
+fn remove_if_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use", path.display()),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::participant::ParticipantId;
+    use crate::protocol::ClientDirective;
+    use crate::server::framing::Framing;
+    use crate::server::test_support::{pop_directives_until, TestProtocol};
+    use crate::server::Server as ServerTrait;
+    use std::io::Write;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vmx-{}-{}.sock", name, std::process::id()));
+        fs::remove_file(&path).unwrap_or(());
+        path
+    }
+
+    fn unix_config(path: &Path) -> ServerConfig {
+        ServerConfig {
+            unix_socket: Some(path.to_owned()),
+            framing: Framing::LengthPrefixed,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn directives_decoded_from_connection() {
+        let path = socket_path("directives");
+        let mut server = Server::<TestProtocol>::new(unix_config(&path));
+        server.start_listening().unwrap();
+        assert_eq!(server.local_addr(), Some(path.clone()));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(&Framing::LengthPrefixed.encode(b"join"))
+            .unwrap();
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Join {})]
+        );
+    }
+
+    #[test]
+    fn socket_permissions_applied() {
+        let path = socket_path("permissions");
+        let mut server = Server::<TestProtocol>::new(ServerConfig {
+            unix_socket_permissions: 0o640,
+            ..unix_config(&path)
+        });
+        server.start_listening().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn only_socket_left_in_directory() {
+        let directory = std::env::temp_dir().join(format!("vmx-only-{}", std::process::id()));
+        fs::remove_dir_all(&directory).unwrap_or(());
+        fs::create_dir(&directory).unwrap();
+        let path = directory.join("exchange.sock");
+        let mut server = Server::<TestProtocol>::new(unix_config(&path));
+        server.start_listening().unwrap();
+
+        let entries: Vec<PathBuf> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(entries, vec![path]);
+        server.stop_listening().unwrap();
+        fs::remove_dir(&directory).unwrap();
+    }
+
+    #[test]
+    fn stale_socket_replaced_and_removed_on_stop() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let mut server = Server::<TestProtocol>::new(unix_config(&path));
+        server.start_listening().unwrap();
+        UnixStream::connect(&path).unwrap();
+        server.stop_listening().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn socket_in_use_or_other_file_refused() {
+        let path = socket_path("in-use");
+        let mut server = Server::<TestProtocol>::new(unix_config(&path));
+        server.start_listening().unwrap();
+        let mut second_server = Server::<TestProtocol>::new(unix_config(&path));
+        assert!(second_server.start_listening().is_err());
+        // The first server's socket is left alone
+        UnixStream::connect(&path).unwrap();
+
+        let path = socket_path("regular-file");
+        fs::write(&path, b"not a socket").unwrap();
+        let mut server = Server::<TestProtocol>::new(unix_config(&path));
+        assert!(server.start_listening().is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}
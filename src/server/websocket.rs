@@ -0,0 +1,643 @@
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::Frame;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, WebSocket};
+
+use super::credentials::Credentials;
+use super::session::Sessions;
+use super::{ClientId, IncomingMessage, OutgoingMessage, Server as ServerTrait, ServerConfig};
+use crate::participant::{ParticipantId, ParticipantPool};
+use crate::protocol::json::JsonProtocol;
+use crate::protocol::{ClientDirective, ClientNotification, WireProtocol};
+
+/// How often the listening and connection threads check whether they have been asked to stop
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a client has to complete the opening handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a client to answer the closing handshake when stopping
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum Error {
+    Net,
+    Thread,
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for Error {}
+
+/// Thread-per-connection WebSocket server, for browser clients.  As a `ParticipantPool` it
+/// behaves like `tcp::Server`, with each WebSocket message carrying one message of the
+/// `WireProtocol` `W` in place of `ServerConfig::framing`.
+///
+/// Clients may send text or binary messages, and are answered in whichever kind they last sent,
+/// text until they send binary.  Pings are answered, and `stop_listening` closes each connection
+/// with a closing handshake.
+pub struct Server<W = JsonProtocol>
+where
+    W: WireProtocol,
+{
+    config: ServerConfig,
+    local_addr: Option<SocketAddr>,
+    listening_thread: Option<JoinHandle<()>>,
+    stop_requested: Arc<AtomicBool>,
+    next_client_id: u64,
+    client_records: Vec<(ClientRecord, JoinHandle<()>)>,
+    task_channels: (Sender<ServerTask>, Receiver<ServerTask>),
+    pending_messages: Vec<IncomingMessage>,
+    disconnected_clients: Vec<ClientId>,
+    sessions: Sessions,
+    protocol: PhantomData<W>,
+}
+
+impl<W> Server<W>
+where
+    W: WireProtocol,
+{
+    pub fn new(config: ServerConfig) -> Self {
+        let sessions = Sessions::new(None, config.session_outbox_size, true);
+        Self::with_sessions(config, sessions)
+    }
+
+    /// Only participants listed in `credentials` may log in and act
+    pub fn with_credentials(config: ServerConfig, credentials: Credentials) -> Self {
+        let sessions = Sessions::new(Some(credentials), config.session_outbox_size, true);
+        Self::with_sessions(config, sessions)
+    }
+
+    fn with_sessions(config: ServerConfig, sessions: Sessions) -> Self {
+        Self {
+            config,
+            local_addr: None,
+            listening_thread: None,
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            next_client_id: 0,
+            client_records: vec![],
+            task_channels: mpsc::channel(),
+            pending_messages: Vec::default(),
+            disconnected_clients: Vec::default(),
+            sessions,
+            protocol: PhantomData,
+        }
+    }
+
+    /// Address actually bound, which differs from the configured one when listening on port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Clients which have completed the opening handshake
+    pub fn connected_clients(&self) -> Vec<ClientId> {
+        self.client_records
+            .iter()
+            .filter(|(client_record, _join_handle)| client_record.open.load(Ordering::SeqCst))
+            .map(|(client_record, _join_handle)| client_record.client_id)
+            .collect()
+    }
+
+    fn process_tasks(&mut self) {
+        while let Ok(task) = self.task_channels.1.try_recv() {
+            self.handle_task(task);
+        }
+    }
+
+    fn handle_task(&mut self, task: ServerTask) {
+        match task {
+            ServerTask::NewClient(stream) => {
+                if self.listening_thread.is_none() {
+                    // Accepted just before stop_listening
+                    stream.shutdown(Shutdown::Both).unwrap_or(());
+                    return;
+                }
+                if let Err(e) = self.add_client(stream) {
+                    eprintln!("Connecting a client failed: {}", e);
+                }
+            }
+            ServerTask::IncomingMessage(message) => self.pending_messages.push(message),
+            ServerTask::Disconnected(client_id) => {
+                if let Some(idx) = self
+                    .client_records
+                    .iter()
+                    .position(|(client_record, _join_handle)| client_record.client_id == client_id)
+                {
+                    let (_client_record, join_handle) = self.client_records.remove(idx);
+                    join_handle.join().unwrap_or(());
+                    self.disconnected_clients.push(client_id);
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        let client_id = ClientId(self.next_client_id);
+        self.next_client_id += 1;
+        let record = ClientRecord {
+            client_id,
+            writer: Arc::new(Mutex::new(stream.try_clone()?)),
+            open: Arc::new(AtomicBool::new(false)),
+            binary: Arc::new(AtomicBool::new(false)),
+        };
+        let connection = Connection {
+            client_id,
+            stream: ConnectionStream {
+                reader: stream,
+                writer: record.writer.clone(),
+            },
+            open: record.open.clone(),
+            binary: record.binary.clone(),
+            send_channel: self.task_channels.0.clone(),
+            stop_requested: self.stop_requested.clone(),
+        };
+        let websocket_config = WebSocketConfig {
+            max_message_size: Some(self.config.max_frame_size),
+            max_frame_size: Some(self.config.max_frame_size),
+            write_buffer_size: 0,
+            ..WebSocketConfig::default()
+        };
+        let join_handle = thread::spawn(move || connection.run(websocket_config));
+        self.client_records.push((record, join_handle));
+        Ok(())
+    }
+}
+
+impl<W> ServerTrait for Server<W>
+where
+    W: WireProtocol,
+{
+    type Error = self::Error;
+
+    fn start_listening(&mut self) -> Result<(), Self::Error> {
+        assert!(self.listening_thread.is_none());
+        let listener = TcpListener::bind(format!("{}:{}", &self.config.ip, self.config.port))
+            .map_err(|_| Error::Net)?;
+        listener.set_nonblocking(true).map_err(|_| Error::Net)?;
+        self.local_addr = Some(listener.local_addr().map_err(|_| Error::Net)?);
+        self.stop_requested.store(false, Ordering::SeqCst);
+
+        let listener_sending_channel = self.task_channels.0.clone();
+        let stop_requested = self.stop_requested.clone();
+        self.listening_thread = thread::spawn(move || {
+            while !stop_requested.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _peer_addr)) => {
+                        if listener_sending_channel
+                            .send(ServerTask::NewClient(stream))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(STOP_POLL_INTERVAL)
+                    }
+                    Err(e) => {
+                        eprintln!("Accepting clients failed, no longer listening: {}", e);
+                        break;
+                    }
+                }
+            }
+        })
+        .into();
+        Ok(())
+    }
+
+    /// Closes the listening socket and every client connection, waiting for their threads to
+    /// finish.  Clients are reported as disconnected by the next `pop_all_directives`.
+    fn stop_listening(&mut self) -> Result<(), Self::Error> {
+        let listening_thread = match self.listening_thread.take() {
+            Some(listening_thread) => listening_thread,
+            None => return Ok(()),
+        };
+        self.stop_requested.store(true, Ordering::SeqCst);
+        let mut result = listening_thread.join().map_err(|_| Error::Thread);
+
+        // Each connection thread sees the request and closes its connection
+        for (client_record, join_handle) in self.client_records.drain(..) {
+            if join_handle.join().is_err() {
+                result = Err(Error::Thread);
+            }
+            self.disconnected_clients.push(client_record.client_id);
+        }
+        // Leftover tasks from the joined threads, including their disconnections
+        self.process_tasks();
+        self.local_addr = None;
+        result
+    }
+
+    fn drain_pending_messages(&mut self) -> Vec<IncomingMessage> {
+        self.process_tasks();
+        self.pending_messages.drain(..).collect()
+    }
+
+    /// Notifications for clients which have disconnected, or not finished the opening
+    /// handshake, are dropped
+    fn send_notifications(&mut self, notifications: &[OutgoingMessage]) -> Result<(), Self::Error> {
+        let mut result = Ok(());
+        for notification in notifications {
+            let client_record =
+                self.client_records
+                    .iter()
+                    .find_map(|(client_record, _join_handle)| {
+                        if client_record.client_id == notification.client_id
+                            && client_record.open.load(Ordering::SeqCst)
+                        {
+                            Some(client_record)
+                        } else {
+                            None
+                        }
+                    });
+            if let Some(client_record) = client_record {
+                if client_record.send(&notification.bytes).is_err() {
+                    // The connection thread will see the connection close
+                    result = Err(Error::Net);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<W> Drop for Server<W>
+where
+    W: WireProtocol,
+{
+    fn drop(&mut self) {
+        self.stop_listening().unwrap_or(());
+    }
+}
+
+impl<W> ParticipantPool for Server<W>
+where
+    W: WireProtocol,
+{
+    fn pop_all_directives(&mut self) -> Vec<(ParticipantId, ClientDirective)> {
+        let messages = self.drain_pending_messages();
+        let disconnected_clients = self.disconnected_clients.drain(..).collect();
+        let (directives, replies) = self
+            .sessions
+            .decode_directives::<W>(messages, disconnected_clients);
+        self.send_notifications(&replies[..]).unwrap_or(());
+        directives
+    }
+
+    fn push_notifications_to_all(&mut self, notifications: &[(ParticipantId, ClientNotification)]) {
+        let outgoing_messages = self.sessions.encode_notifications::<W>(notifications);
+        self.send_notifications(&outgoing_messages[..])
+            .unwrap_or(());
+    }
+}
+
+struct ClientRecord {
+    client_id: ClientId,
+    /// Shared with the connection thread, which writes control frames
+    writer: Arc<Mutex<TcpStream>>,
+    /// Set once the opening handshake is complete
+    open: Arc<AtomicBool>,
+    /// Whether the client last sent a binary message rather than text
+    binary: Arc<AtomicBool>,
+}
+
+impl ClientRecord {
+    fn send(&self, bytes: &[u8]) -> io::Result<()> {
+        // Text messages must be UTF-8
+        let data = match std::str::from_utf8(bytes) {
+            Ok(_) if !self.binary.load(Ordering::SeqCst) => Data::Text,
+            _ => Data::Binary,
+        };
+        let mut frame_bytes: Vec<u8> = Vec::with_capacity(bytes.len() + 10);
+        Frame::message(bytes.to_vec(), OpCode::Data(data), true)
+            .format(&mut frame_bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        lock_writer(&self.writer)?.write_all(&frame_bytes)
+    }
+}
+
+fn lock_writer(writer: &Mutex<TcpStream>) -> io::Result<std::sync::MutexGuard<'_, TcpStream>> {
+    writer
+        .lock()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "poisoned writer"))
+}
+
+/// Reads from the connection's own socket and writes through the lock shared with the server, so
+/// that frames from the two threads never interleave.  Each write is written whole.
+struct ConnectionStream {
+    reader: TcpStream,
+    writer: Arc<Mutex<TcpStream>>,
+}
+
+impl Read for ConnectionStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for ConnectionStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock_writer(&self.writer)?.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock_writer(&self.writer)?.flush()
+    }
+}
+
+/// The state moved to a connection's thread, which does the handshakes and reads messages
+struct Connection {
+    client_id: ClientId,
+    stream: ConnectionStream,
+    open: Arc<AtomicBool>,
+    binary: Arc<AtomicBool>,
+    send_channel: Sender<ServerTask>,
+    stop_requested: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn run(self, websocket_config: WebSocketConfig) {
+        let Connection {
+            client_id,
+            stream,
+            open,
+            binary,
+            send_channel,
+            stop_requested,
+        } = self;
+        let reader = stream.reader.try_clone();
+        match accept(stream, websocket_config) {
+            Ok(mut websocket) => {
+                open.store(true, Ordering::SeqCst);
+                'reading: loop {
+                    if stop_requested.load(Ordering::SeqCst) {
+                        close(&mut websocket);
+                        break 'reading;
+                    }
+                    let bytes = match websocket.read() {
+                        Ok(Message::Text(text)) => {
+                            binary.store(false, Ordering::SeqCst);
+                            text.into_bytes()
+                        }
+                        Ok(Message::Binary(bytes)) => {
+                            binary.store(true, Ordering::SeqCst);
+                            bytes
+                        }
+                        // Pongs and closing replies are queued by tungstenite and sent on the next read
+                        Ok(_) => continue,
+                        Err(tungstenite::Error::Io(e)) if is_timeout(&e) => continue,
+                        // Closed, or an oversized or invalid message, which the stream can't recover from
+                        Err(_) => break 'reading,
+                    };
+                    let message = IncomingMessage { client_id, bytes };
+                    if send_channel
+                        .send(ServerTask::IncomingMessage(message))
+                        .is_err()
+                    {
+                        // The server has been dropped
+                        break 'reading;
+                    }
+                }
+            }
+            Err(e) => eprintln!(
+                "WebSocket handshake with client {} failed: {}",
+                client_id.0, e
+            ),
+        }
+        if let Ok(reader) = reader {
+            reader.shutdown(Shutdown::Both).unwrap_or(());
+        }
+        send_channel
+            .send(ServerTask::Disconnected(client_id))
+            .unwrap_or(());
+    }
+}
+
+fn accept(
+    stream: ConnectionStream,
+    websocket_config: WebSocketConfig,
+) -> io::Result<WebSocket<ConnectionStream>> {
+    stream.reader.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let websocket = tungstenite::accept_with_config(stream, Some(websocket_config))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    // Reads time out so the thread notices stop_listening
+    websocket
+        .get_ref()
+        .reader
+        .set_read_timeout(Some(STOP_POLL_INTERVAL))?;
+    Ok(websocket)
+}
+
+/// Starts the closing handshake and waits a little for the client's reply
+fn close(websocket: &mut WebSocket<ConnectionStream>) {
+    if websocket.close(None).is_err() {
+        return;
+    }
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while Instant::now() < deadline {
+        match websocket.read() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if is_timeout(&e) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+enum ServerTask {
+    NewClient(TcpStream),
+    IncomingMessage(IncomingMessage),
+    Disconnected(ClientId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{pop_directives_until, TestProtocol};
+    use crate::server::ServerMode;
+
+    type Client = WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+    fn listening_server(max_frame_size: usize) -> Server<TestProtocol> {
+        let mut server = Server::<TestProtocol>::new(ServerConfig {
+            port: 0,
+            mode: ServerMode::WebSocket,
+            max_frame_size,
+            ..ServerConfig::default()
+        });
+        server.start_listening().unwrap();
+        server
+    }
+
+    fn connect_client(server: &mut Server<TestProtocol>) -> Client {
+        // The handshake needs the server to hand the connection to its thread meanwhile
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let connecting = thread::spawn(move || tungstenite::connect(url).unwrap().0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.connected_clients().is_empty() || !connecting.is_finished() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for connection"
+            );
+            assert!(server.drain_pending_messages().is_empty());
+            thread::sleep(Duration::from_millis(1));
+        }
+        let client = connecting.join().unwrap();
+        if let tungstenite::stream::MaybeTlsStream::Plain(stream) = client.get_ref() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        client
+    }
+
+    fn rejected(reason: &str) -> ClientNotification {
+        ClientNotification::Rejected {
+            reason: reason.to_owned(),
+        }
+    }
+
+    #[test]
+    fn text_and_binary_messages_decoded() {
+        let mut server = listening_server(1024);
+        let mut client = connect_client(&mut server);
+
+        client.send(Message::Text("join".to_owned())).unwrap();
+        client.send(Message::Binary(b"leave".to_vec())).unwrap();
+        let directives = pop_directives_until(&mut server, |d| d.len() == 2);
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(0), ClientDirective::Join {}),
+                (ParticipantId(0), ClientDirective::Leave {}),
+            ]
+        );
+    }
+
+    #[test]
+    fn replies_match_client_message_kind() {
+        let mut server = listening_server(1024);
+        let mut client = connect_client(&mut server);
+
+        client.send(Message::Text("leave".to_owned())).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        server.pop_all_directives();
+        assert_eq!(
+            client.read().unwrap(),
+            Message::Text(format!("{:?}", rejected("not joined")))
+        );
+
+        client.send(Message::Binary(b"leave".to_vec())).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        server.pop_all_directives();
+        assert_eq!(
+            client.read().unwrap(),
+            Message::Binary(format!("{:?}", rejected("not joined")).into_bytes())
+        );
+    }
+
+    #[test]
+    fn pings_answered() {
+        let mut server = listening_server(1024);
+        let mut client = connect_client(&mut server);
+
+        client
+            .send(Message::Ping(b"are you there".to_vec()))
+            .unwrap();
+        assert_eq!(
+            client.read().unwrap(),
+            Message::Pong(b"are you there".to_vec())
+        );
+    }
+
+    #[test]
+    fn client_close_leaves_joined_participant() {
+        let mut server = listening_server(1024);
+        let mut client = connect_client(&mut server);
+        client.send(Message::Text("join".to_owned())).unwrap();
+        pop_directives_until(&mut server, |d| !d.is_empty());
+
+        client.close(None).unwrap();
+        // The server's reply completes the closing handshake
+        loop {
+            match client.read() {
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let directives = pop_directives_until(&mut server, |d| !d.is_empty());
+        assert_eq!(
+            directives,
+            vec![(ParticipantId(0), ClientDirective::Leave {})]
+        );
+        assert!(server.connected_clients().is_empty());
+    }
+
+    #[test]
+    fn oversized_message_disconnects_client() {
+        let mut server = listening_server(16);
+        let mut client = connect_client(&mut server);
+        client.send(Message::Text("join".to_owned())).unwrap();
+        client.send(Message::Text("x".repeat(17))).unwrap();
+
+        let directives = pop_directives_until(&mut server, |d| d.len() == 2);
+        assert_eq!(
+            directives,
+            vec![
+                (ParticipantId(0), ClientDirective::Join {}),
+                (ParticipantId(0), ClientDirective::Leave {}),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_handshake_disconnects() {
+        let mut server = listening_server(1024);
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        // Not a WebSocket upgrade request
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.disconnected_clients.is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for disconnect"
+            );
+            server.drain_pending_messages();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(server.connected_clients().is_empty());
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = Vec::default();
+        client.read_to_end(&mut received).unwrap();
+    }
+
+    #[test]
+    fn stop_listening_closes_connections() {
+        let mut server = listening_server(1024);
+        let address = server.local_addr().unwrap();
+        let mut client = connect_client(&mut server);
+
+        server.stop_listening().unwrap();
+        assert!(matches!(client.read(), Ok(Message::Close(_))));
+        assert!(server.connected_clients().is_empty());
+        assert!(tungstenite::connect(format!("ws://{}", address)).is_err());
+    }
+}
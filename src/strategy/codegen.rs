@@ -0,0 +1,729 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use super::parser::{BinaryOp, Expr, ExprKind, Stmt, StmtKind, UnaryOp};
+use super::Error;
+use crate::vm::{Instruction, RegIdx};
+
+/// r0 to r14, since r15 is the program counter
+const NUM_ALLOCATABLE_REGISTERS: usize = 15;
+
+/// Where the inputs are, see `auction::ProgramInstance`
+const PARAMETERS_ARRAY: i64 = 0;
+const BID_ORDERS_ARRAY: i64 = 9;
+const OFFER_ORDERS_ARRAY: i64 = 10;
+const STORAGE_ARRAY: i64 = 12;
+
+/// Named inputs, each an array and index
+const INPUTS: &[(&str, i64, i64)] = &[
+    ("lowest_bid", 1, 0),
+    ("best_bid", 1, 1),
+    ("best_offer", 3, 0),
+    ("highest_offer", 3, 1),
+    ("my_lowest_bid", 5, 0),
+    ("my_best_bid", 5, 1),
+    ("my_best_offer", 7, 0),
+    ("my_highest_offer", 7, 1),
+    ("my_position", 11, 0),
+];
+
+/// Inputs taking one argument, each an array indexed by it, and what the argument is
+const INDEXED_INPUTS: &[(&str, i64, &str)] = &[
+    ("bid_size", 2, "a price"),
+    ("offer_size", 4, "a price"),
+    ("my_bid_size", 6, "a price"),
+    ("my_offer_size", 8, "a price"),
+    ("stored", STORAGE_ARRAY, "a key"),
+];
+
+pub fn generate(statements: &[Stmt]) -> Result<Vec<Instruction>, Error> {
+    let mut generator = Generator {
+        code: Vec::default(),
+        labels: Vec::default(),
+        registers_in_use: [false; NUM_ALLOCATABLE_REGISTERS],
+        scopes: vec![HashMap::default()],
+        parameters: HashMap::default(),
+        line: 1,
+        column: 1,
+    };
+    generator.block(statements)?;
+    generator.emit(Instruction::Halt {});
+    Ok(generator.resolve_labels())
+}
+
+enum Emitted {
+    Instruction(Instruction),
+    /// `movimm` of a label's address, which is only known once all the code is generated
+    LoadLabel {
+        dst: RegIdx,
+        label: usize,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct Variable {
+    reg: RegIdx,
+    /// Loop variables can't be assigned, so that loops stay bounded
+    mutable: bool,
+}
+
+/// An expression's result, in a register which the caller frees if `temporary`, or in a
+/// variable's register otherwise
+#[derive(Clone, Copy)]
+struct Value {
+    reg: RegIdx,
+    temporary: bool,
+}
+
+struct Generator {
+    code: Vec<Emitted>,
+    labels: Vec<Option<usize>>,
+    registers_in_use: [bool; NUM_ALLOCATABLE_REGISTERS],
+    scopes: Vec<HashMap<String, Variable>>,
+    parameters: HashMap<String, u64>,
+    /// Where the statement or expression being generated is, for errors
+    line: usize,
+    column: usize,
+}
+
+impl Generator {
+    fn error(&self, message: String) -> Error {
+        Error::new(self.line, self.column, message)
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.code.push(Emitted::Instruction(instruction));
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place_label(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    fn resolve_labels(self) -> Vec<Instruction> {
+        let labels = self.labels;
+        self.code
+            .into_iter()
+            .map(|emitted| match emitted {
+                Emitted::Instruction(instruction) => instruction,
+                Emitted::LoadLabel { dst, label } => Instruction::MovImm {
+                    dst,
+                    imm: labels[label].expect("every label is placed") as i32,
+                },
+            })
+            .collect()
+    }
+
+    fn allocate(&mut self) -> Result<RegIdx, Error> {
+        match self.registers_in_use.iter().position(|in_use| !in_use) {
+            Some(idx) => {
+                self.registers_in_use[idx] = true;
+                Ok(RegIdx(idx as u8))
+            }
+            None => Err(self.error(format!(
+                "out of registers, at most {} variables and intermediate values can be live at once",
+                NUM_ALLOCATABLE_REGISTERS
+            ))),
+        }
+    }
+
+    fn free(&mut self, reg: RegIdx) {
+        self.registers_in_use[reg.0 as usize] = false;
+    }
+
+    fn release(&mut self, value: Value) {
+        if value.temporary {
+            self.free(value.reg);
+        }
+    }
+
+    /// A register to write an operation's result to, reusing one of the operands' if possible
+    fn result_register(&mut self, operands: &[Value]) -> Result<RegIdx, Error> {
+        match operands.iter().find(|value| value.temporary) {
+            Some(value) => Ok(value.reg),
+            None => self.allocate(),
+        }
+    }
+
+    fn variable(&self, name: &str) -> Option<Variable> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    fn block(&mut self, statements: &[Stmt]) -> Result<(), Error> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn scoped_block(&mut self, statements: &[Stmt]) -> Result<(), Error> {
+        self.scopes.push(HashMap::default());
+        let result = self.block(statements);
+        self.pop_scope();
+        result
+    }
+
+    fn pop_scope(&mut self) {
+        let scope = self.scopes.pop().expect("scopes are balanced");
+        for variable in scope.values() {
+            self.free(variable.reg);
+        }
+    }
+
+    fn declare(&mut self, name: &str, reg: RegIdx, mutable: bool) -> Result<(), Error> {
+        if INPUTS.iter().any(|(input, _arr, _idx)| *input == name) {
+            return Err(self.error(format!("\"{}\" is an input and can't be redefined", name)));
+        }
+        let previous = self
+            .scopes
+            .last_mut()
+            .expect("there is always a scope")
+            .insert(name.to_owned(), Variable { reg, mutable });
+        if let Some(previous) = previous {
+            self.free(previous.reg);
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), Error> {
+        self.line = statement.line;
+        self.column = statement.column;
+        match &statement.kind {
+            StmtKind::Param { name, idx } => {
+                if self.parameters.insert(name.clone(), *idx).is_some() {
+                    return Err(self.error(format!("parameter \"{}\" is already defined", name)));
+                }
+            }
+            StmtKind::Let { name, value } => {
+                let value = self.expression(value)?;
+                let reg = if value.temporary {
+                    value.reg
+                } else {
+                    let reg = self.allocate()?;
+                    self.emit(Instruction::Mov {
+                        dst: reg,
+                        src: value.reg,
+                    });
+                    reg
+                };
+                self.line = statement.line;
+                self.column = statement.column;
+                self.declare(name, reg, true)?;
+            }
+            StmtKind::Assign { name, value } => {
+                let variable = match self.variable(name) {
+                    Some(variable) if variable.mutable => variable,
+                    Some(_) => {
+                        return Err(
+                            self.error(format!("loop variable \"{}\" can't be assigned", name))
+                        )
+                    }
+                    None => return Err(self.error(format!("unknown variable \"{}\"", name))),
+                };
+                let value = self.expression(value)?;
+                self.emit(Instruction::Mov {
+                    dst: variable.reg,
+                    src: value.reg,
+                });
+                self.release(value);
+            }
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let else_label = self.new_label();
+                self.branch(condition, false, else_label)?;
+                self.scoped_block(then_block)?;
+                if else_block.is_empty() {
+                    self.place_label(else_label);
+                } else {
+                    let end_label = self.new_label();
+                    self.jump(end_label)?;
+                    self.place_label(else_label);
+                    self.scoped_block(else_block)?;
+                    self.place_label(end_label);
+                }
+            }
+            StmtKind::For {
+                variable,
+                start,
+                end,
+                body,
+            } => {
+                self.scopes.push(HashMap::default());
+                let result = self.for_loop(variable, start, end, body);
+                self.pop_scope();
+                result?;
+            }
+            StmtKind::Call { name, args } => self.output(name, args)?,
+            StmtKind::Halt => self.emit(Instruction::Halt {}),
+        }
+        Ok(())
+    }
+
+    fn for_loop(
+        &mut self,
+        variable: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Stmt],
+    ) -> Result<(), Error> {
+        let counter = self.copy_to_new_register(start)?;
+        let limit = self.copy_to_new_register(end)?;
+        // The limit isn't reachable by name, but is freed with the loop's scope
+        self.scopes
+            .last_mut()
+            .expect("the loop has a scope")
+            .insert(
+                String::default(),
+                Variable {
+                    reg: limit,
+                    mutable: false,
+                },
+            );
+        self.declare(variable, counter, false)?;
+
+        let top_label = self.new_label();
+        let end_label = self.new_label();
+        self.place_label(top_label);
+        let address = self.allocate()?;
+        self.emit_load_label(address, end_label);
+        self.emit(Instruction::Jge {
+            adr: address,
+            v0: counter,
+            v1: limit,
+        });
+        self.free(address);
+
+        self.scoped_block(body)?;
+
+        let one = self.constant(1)?;
+        self.emit(Instruction::Add {
+            dst: counter,
+            v0: counter,
+            v1: one,
+        });
+        self.free(one);
+        self.jump(top_label)?;
+        self.place_label(end_label);
+        Ok(())
+    }
+
+    fn copy_to_new_register(&mut self, expr: &Expr) -> Result<RegIdx, Error> {
+        let value = self.expression(expr)?;
+        if value.temporary {
+            return Ok(value.reg);
+        }
+        let reg = self.allocate()?;
+        self.emit(Instruction::Mov {
+            dst: reg,
+            src: value.reg,
+        });
+        Ok(reg)
+    }
+
+    /// `bid(price, quantity)` and `offer(price, quantity)` add to the orders at a price, ignoring
+    /// prices below 1.  `cancel_bids()` and `cancel_offers()` drop the orders from previous rounds.
+    /// `store(key, value)` keeps a value for `stored(key)` to read in later rounds.
+    fn output(&mut self, name: &str, args: &[Expr]) -> Result<(), Error> {
+        match (name, args) {
+            ("bid", [price, quantity]) => self.add_order(BID_ORDERS_ARRAY, price, quantity),
+            ("offer", [price, quantity]) => self.add_order(OFFER_ORDERS_ARRAY, price, quantity),
+            ("cancel_bids", []) => self.cancel_orders(BID_ORDERS_ARRAY),
+            ("cancel_offers", []) => self.cancel_orders(OFFER_ORDERS_ARRAY),
+            ("store", [key, value]) => self.store(key, value),
+            ("bid", _) | ("offer", _) => Err(self.error(format!(
+                "{} takes a price and a quantity, not {} arguments",
+                name,
+                args.len()
+            ))),
+            ("cancel_bids", _) | ("cancel_offers", _) => {
+                Err(self.error(format!("{} takes no arguments", name)))
+            }
+            ("store", _) => Err(self.error(format!(
+                "store takes a key and a value, not {} arguments",
+                args.len()
+            ))),
+            _ => Err(self.error(format!(
+                "unknown output \"{}\", expected bid, offer, cancel_bids, cancel_offers or store",
+                name
+            ))),
+        }
+    }
+
+    fn add_order(&mut self, arr: i64, price: &Expr, quantity: &Expr) -> Result<(), Error> {
+        let price = self.expression(price)?;
+        let quantity = self.expression(quantity)?;
+        let skip_label = self.new_label();
+        let zero = self.constant(0)?;
+        let address = self.allocate()?;
+        self.emit_load_label(address, skip_label);
+        self.emit(Instruction::Jle {
+            adr: address,
+            v0: price.reg,
+            v1: zero,
+        });
+        self.free(zero);
+
+        let arr_reg = address;
+        self.load_constant(arr_reg, arr)?;
+        let total = self.allocate()?;
+        self.emit(Instruction::ArrGet {
+            dst: total,
+            arr: arr_reg,
+            idx: price.reg,
+        });
+        self.emit(Instruction::Add {
+            dst: total,
+            v0: total,
+            v1: quantity.reg,
+        });
+        self.emit(Instruction::ArrIns {
+            val: total,
+            arr: arr_reg,
+            idx: price.reg,
+        });
+        self.place_label(skip_label);
+        self.free(total);
+        self.free(arr_reg);
+        self.release(quantity);
+        self.release(price);
+        Ok(())
+    }
+
+    fn store(&mut self, key: &Expr, value: &Expr) -> Result<(), Error> {
+        let key = self.expression(key)?;
+        let value = self.expression(value)?;
+        let arr_reg = self.constant(STORAGE_ARRAY)?;
+        self.emit(Instruction::ArrIns {
+            val: value.reg,
+            arr: arr_reg,
+            idx: key.reg,
+        });
+        self.free(arr_reg);
+        self.release(value);
+        self.release(key);
+        Ok(())
+    }
+
+    fn cancel_orders(&mut self, arr: i64) -> Result<(), Error> {
+        let arr_reg = self.constant(arr)?;
+        let idx = self.constant(0)?;
+        let one = self.constant(1)?;
+        self.emit(Instruction::ArrIns {
+            val: one,
+            arr: arr_reg,
+            idx,
+        });
+        self.free(one);
+        self.free(idx);
+        self.free(arr_reg);
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<Value, Error> {
+        self.line = expr.line;
+        self.column = expr.column;
+        match &expr.kind {
+            ExprKind::Number(n) => Ok(Value {
+                reg: self.constant(*n)?,
+                temporary: true,
+            }),
+            ExprKind::Name(name) => {
+                if let Some(variable) = self.variable(name) {
+                    return Ok(Value {
+                        reg: variable.reg,
+                        temporary: false,
+                    });
+                }
+                match INPUTS.iter().find(|(input, _arr, _idx)| input == name) {
+                    Some((_input, arr, idx)) => self.read_input(*arr, *idx),
+                    None => Err(self.error(format!("unknown variable or input \"{}\"", name))),
+                }
+            }
+            ExprKind::Param(name) => match self.parameters.get(name) {
+                Some(idx) => {
+                    let idx = *idx as i64;
+                    self.read_input(PARAMETERS_ARRAY, idx)
+                }
+                None => Err(self.error(format!(
+                    "unknown parameter \"{}\", declare it with `param {} = INDEX;`",
+                    name, name
+                ))),
+            },
+            ExprKind::Call { name, args } => {
+                let (arr, argument) = match INDEXED_INPUTS
+                    .iter()
+                    .find(|(input, _arr, _arg)| input == name)
+                {
+                    Some((_input, arr, argument)) => (*arr, *argument),
+                    None => return Err(self.error(format!("unknown input \"{}\"", name))),
+                };
+                let idx = match &args[..] {
+                    [idx] => idx,
+                    _ => {
+                        return Err(self.error(format!(
+                            "{} takes {}, not {} arguments",
+                            name,
+                            argument,
+                            args.len()
+                        )))
+                    }
+                };
+                let idx = self.expression(idx)?;
+                let reg = self.result_register(&[idx])?;
+                let arr_reg = self.constant(arr)?;
+                self.emit(Instruction::ArrGet {
+                    dst: reg,
+                    arr: arr_reg,
+                    idx: idx.reg,
+                });
+                self.free(arr_reg);
+                Ok(Value {
+                    reg,
+                    temporary: true,
+                })
+            }
+            ExprKind::Unary {
+                op: UnaryOp::Negate,
+                operand,
+            } => {
+                let operand = self.expression(operand)?;
+                let reg = self.result_register(&[operand])?;
+                let minus_one = self.constant(-1)?;
+                self.emit(Instruction::Mul {
+                    dst: reg,
+                    v0: minus_one,
+                    v1: operand.reg,
+                });
+                self.free(minus_one);
+                Ok(Value {
+                    reg,
+                    temporary: true,
+                })
+            }
+            ExprKind::Binary { op, left, right } if is_arithmetic(*op) => {
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                let reg = self.result_register(&[left, right])?;
+                let (v0, v1) = (left.reg, right.reg);
+                match op {
+                    BinaryOp::Add => self.emit(Instruction::Add { dst: reg, v0, v1 }),
+                    BinaryOp::Mul => self.emit(Instruction::Mul { dst: reg, v0, v1 }),
+                    BinaryOp::Div => self.emit(Instruction::Div { dst: reg, v0, v1 }),
+                    BinaryOp::Mod => self.emit(Instruction::Mod { dst: reg, v0, v1 }),
+                    BinaryOp::Sub => {
+                        // There is no subtraction, so add the negated right hand side
+                        let negated = self.constant(-1)?;
+                        self.emit(Instruction::Mul {
+                            dst: negated,
+                            v0: negated,
+                            v1,
+                        });
+                        self.emit(Instruction::Add {
+                            dst: reg,
+                            v0,
+                            v1: negated,
+                        });
+                        self.free(negated);
+                    }
+                    _ => unreachable!("not arithmetic"),
+                }
+                for operand in &[left, right] {
+                    if operand.temporary && operand.reg != reg {
+                        self.free(operand.reg);
+                    }
+                }
+                Ok(Value {
+                    reg,
+                    temporary: true,
+                })
+            }
+            // Comparisons and logic as values are 1 if true and 0 if false
+            ExprKind::Unary { .. } | ExprKind::Binary { .. } => {
+                let reg = self.constant(0)?;
+                let false_label = self.new_label();
+                self.branch(expr, false, false_label)?;
+                self.load_constant(reg, 1)?;
+                self.place_label(false_label);
+                Ok(Value {
+                    reg,
+                    temporary: true,
+                })
+            }
+        }
+    }
+
+    fn read_input(&mut self, arr: i64, idx: i64) -> Result<Value, Error> {
+        let reg = self.constant(arr)?;
+        let idx_reg = self.constant(idx)?;
+        self.emit(Instruction::ArrGet {
+            dst: reg,
+            arr: reg,
+            idx: idx_reg,
+        });
+        self.free(idx_reg);
+        Ok(Value {
+            reg,
+            temporary: true,
+        })
+    }
+
+    /// Jumps to `label` if `condition` is `when`, where any non-zero value is true
+    fn branch(&mut self, condition: &Expr, when: bool, label: usize) -> Result<(), Error> {
+        self.line = condition.line;
+        self.column = condition.column;
+        match &condition.kind {
+            ExprKind::Unary {
+                op: UnaryOp::Not,
+                operand,
+            } => self.branch(operand, !when, label),
+            // Jumping when both are true or either is false, and vice versa for `||`
+            ExprKind::Binary {
+                op: op @ BinaryOp::And,
+                left,
+                right,
+            }
+            | ExprKind::Binary {
+                op: op @ BinaryOp::Or,
+                left,
+                right,
+            } => {
+                let short_circuits_on = *op == BinaryOp::Or;
+                if when == short_circuits_on {
+                    self.branch(left, when, label)?;
+                    self.branch(right, when, label)
+                } else {
+                    let skip_label = self.new_label();
+                    self.branch(left, !when, skip_label)?;
+                    self.branch(right, when, label)?;
+                    self.place_label(skip_label);
+                    Ok(())
+                }
+            }
+            ExprKind::Binary { op, left, right } if !is_arithmetic(*op) => {
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                let op = if when { *op } else { negate_comparison(*op) };
+                self.conditional_jump(op, left.reg, right.reg, label)?;
+                self.release(right);
+                self.release(left);
+                Ok(())
+            }
+            _ => {
+                let value = self.expression(condition)?;
+                let zero = self.constant(0)?;
+                let op = if when {
+                    BinaryOp::NotEqual
+                } else {
+                    BinaryOp::Equal
+                };
+                self.conditional_jump(op, value.reg, zero, label)?;
+                self.free(zero);
+                self.release(value);
+                Ok(())
+            }
+        }
+    }
+
+    fn conditional_jump(
+        &mut self,
+        op: BinaryOp,
+        v0: RegIdx,
+        v1: RegIdx,
+        label: usize,
+    ) -> Result<(), Error> {
+        let adr = self.allocate()?;
+        self.emit_load_label(adr, label);
+        self.emit(match op {
+            BinaryOp::Equal => Instruction::Jeq { adr, v0, v1 },
+            BinaryOp::NotEqual => Instruction::Jne { adr, v0, v1 },
+            BinaryOp::Less => Instruction::Jlt { adr, v0, v1 },
+            BinaryOp::LessEqual => Instruction::Jle { adr, v0, v1 },
+            BinaryOp::Greater => Instruction::Jgt { adr, v0, v1 },
+            BinaryOp::GreaterEqual => Instruction::Jge { adr, v0, v1 },
+            _ => unreachable!("not a comparison"),
+        });
+        self.free(adr);
+        Ok(())
+    }
+
+    fn jump(&mut self, label: usize) -> Result<(), Error> {
+        let adr = self.allocate()?;
+        self.emit_load_label(adr, label);
+        self.emit(Instruction::Jmp { adr });
+        self.free(adr);
+        Ok(())
+    }
+
+    fn emit_load_label(&mut self, dst: RegIdx, label: usize) {
+        self.code.push(Emitted::LoadLabel { dst, label });
+    }
+
+    fn constant(&mut self, value: i64) -> Result<RegIdx, Error> {
+        let reg = self.allocate()?;
+        self.load_constant(reg, value)?;
+        Ok(reg)
+    }
+
+    /// `movimm` only takes 32 bits, so larger values are built up 16 bits at a time
+    fn load_constant(&mut self, dst: RegIdx, value: i64) -> Result<(), Error> {
+        if let Ok(imm) = i32::try_from(value) {
+            self.emit(Instruction::MovImm { dst, imm });
+            return Ok(());
+        }
+        let high = (value >> 32) as i32;
+        let low = value as u32;
+        let scratch = self.allocate()?;
+        self.emit(Instruction::MovImm { dst, imm: high });
+        for chunk in &[low >> 16, low & 0xffff] {
+            self.emit(Instruction::MovImm {
+                dst: scratch,
+                imm: 1 << 16,
+            });
+            self.emit(Instruction::Mul {
+                dst,
+                v0: dst,
+                v1: scratch,
+            });
+            self.emit(Instruction::MovImm {
+                dst: scratch,
+                imm: *chunk as i32,
+            });
+            self.emit(Instruction::Add {
+                dst,
+                v0: dst,
+                v1: scratch,
+            });
+        }
+        self.free(scratch);
+        Ok(())
+    }
+}
+
+fn is_arithmetic(op: BinaryOp) -> bool {
+    match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => true,
+        _ => false,
+    }
+}
+
+fn negate_comparison(op: BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::Equal => BinaryOp::NotEqual,
+        BinaryOp::NotEqual => BinaryOp::Equal,
+        BinaryOp::Less => BinaryOp::GreaterEqual,
+        BinaryOp::LessEqual => BinaryOp::Greater,
+        BinaryOp::Greater => BinaryOp::LessEqual,
+        BinaryOp::GreaterEqual => BinaryOp::Less,
+        _ => unreachable!("not a comparison"),
+    }
+}
@@ -0,0 +1,164 @@
+use super::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Number(i64),
+    Identifier(String),
+    // Keywords
+    Let,
+    Param,
+    If,
+    Else,
+    For,
+    In,
+    Halt,
+    // Punctuation
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Semicolon,
+    Dot,
+    DotDot,
+    Assign,
+    // Operators
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    Not,
+    End,
+}
+
+/// A token and the 1-based line and column it starts at
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spanned {
+    pub token: Token,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Splits `source` into tokens, ending with `Token::End`.  `#` starts a comment running to the
+/// end of the line.
+pub fn tokenize(source: &str) -> Result<Vec<Spanned>, Error> {
+    let mut tokens: Vec<Spanned> = Vec::default();
+    let chars: Vec<char> = source.chars().collect();
+    let (mut pos, mut line, mut column) = (0, 1, 1);
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        let (start_line, start_column) = (line, column);
+        let next = chars.get(pos + 1).copied();
+
+        if c == '\n' {
+            pos += 1;
+            line += 1;
+            column = 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            pos += 1;
+            column += 1;
+            continue;
+        }
+        if c == '#' {
+            while pos < chars.len() && chars[pos] != '\n' {
+                pos += 1;
+            }
+            continue;
+        }
+
+        let (token, len) = if c.is_ascii_digit() {
+            let len = chars[pos..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            let text: String = chars[pos..pos + len]
+                .iter()
+                .filter(|c| **c != '_')
+                .collect();
+            let value = text.parse::<i64>().map_err(|_| {
+                Error::new(
+                    start_line,
+                    start_column,
+                    format!("invalid number \"{}\"", text),
+                )
+            })?;
+            (Token::Number(value), len)
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = chars[pos..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            let word: String = chars[pos..pos + len].iter().collect();
+            let token = match &word[..] {
+                "let" => Token::Let,
+                "param" => Token::Param,
+                "if" => Token::If,
+                "else" => Token::Else,
+                "for" => Token::For,
+                "in" => Token::In,
+                "halt" => Token::Halt,
+                _ => Token::Identifier(word),
+            };
+            (token, len)
+        } else {
+            match (c, next) {
+                ('.', Some('.')) => (Token::DotDot, 2),
+                ('=', Some('=')) => (Token::Equal, 2),
+                ('!', Some('=')) => (Token::NotEqual, 2),
+                ('<', Some('=')) => (Token::LessEqual, 2),
+                ('>', Some('=')) => (Token::GreaterEqual, 2),
+                ('&', Some('&')) => (Token::And, 2),
+                ('|', Some('|')) => (Token::Or, 2),
+                ('(', _) => (Token::LeftParen, 1),
+                (')', _) => (Token::RightParen, 1),
+                ('{', _) => (Token::LeftBrace, 1),
+                ('}', _) => (Token::RightBrace, 1),
+                (',', _) => (Token::Comma, 1),
+                (';', _) => (Token::Semicolon, 1),
+                ('.', _) => (Token::Dot, 1),
+                ('=', _) => (Token::Assign, 1),
+                ('+', _) => (Token::Plus, 1),
+                ('-', _) => (Token::Minus, 1),
+                ('*', _) => (Token::Star, 1),
+                ('/', _) => (Token::Slash, 1),
+                ('%', _) => (Token::Percent, 1),
+                ('<', _) => (Token::Less, 1),
+                ('>', _) => (Token::Greater, 1),
+                ('!', _) => (Token::Not, 1),
+                _ => {
+                    return Err(Error::new(
+                        start_line,
+                        start_column,
+                        format!("unexpected character '{}'", c),
+                    ))
+                }
+            }
+        };
+        tokens.push(Spanned {
+            token,
+            line: start_line,
+            column: start_column,
+        });
+        pos += len;
+        column += len;
+    }
+
+    tokens.push(Spanned {
+        token: Token::End,
+        line,
+        column,
+    });
+    Ok(tokens)
+}
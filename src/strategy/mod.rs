@@ -0,0 +1,305 @@
+//! A small language for writing bidding programs, compiled to `vm::Program`s.  See
+//! `doc/strategy.md` for the language.
+//!
+//! ```text
+//! param edge = 0;
+//!
+//! cancel_bids();
+//! cancel_offers();
+//! if best_bid > 0 && best_offer > 0 {
+//!     let mid = (best_bid + best_offer) / 2;
+//!     bid(mid - param.edge, 10);
+//!     offer(mid + param.edge, 10);
+//! }
+//! ```
+
+mod codegen;
+mod lexer;
+mod parser;
+
+use crate::vm::Program;
+
+/// Where compilation failed, at a 1-based `line` and `column` of the source
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Error {
+    fn new(line: usize, column: usize, message: String) -> Self {
+        Self {
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for Error {}
+
+pub fn compile(source: &str) -> Result<Program, Error> {
+    let tokens = lexer::tokenize(source)?;
+    let statements = parser::parse(&tokens)?;
+    let instructions = codegen::generate(&statements)?;
+    Ok(Program::from_instructions(&instructions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{self, ExecutionState, ProgramInstance};
+
+    const GAS_LIMIT: u64 = 100_000;
+
+    /// Runs the compiled `source` with `inputs` as (array, index, value) until it halts
+    fn run(source: &str, inputs: &[(u64, u64, i64)]) -> ProgramInstance {
+        let program = compile(source).unwrap();
+        let mut state = ExecutionState::default();
+        for (arr, idx, val) in inputs {
+            state.array_insert(*arr, *idx, *val);
+        }
+        let mut instance = ProgramInstance::new(program, state);
+        instance.set_gas_limit(GAS_LIMIT);
+        while instance.execute_step().unwrap() {}
+        instance
+    }
+
+    fn bids(instance: &ProgramInstance) -> Vec<(u64, i64)> {
+        sorted_orders(instance, 9)
+    }
+
+    fn offers(instance: &ProgramInstance) -> Vec<(u64, i64)> {
+        sorted_orders(instance, 10)
+    }
+
+    fn sorted_orders(instance: &ProgramInstance, arr: u64) -> Vec<(u64, i64)> {
+        let mut orders: Vec<(u64, i64)> = instance.state().iter_touched_values(arr).collect();
+        orders.sort();
+        orders
+    }
+
+    /// The value of `expression`, bid at price 1
+    fn evaluate(expression: &str) -> i64 {
+        let instance = run(&format!("bid(1, {});", expression), &[]);
+        instance.state().array_read(9, 1)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9);
+        assert_eq!(evaluate("10 - 3 - 2"), 5);
+        assert_eq!(evaluate("-7 / 2"), -3);
+        assert_eq!(evaluate("-7 % 3"), -1);
+        assert_eq!(evaluate("-(2 - 5)"), 3);
+        assert_eq!(evaluate("5_000_000_000 * 2"), 10_000_000_000);
+        assert_eq!(evaluate("-9223372036854775807 - 1"), i64::MIN);
+        assert_eq!(evaluate("9223372036854775807"), i64::MAX);
+        assert_eq!(evaluate("-4294967297"), -4_294_967_297);
+    }
+
+    #[test]
+    fn comparisons_and_logic() {
+        assert_eq!(evaluate("1 < 2"), 1);
+        assert_eq!(evaluate("2 <= 1"), 0);
+        assert_eq!(evaluate("3 == 3 && 4 != 4"), 0);
+        assert_eq!(evaluate("3 == 3 || 4 != 4"), 1);
+        assert_eq!(evaluate("!(1 > 2) && !0"), 1);
+        assert_eq!(evaluate("(1 >= 1) + (2 > 1) + 7"), 9);
+        assert_eq!(evaluate("0 || 5"), 1);
+    }
+
+    #[test]
+    fn variables_and_control_flow() {
+        let source = "
+            let total = 0;
+            for i in 1..5 {
+                let square = i * i;
+                if i % 2 == 0 {
+                    total = total + square;
+                } else if i == 3 {
+                    total = total - 1;
+                } else {
+                    total = total + 100;
+                }
+            }
+            bid(1, total);
+            halt;
+            bid(2, 1);
+        ";
+        // 100 + 4 - 1 + 16
+        assert_eq!(bids(&run(source, &[])), vec![(1, 119)]);
+    }
+
+    #[test]
+    fn inputs_and_outputs() {
+        let source = "
+            param edge = 3;
+            param size = 0;
+            cancel_bids();
+            if best_bid > 0 && best_offer > 0 {
+                let mid = (best_bid + best_offer) / 2;
+                bid(mid - param.edge, param.size + bid_size(best_bid));
+                bid(mid - param.edge, 1);
+                offer(mid + param.edge, param.size - my_position);
+                offer(0, 5);
+            }
+        ";
+        let inputs = [
+            (0, 0, 10),
+            (0, 3, 2),
+            (1, 1, 100),
+            (2, 100, 7),
+            (3, 0, 110),
+            (11, 0, 4),
+        ];
+        let instance = run(source, &inputs);
+        assert_eq!(bids(&instance), vec![(0, 1), (103, 18)]);
+        assert_eq!(offers(&instance), vec![(107, 6)]);
+
+        // No book, so no orders
+        let instance = run(source, &[]);
+        assert_eq!(bids(&instance), vec![(0, 1)]);
+        assert_eq!(offers(&instance), vec![]);
+    }
+
+    #[test]
+    fn storage() {
+        let source = "
+            store(1, stored(1) + 5);
+            store(2, stored(3));
+            bid(1, stored(1));
+        ";
+        let instance = run(source, &[(12, 1, 10), (12, 3, 7)]);
+        let mut storage: Vec<(u64, i64)> = instance.state().iter_touched_values(12).collect();
+        storage.sort();
+        assert_eq!(storage, vec![(1, 15), (2, 7), (3, 7)]);
+        assert_eq!(bids(&instance), vec![(1, 15)]);
+    }
+
+    #[test]
+    fn loop_bounds_evaluated_once() {
+        let source = "
+            let n = 3;
+            for i in 0..n {
+                n = n + 1;
+                bid(10 + i, n);
+            }
+        ";
+        assert_eq!(bids(&run(source, &[])), vec![(10, 4), (11, 5), (12, 6)]);
+    }
+
+    #[test]
+    fn registers_reused() {
+        // Far more temporaries than registers over the program, but few at once
+        let mut source = String::default();
+        for i in 0..100 {
+            source.push_str(&format!("let v{} = {} * 2 + 1;\n", i % 10, i));
+        }
+        source.push_str("bid(1, v0 + v1 + v2 + v3 + v4 + v5 + v6 + v7 + v8 + v9);");
+        // The last ten: 2 * (90 + ... + 99) + 10
+        assert_eq!(bids(&run(&source, &[])), vec![(1, 1900)]);
+
+        let too_many: String = (0..16).map(|i| format!("let v{} = {};\n", i, i)).collect();
+        assert_eq!(
+            compile(&too_many).unwrap_err(),
+            Error::new(
+                16,
+                11,
+                "out of registers, at most 15 variables and intermediate values can be live at once"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn semantic_errors() {
+        let cases = [
+            ("bid(1, x);", 1, 8, "unknown variable or input \"x\""),
+            ("x = 1;", 1, 1, "unknown variable \"x\""),
+            (
+                "for i in 0..3 { i = 5; }",
+                1,
+                17,
+                "loop variable \"i\" can't be assigned",
+            ),
+            (
+                "let best_bid = 1;",
+                1,
+                1,
+                "\"best_bid\" is an input and can't be redefined",
+            ),
+            (
+                "bid(param.edge, 1);",
+                1,
+                5,
+                "unknown parameter \"edge\", declare it with `param edge = INDEX;`",
+            ),
+            (
+                "param a = 1; param a = 2;",
+                1,
+                14,
+                "parameter \"a\" is already defined",
+            ),
+            (
+                "bid(1);",
+                1,
+                1,
+                "bid takes a price and a quantity, not 1 arguments",
+            ),
+            (
+                "buy(1, 2);",
+                1,
+                1,
+                "unknown output \"buy\", expected bid, offer, cancel_bids, cancel_offers or store",
+            ),
+            ("bid(1, depth(3));", 1, 8, "unknown input \"depth\""),
+            (
+                "bid(1, bid_size());",
+                1,
+                8,
+                "bid_size takes a price, not 0 arguments",
+            ),
+            (
+                "bid(1, stored(1, 2));",
+                1,
+                8,
+                "stored takes a key, not 2 arguments",
+            ),
+            (
+                "store(1);",
+                1,
+                1,
+                "store takes a key and a value, not 1 arguments",
+            ),
+        ];
+        for (source, line, column, message) in cases.iter() {
+            assert_eq!(
+                compile(source),
+                Err(Error::new(*line, *column, message.to_string())),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn assembly_round_trips() {
+        let program = compile("for i in 0..3 { if i != 1 { bid(i + 1, 2); } }").unwrap();
+        assert_eq!(
+            vm::Program::try_from_str(&program.get_string()).unwrap(),
+            program
+        );
+        assert_eq!(
+            program.instructions().last(),
+            Some(&vm::Instruction::Halt {})
+        );
+    }
+}
@@ -0,0 +1,527 @@
+use super::lexer::{Spanned, Token};
+use super::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExprKind {
+    Number(i64),
+    /// A variable or one of the named book inputs
+    Name(String),
+    /// `param.NAME`
+    Param(String),
+    /// One of the book inputs taking a price, such as `bid_size(price)`
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StmtKind {
+    /// `param NAME = INDEX;` names one of the program's parameters
+    Param {
+        name: String,
+        idx: u64,
+    },
+    Let {
+        name: String,
+        value: Expr,
+    },
+    Assign {
+        name: String,
+        value: Expr,
+    },
+    If {
+        condition: Expr,
+        then_block: Vec<Stmt>,
+        else_block: Vec<Stmt>,
+    },
+    /// Runs `body` for each `variable` from `start` up to but excluding `end`, both evaluated once
+    For {
+        variable: String,
+        start: Expr,
+        end: Expr,
+        body: Vec<Stmt>,
+    },
+    /// One of the outputs, such as `bid(price, quantity)`
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Halt,
+}
+
+pub fn parse(tokens: &[Spanned]) -> Result<Vec<Stmt>, Error> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let mut statements: Vec<Stmt> = Vec::default();
+    while parser.peek() != &Token::End {
+        statements.push(parser.statement()?);
+    }
+    Ok(statements)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Spanned],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn current(&self) -> &Spanned {
+        &self.tokens[self.pos]
+    }
+
+    /// The last token is always `Token::End`, which is never consumed
+    fn advance(&mut self) -> &Spanned {
+        let spanned = &self.tokens[self.pos];
+        if spanned.token != Token::End {
+            self.pos += 1;
+        }
+        spanned
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == token {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), Error> {
+        if self.eat(&token) {
+            Ok(())
+        } else {
+            Err(self.unexpected(what))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        let current = self.current();
+        let found = match &current.token {
+            Token::End => "end of program".to_owned(),
+            Token::Number(n) => format!("{}", n),
+            Token::Identifier(name) => format!("\"{}\"", name),
+            token => format!("{:?}", token),
+        };
+        Error::new(
+            current.line,
+            current.column,
+            format!("expected {}, found {}", expected, found),
+        )
+    }
+
+    fn identifier(&mut self, what: &str) -> Result<String, Error> {
+        match self.peek().clone() {
+            Token::Identifier(name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, Error> {
+        let (line, column) = (self.current().line, self.current().column);
+        let kind = match self.peek().clone() {
+            Token::Param => {
+                self.advance();
+                let name = self.identifier("a parameter name")?;
+                self.expect(Token::Assign, "'='")?;
+                let idx = match self.peek() {
+                    Token::Number(idx) if *idx >= 0 => *idx as u64,
+                    _ => return Err(self.unexpected("a parameter index")),
+                };
+                self.advance();
+                self.expect(Token::Semicolon, "';'")?;
+                StmtKind::Param { name, idx }
+            }
+            Token::Let => {
+                self.advance();
+                let name = self.identifier("a variable name")?;
+                self.expect(Token::Assign, "'='")?;
+                let value = self.expression()?;
+                self.expect(Token::Semicolon, "';'")?;
+                StmtKind::Let { name, value }
+            }
+            Token::If => self.if_statement()?,
+            Token::For => {
+                self.advance();
+                let variable = self.identifier("a loop variable")?;
+                self.expect(Token::In, "'in'")?;
+                let start = self.expression()?;
+                self.expect(Token::DotDot, "'..'")?;
+                let end = self.expression()?;
+                let body = self.block()?;
+                StmtKind::For {
+                    variable,
+                    start,
+                    end,
+                    body,
+                }
+            }
+            Token::Halt => {
+                self.advance();
+                self.expect(Token::Semicolon, "';'")?;
+                StmtKind::Halt
+            }
+            Token::Identifier(name) => {
+                self.advance();
+                let kind = if self.eat(&Token::Assign) {
+                    StmtKind::Assign {
+                        name,
+                        value: self.expression()?,
+                    }
+                } else if self.peek() == &Token::LeftParen {
+                    StmtKind::Call {
+                        name,
+                        args: self.arguments()?,
+                    }
+                } else {
+                    return Err(self.unexpected("'=' or '('"));
+                };
+                self.expect(Token::Semicolon, "';'")?;
+                kind
+            }
+            _ => return Err(self.unexpected("a statement")),
+        };
+        Ok(Stmt { kind, line, column })
+    }
+
+    /// An `if` statement, including any `else if` chain
+    fn if_statement(&mut self) -> Result<StmtKind, Error> {
+        self.expect(Token::If, "'if'")?;
+        let condition = self.expression()?;
+        let then_block = self.block()?;
+        let else_block = if self.eat(&Token::Else) {
+            if self.peek() == &Token::If {
+                let (line, column) = (self.current().line, self.current().column);
+                vec![Stmt {
+                    kind: self.if_statement()?,
+                    line,
+                    column,
+                }]
+            } else {
+                self.block()?
+            }
+        } else {
+            Vec::default()
+        };
+        Ok(StmtKind::If {
+            condition,
+            then_block,
+            else_block,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, Error> {
+        self.expect(Token::LeftBrace, "'{'")?;
+        let mut statements: Vec<Stmt> = Vec::default();
+        while !self.eat(&Token::RightBrace) {
+            if self.peek() == &Token::End {
+                return Err(self.unexpected("'}'"));
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Expr>, Error> {
+        self.expect(Token::LeftParen, "'('")?;
+        let mut args: Vec<Expr> = Vec::default();
+        if self.eat(&Token::RightParen) {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression()?);
+            if self.eat(&Token::RightParen) {
+                return Ok(args);
+            }
+            self.expect(Token::Comma, "',' or ')'")?;
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, Error> {
+        self.binary(0)
+    }
+
+    /// Operators binding tighter at each level, all left-associative except comparisons, which
+    /// don't chain
+    fn binary(&mut self, level: usize) -> Result<Expr, Error> {
+        const LEVELS: &[&[(Token, BinaryOp)]] = &[
+            &[(Token::Or, BinaryOp::Or)],
+            &[(Token::And, BinaryOp::And)],
+            &[
+                (Token::Equal, BinaryOp::Equal),
+                (Token::NotEqual, BinaryOp::NotEqual),
+                (Token::Less, BinaryOp::Less),
+                (Token::LessEqual, BinaryOp::LessEqual),
+                (Token::Greater, BinaryOp::Greater),
+                (Token::GreaterEqual, BinaryOp::GreaterEqual),
+            ],
+            &[(Token::Plus, BinaryOp::Add), (Token::Minus, BinaryOp::Sub)],
+            &[
+                (Token::Star, BinaryOp::Mul),
+                (Token::Slash, BinaryOp::Div),
+                (Token::Percent, BinaryOp::Mod),
+            ],
+        ];
+        const COMPARISON_LEVEL: usize = 2;
+
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = LEVELS[level]
+                .iter()
+                .find(|(token, _op)| token == self.peek())
+                .map(|(_token, op)| *op);
+            let op = match op {
+                Some(op) => op,
+                None => return Ok(left),
+            };
+            let (line, column) = (self.current().line, self.current().column);
+            self.advance();
+            let right = self.binary(level + 1)?;
+            left = Expr {
+                kind: ExprKind::Binary {
+                    op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                line,
+                column,
+            };
+            if level == COMPARISON_LEVEL
+                && LEVELS[level]
+                    .iter()
+                    .any(|(token, _op)| token == self.peek())
+            {
+                return Err(self.unexpected("comparisons to be combined with && or ||"));
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        let (line, column) = (self.current().line, self.current().column);
+        let op = match self.peek() {
+            Token::Minus => UnaryOp::Negate,
+            Token::Not => UnaryOp::Not,
+            _ => return self.primary(),
+        };
+        self.advance();
+        let operand = self.unary()?;
+        let kind = match (op, operand.kind) {
+            // Folded, so negative constants cost no more than positive ones
+            (UnaryOp::Negate, ExprKind::Number(n)) => ExprKind::Number(-n),
+            (op, kind) => ExprKind::Unary {
+                op,
+                operand: Box::new(Expr { kind, ..operand }),
+            },
+        };
+        Ok(Expr { kind, line, column })
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        let (line, column) = (self.current().line, self.current().column);
+        let kind = match self.peek().clone() {
+            Token::Number(n) => {
+                self.advance();
+                ExprKind::Number(n)
+            }
+            Token::Param => {
+                self.advance();
+                self.expect(Token::Dot, "'.'")?;
+                ExprKind::Param(self.identifier("a parameter name")?)
+            }
+            Token::Identifier(name) => {
+                self.advance();
+                if self.peek() == &Token::LeftParen {
+                    ExprKind::Call {
+                        name,
+                        args: self.arguments()?,
+                    }
+                } else {
+                    ExprKind::Name(name)
+                }
+            }
+            Token::LeftParen => {
+                self.advance();
+                let expr = self.expression()?;
+                self.expect(Token::RightParen, "')'")?;
+                return Ok(expr);
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Expr { kind, line, column })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lexer::tokenize;
+    use super::*;
+
+    fn parse_source(source: &str) -> Result<Vec<Stmt>, Error> {
+        parse(&tokenize(source)?)
+    }
+
+    fn number(n: i64, line: usize, column: usize) -> Expr {
+        Expr {
+            kind: ExprKind::Number(n),
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let statements = parse_source("let x = 1 + 2 * -3;").unwrap();
+        assert_eq!(
+            statements,
+            vec![Stmt {
+                kind: StmtKind::Let {
+                    name: "x".to_owned(),
+                    value: Expr {
+                        kind: ExprKind::Binary {
+                            op: BinaryOp::Add,
+                            left: Box::new(number(1, 1, 9)),
+                            right: Box::new(Expr {
+                                kind: ExprKind::Binary {
+                                    op: BinaryOp::Mul,
+                                    left: Box::new(number(2, 1, 13)),
+                                    right: Box::new(number(-3, 1, 17)),
+                                },
+                                line: 1,
+                                column: 15,
+                            }),
+                        },
+                        line: 1,
+                        column: 11,
+                    },
+                },
+                line: 1,
+                column: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn statements() {
+        let source = "
+            param edge = 2;
+            # comment
+            if best_bid > 0 && !(x == 1) {
+                bid(best_bid - param.edge, 10);
+            } else if y { halt; } else { x = 3; }
+            for i in 0..3 { offer(my_bid_size(i), 1_000); }
+        ";
+        let statements = parse_source(source).unwrap();
+        assert_eq!(statements.len(), 3);
+        match &statements[1].kind {
+            StmtKind::If { else_block, .. } => match &else_block[0].kind {
+                StmtKind::If { else_block, .. } => assert_eq!(
+                    else_block[0].kind,
+                    StmtKind::Assign {
+                        name: "x".to_owned(),
+                        value: number(3, 6, 46)
+                    }
+                ),
+                kind => panic!("{:?}", kind),
+            },
+            kind => panic!("{:?}", kind),
+        }
+        assert_eq!((statements[2].line, statements[2].column), (7, 13));
+    }
+
+    #[test]
+    fn errors_located() {
+        let cases = [
+            ("let x = ;", 1, 9, "expected an expression, found Semicolon"),
+            ("let x = 1\nbid(1, 2);", 2, 1, "expected ';', found \"bid\""),
+            (
+                "if x { bid(1, 2);",
+                1,
+                18,
+                "expected '}', found end of program",
+            ),
+            (
+                "let x = 1 < 2 < 3;",
+                1,
+                15,
+                "expected comparisons to be combined with && or ||, found Less",
+            ),
+            ("let x = 1 @ 2;", 1, 11, "unexpected character '@'"),
+            (
+                "let x = 99999999999999999999;",
+                1,
+                9,
+                "invalid number \"99999999999999999999\"",
+            ),
+            (
+                "param p = -1;",
+                1,
+                11,
+                "expected a parameter index, found Minus",
+            ),
+        ];
+        for (source, line, column, message) in cases.iter() {
+            assert_eq!(
+                parse_source(source),
+                Err(Error::new(*line, *column, message.to_string())),
+                "{}",
+                source
+            );
+        }
+    }
+}
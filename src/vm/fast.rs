@@ -0,0 +1,663 @@
+//! Runs a `ProgramInstance` to completion faster than stepping it.  The program is decoded once
+//! into flat operations, with registers checked and gas costs looked up, and executed in a single
+//! loop with the registers held locally.  Read-only arrays, which hold the book and the other
+//! inputs, are copied into dense vectors so reading them needs no hashing.
+
+use super::{
+    Error, Instruction, Program, ProgramInstance, RegIdx, MAX_CALL_DEPTH, MAX_STACK_SIZE,
+    NUM_REGISTERS, RP_IDX,
+};
+
+/// Decoding checks registers are below `NUM_REGISTERS`, so masking with this changes nothing but
+/// lets the compiler drop bounds checks
+const REGISTER_MASK: usize = NUM_REGISTERS - 1;
+const PC: usize = RP_IDX.0 as usize;
+/// Arrays numbered below this have their write gas and dense copies looked up by index
+const TABLE_ARRAYS: usize = 16;
+/// A read-only array is copied densely unless that takes more than this many slots per value
+const MAX_SLOTS_PER_VALUE: u64 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Code {
+    ArrIns,
+    ArrGet,
+    MovImm,
+    Mov,
+    Jmp,
+    Jeq,
+    Jne,
+    Jgt,
+    Jge,
+    Jlt,
+    Jle,
+    Add,
+    Mul,
+    Div,
+    Mod,
+    Noop,
+    Halt,
+    Call,
+    Ret,
+    Push,
+    Pop,
+}
+
+/// A decoded instruction, with its registers `a`, `b` and `c` in the order it's written in
+/// assembly
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Op {
+    code: Code,
+    a: u8,
+    b: u8,
+    c: u8,
+    imm: i32,
+    gas: u64,
+}
+
+impl Op {
+    fn new(code: Code, registers: &[RegIdx], imm: i32, gas: u64) -> Option<Self> {
+        let mut indexes = [0; 3];
+        for (index, register) in indexes.iter_mut().zip(registers) {
+            if register.0 as usize >= NUM_REGISTERS {
+                return None;
+            }
+            *index = register.0;
+        }
+        Some(Self {
+            code,
+            a: indexes[0],
+            b: indexes[1],
+            c: indexes[2],
+            imm,
+            gas,
+        })
+    }
+}
+
+/// `None` if an instruction names a register which doesn't exist, which only instructions built
+/// by hand rather than parsed can do
+fn decode(program: &Program) -> Option<Vec<Op>> {
+    program
+        .instructions
+        .iter()
+        .map(|instruction| {
+            let gas = instruction.gas_cost();
+            match *instruction {
+                Instruction::ArrIns { val, arr, idx } => {
+                    Op::new(Code::ArrIns, &[val, arr, idx], 0, gas)
+                }
+                Instruction::ArrGet { dst, arr, idx } => {
+                    Op::new(Code::ArrGet, &[dst, arr, idx], 0, gas)
+                }
+                Instruction::MovImm { dst, imm } => Op::new(Code::MovImm, &[dst], imm, gas),
+                Instruction::Mov { dst, src } => Op::new(Code::Mov, &[dst, src], 0, gas),
+                Instruction::Jmp { adr } => Op::new(Code::Jmp, &[adr], 0, gas),
+                Instruction::Jeq { adr, v0, v1 } => Op::new(Code::Jeq, &[adr, v0, v1], 0, gas),
+                Instruction::Jne { adr, v0, v1 } => Op::new(Code::Jne, &[adr, v0, v1], 0, gas),
+                Instruction::Jgt { adr, v0, v1 } => Op::new(Code::Jgt, &[adr, v0, v1], 0, gas),
+                Instruction::Jge { adr, v0, v1 } => Op::new(Code::Jge, &[adr, v0, v1], 0, gas),
+                Instruction::Jlt { adr, v0, v1 } => Op::new(Code::Jlt, &[adr, v0, v1], 0, gas),
+                Instruction::Jle { adr, v0, v1 } => Op::new(Code::Jle, &[adr, v0, v1], 0, gas),
+                Instruction::Add { dst, v0, v1 } => Op::new(Code::Add, &[dst, v0, v1], 0, gas),
+                Instruction::Mul { dst, v0, v1 } => Op::new(Code::Mul, &[dst, v0, v1], 0, gas),
+                Instruction::Div { dst, v0, v1 } => Op::new(Code::Div, &[dst, v0, v1], 0, gas),
+                Instruction::Mod { dst, v0, v1 } => Op::new(Code::Mod, &[dst, v0, v1], 0, gas),
+                Instruction::Noop {} => Op::new(Code::Noop, &[], 0, gas),
+                Instruction::Halt {} => Op::new(Code::Halt, &[], 0, gas),
+                Instruction::Call { adr } => Op::new(Code::Call, &[adr], 0, gas),
+                Instruction::Ret {} => Op::new(Code::Ret, &[], 0, gas),
+                Instruction::Push { src } => Op::new(Code::Push, &[src], 0, gas),
+                Instruction::Pop { dst } => Op::new(Code::Pop, &[dst], 0, gas),
+            }
+        })
+        .collect()
+}
+
+/// The values of a read-only array from index `first` on, with 0 for those never written
+struct DenseArray {
+    first: u64,
+    values: Vec<i64>,
+}
+
+impl DenseArray {
+    fn get(&self, idx: u64) -> i64 {
+        self.values
+            .get(idx.wrapping_sub(self.first) as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl ProgramInstance {
+    /// Executes until the program halts, as calling `execute_step` until it returns `Ok(false)`
+    /// would, with the same gas, limits and errors, but faster.  Running off the end of the
+    /// program is an `Error::ExecutionError`.  Falls back to stepping when tracing is enabled.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let ops = match decode(&self.program) {
+            Some(ops) if self.trace.is_none() => ops,
+            _ => {
+                while self.execute_step()? {}
+                return Ok(());
+            }
+        };
+        let dense_arrays = self.dense_arrays();
+        let mut write_gas = [0; TABLE_ARRAYS];
+        for (arr, gas) in &self.array_write_gas {
+            if let Some(slot) = write_gas.get_mut(*arr as usize) {
+                *slot = *gas;
+            }
+        }
+        let mut regs = [0; NUM_REGISTERS];
+        for (reg, register) in regs.iter_mut().zip(self.state.registers.iter()) {
+            *reg = register.0;
+        }
+        let mut gas_used = self.gas_used;
+        let mut steps_executed = 0;
+
+        let result = loop {
+            let op = match ops.get(regs[PC] as usize) {
+                Some(op) => *op,
+                None => break Err(Error::ExecutionError),
+            };
+            let a = op.a as usize & REGISTER_MASK;
+            let b = op.b as usize & REGISTER_MASK;
+            let c = op.c as usize & REGISTER_MASK;
+            macro_rules! charge {
+                ($gas:expr) => {{
+                    let total = gas_used + $gas;
+                    if total > self.gas_limit {
+                        break Err(Error::OutOfGas);
+                    }
+                    gas_used = total;
+                    steps_executed += 1;
+                }};
+            }
+            macro_rules! jump_if {
+                ($condition:expr) => {{
+                    charge!(op.gas);
+                    if $condition {
+                        regs[PC] = regs[a];
+                    } else {
+                        regs[PC] += 1;
+                    }
+                }};
+            }
+            match op.code {
+                Code::ArrIns => {
+                    let (arr, idx) = (regs[b] as u64, regs[c] as u64);
+                    if let Err(e) = self.check_write(arr, idx) {
+                        break Err(e);
+                    }
+                    let extra = match write_gas.get(arr as usize) {
+                        Some(gas) => *gas,
+                        None => self.array_write_gas.get(&arr).copied().unwrap_or(0),
+                    };
+                    charge!(op.gas + extra);
+                    self.state.array_insert(arr, idx, regs[a]);
+                    regs[PC] += 1;
+                }
+                Code::ArrGet => {
+                    charge!(op.gas);
+                    let (arr, idx) = (regs[b] as u64, regs[c] as u64);
+                    regs[a] = match dense_arrays.get(arr as usize) {
+                        Some(Some(array)) => array.get(idx),
+                        _ => self.state.array_read(arr, idx),
+                    };
+                    regs[PC] += 1;
+                }
+                Code::MovImm => {
+                    charge!(op.gas);
+                    regs[a] = op.imm as i64;
+                    regs[PC] += 1;
+                }
+                Code::Mov => {
+                    charge!(op.gas);
+                    regs[a] = regs[b];
+                    regs[PC] += 1;
+                }
+                Code::Jmp => {
+                    charge!(op.gas);
+                    regs[PC] = regs[a];
+                }
+                Code::Jeq => jump_if!(regs[b] == regs[c]),
+                Code::Jne => jump_if!(regs[b] != regs[c]),
+                Code::Jgt => jump_if!(regs[b] > regs[c]),
+                Code::Jge => jump_if!(regs[b] >= regs[c]),
+                Code::Jlt => jump_if!(regs[b] < regs[c]),
+                Code::Jle => jump_if!(regs[b] <= regs[c]),
+                Code::Add => {
+                    charge!(op.gas);
+                    regs[a] = regs[b].wrapping_add(regs[c]);
+                    regs[PC] += 1;
+                }
+                Code::Mul => {
+                    charge!(op.gas);
+                    regs[a] = regs[b].wrapping_mul(regs[c]);
+                    regs[PC] += 1;
+                }
+                Code::Div => {
+                    if regs[c] == 0 {
+                        break Err(Error::DivideByZero);
+                    }
+                    charge!(op.gas);
+                    regs[a] = regs[b].wrapping_div(regs[c]);
+                    regs[PC] += 1;
+                }
+                Code::Mod => {
+                    if regs[c] == 0 {
+                        break Err(Error::DivideByZero);
+                    }
+                    charge!(op.gas);
+                    regs[a] = regs[b].wrapping_rem(regs[c]);
+                    regs[PC] += 1;
+                }
+                Code::Noop => {
+                    charge!(op.gas);
+                    regs[PC] += 1;
+                }
+                Code::Halt => {
+                    charge!(op.gas);
+                    regs[PC] += 1;
+                    break Ok(());
+                }
+                Code::Call => {
+                    if self.state.call_stack.len() >= MAX_CALL_DEPTH {
+                        break Err(Error::StackOverflow);
+                    }
+                    charge!(op.gas);
+                    self.state.call_stack.push(regs[PC] + 1);
+                    regs[PC] = regs[a];
+                }
+                Code::Ret => {
+                    charge!(op.gas);
+                    match self.state.call_stack.pop() {
+                        Some(return_address) => regs[PC] = return_address,
+                        None => {
+                            regs[PC] += 1;
+                            break Ok(());
+                        }
+                    }
+                }
+                Code::Push => {
+                    if self.state.stack.len() >= MAX_STACK_SIZE {
+                        break Err(Error::StackOverflow);
+                    }
+                    charge!(op.gas);
+                    self.state.stack.push(regs[a]);
+                    regs[PC] += 1;
+                }
+                Code::Pop => {
+                    let val = match self.state.stack.last() {
+                        Some(val) => *val,
+                        None => break Err(Error::StackUnderflow),
+                    };
+                    charge!(op.gas);
+                    self.state.stack.pop();
+                    regs[a] = val;
+                    regs[PC] += 1;
+                }
+            }
+        };
+
+        for (register, reg) in self.state.registers.iter_mut().zip(regs.iter()) {
+            register.0 = *reg;
+        }
+        self.gas_used = gas_used;
+        self.steps_executed += steps_executed;
+        result
+    }
+
+    /// Dense copies of the read-only arrays numbered below `TABLE_ARRAYS`, indexed by array,
+    /// where they're not too sparse.  Writes to them fail, so the copies stay correct.
+    fn dense_arrays(&self) -> Vec<Option<DenseArray>> {
+        (0..TABLE_ARRAYS as u64)
+            .map(|arr| {
+                if !self.read_only_arrays.contains(&arr) {
+                    return None;
+                }
+                let array = self.state.arrays.get(&arr)?;
+                let first = *array.0.keys().min()?;
+                let last = *array.0.keys().max()?;
+                let len = array.0.len() as u64;
+                if last - first >= len.saturating_mul(MAX_SLOTS_PER_VALUE) {
+                    return None;
+                }
+                let mut values = vec![0; (last - first + 1) as usize];
+                for (idx, val) in &array.0 {
+                    values[(idx - first) as usize] = *val;
+                }
+                Some(DenseArray { first, values })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{ExecutionState, MemoryLimits};
+
+    /// Small, deterministic pseudo-random numbers, so failures can be reproduced
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn register(&mut self) -> RegIdx {
+            // Mostly the first few, so values are reused, and sometimes the program counter
+            match self.below(8) {
+                7 => RP_IDX,
+                _ => RegIdx(self.below(6) as u8),
+            }
+        }
+    }
+
+    /// A program over the whole instruction set, with small immediates so that its values often
+    /// coincide
+    fn random_program(rng: &mut XorShift, len: usize) -> Program {
+        let instructions: Vec<Instruction> = (0..len)
+            .map(|_| {
+                let (r0, r1, r2) = (rng.register(), rng.register(), rng.register());
+                match rng.below(19) {
+                    0 | 1 | 2 => Instruction::MovImm {
+                        dst: RegIdx(rng.below(6) as u8),
+                        imm: rng.below(len as u64 + 4) as i32 - 2,
+                    },
+                    3 => Instruction::Mov { dst: r0, src: r1 },
+                    4 => Instruction::ArrIns {
+                        val: r0,
+                        arr: r1,
+                        idx: r2,
+                    },
+                    5 | 6 => Instruction::ArrGet {
+                        dst: r0,
+                        arr: r1,
+                        idx: r2,
+                    },
+                    7 => Instruction::Add {
+                        dst: r0,
+                        v0: r1,
+                        v1: r2,
+                    },
+                    8 => Instruction::Jmp { adr: r0 },
+                    9 => Instruction::Jlt {
+                        adr: r0,
+                        v0: r1,
+                        v1: r2,
+                    },
+                    10 => Instruction::Jeq {
+                        adr: r0,
+                        v0: r1,
+                        v1: r2,
+                    },
+                    11 => Instruction::Call { adr: r0 },
+                    12 => Instruction::Ret {},
+                    13 => Instruction::Push { src: r0 },
+                    14 => Instruction::Pop { dst: r0 },
+                    15 => Instruction::Mul {
+                        dst: r0,
+                        v0: r1,
+                        v1: r2,
+                    },
+                    16 => Instruction::Div {
+                        dst: r0,
+                        v0: r1,
+                        v1: r2,
+                    },
+                    17 => Instruction::Mod {
+                        dst: r0,
+                        v0: r1,
+                        v1: r2,
+                    },
+                    _ => match rng.below(4) {
+                        0 => Instruction::Halt {},
+                        1 => Instruction::Noop {},
+                        2 => Instruction::Jge {
+                            adr: r0,
+                            v0: r1,
+                            v1: r2,
+                        },
+                        _ => Instruction::Jne {
+                            adr: r0,
+                            v0: r1,
+                            v1: r2,
+                        },
+                    },
+                }
+            })
+            .collect();
+        Program::from_instructions(&instructions)
+    }
+
+    /// An instance with dense and sparse read-only inputs, and a writable array already written
+    fn instance(program: &Program, gas_limit: u64, memory_limits: MemoryLimits) -> ProgramInstance {
+        let mut state = ExecutionState::default();
+        for idx in 0..5 {
+            state.array_insert(0, idx, idx as i64 + 1);
+            state.array_insert(1, 3 + 2 * idx, -(idx as i64));
+        }
+        state.array_insert(3, 1, 7);
+        state.array_insert(3, 1_000, 8);
+        state.array_insert(2, 1, 9);
+        let mut instance = ProgramInstance::new(program.clone(), state);
+        instance.set_gas_limit(gas_limit);
+        instance.set_memory_limits(memory_limits);
+        instance.set_array_write_gas(2, 3);
+        for arr in 0..=1 {
+            instance.set_read_only(arr);
+        }
+        instance.set_read_only(3);
+        instance
+    }
+
+    fn stepped(mut instance: ProgramInstance) -> (Result<(), Error>, ProgramInstance) {
+        let result = loop {
+            match instance.execute_step() {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        (result, instance)
+    }
+
+    /// Panics unless the instances finished in the same state
+    fn assert_same(run: &ProgramInstance, stepped: &ProgramInstance, context: &str) {
+        assert_eq!(run.gas_used(), stepped.gas_used(), "{}", context);
+        assert_eq!(
+            run.steps_executed(),
+            stepped.steps_executed(),
+            "{}",
+            context
+        );
+        for idx in 0..NUM_REGISTERS as u8 {
+            assert_eq!(
+                run.state().register_read(RegIdx(idx)),
+                stepped.state().register_read(RegIdx(idx)),
+                "r{} {}",
+                idx,
+                context
+            );
+        }
+        assert_eq!(run.state().stack(), stepped.state().stack(), "{}", context);
+        assert_eq!(
+            run.state().call_stack(),
+            stepped.state().call_stack(),
+            "{}",
+            context
+        );
+        let arrays = run.state().touched_arrays();
+        assert_eq!(arrays, stepped.state().touched_arrays(), "{}", context);
+        for arr in arrays {
+            let mut run_values: Vec<(u64, i64)> = run.state().iter_touched_values(arr).collect();
+            let mut stepped_values: Vec<(u64, i64)> =
+                stepped.state().iter_touched_values(arr).collect();
+            run_values.sort();
+            stepped_values.sort();
+            assert_eq!(run_values, stepped_values, "arr{} {}", arr, context);
+        }
+    }
+
+    #[test]
+    fn decoded() {
+        let program = Program::try_from_str(
+            "movimm r1 -3
+            arrins r2 r3 r4
+            jle r5 r6 r7
+            pop r15
+            halt",
+        )
+        .unwrap();
+        assert_eq!(
+            decode(&program).unwrap(),
+            vec![
+                Op::new(Code::MovImm, &[RegIdx(1)], -3, 1).unwrap(),
+                Op::new(Code::ArrIns, &[RegIdx(2), RegIdx(3), RegIdx(4)], 0, 2).unwrap(),
+                Op::new(Code::Jle, &[RegIdx(5), RegIdx(6), RegIdx(7)], 0, 1).unwrap(),
+                Op::new(Code::Pop, &[RegIdx(15)], 0, 1).unwrap(),
+                Op::new(Code::Halt, &[], 0, 0).unwrap(),
+            ]
+        );
+
+        // Only possible by hand, and left to the stepping interpreter
+        let invalid = Program::from_instructions(&[Instruction::Mov {
+            dst: RegIdx(0),
+            src: RegIdx(16),
+        }]);
+        assert_eq!(decode(&invalid), None);
+    }
+
+    #[test]
+    fn dense_arrays_only_for_compact_read_only_inputs() {
+        let instance = instance(&Program::from_instructions(&[]), 0, MemoryLimits::default());
+        let dense = instance.dense_arrays();
+        let array = dense[0].as_ref().unwrap();
+        assert_eq!((array.first, array.values.len()), (0, 5));
+        assert_eq!((array.get(4), array.get(5), array.get(u64::MAX)), (5, 0, 0));
+        let array = dense[1].as_ref().unwrap();
+        assert_eq!((array.first, array.values.len()), (3, 9));
+        assert_eq!((array.get(2), array.get(5), array.get(6)), (0, -1, 0));
+        assert!(dense[2].is_none(), "writable");
+        assert!(dense[3].is_none(), "sparse");
+        assert!(dense[4].is_none(), "empty");
+    }
+
+    #[test]
+    fn run_matches_stepping_random_programs() {
+        const GAS_LIMIT: u64 = 50;
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        let mut outcomes = std::collections::HashMap::new();
+        for iteration in 0..5_000 {
+            let len = 1 + rng.below(24) as usize;
+            let program = random_program(&mut rng, len);
+            let memory_limits = match iteration % 3 {
+                0 => MemoryLimits::default(),
+                _ => MemoryLimits {
+                    max_arrays: rng.below(3),
+                    max_array_entries: rng.below(4),
+                },
+            };
+            let mut run = instance(&program, GAS_LIMIT, memory_limits);
+            let run_result = run.run();
+            let (step_result, stepped) = stepped(instance(&program, GAS_LIMIT, memory_limits));
+            let context = format!("iteration {}\n{}", iteration, program.get_string());
+            assert_eq!(run_result, step_result, "{}", context);
+            assert_same(&run, &stepped, &context);
+            *outcomes.entry(format!("{:?}", run_result)).or_insert(0) += 1;
+        }
+        // Every way a program can end is covered
+        for outcome in &[
+            "Ok(())",
+            "Err(ExecutionError)",
+            "Err(OutOfGas)",
+            "Err(MemoryLimit)",
+            "Err(ReadOnlyArray)",
+            "Err(StackUnderflow)",
+            "Err(DivideByZero)",
+        ] {
+            assert!(
+                outcomes.get(*outcome).copied().unwrap_or(0) > 10,
+                "{} {:?}",
+                outcome,
+                outcomes
+            );
+        }
+    }
+
+    #[test]
+    fn run_matches_stepping_edge_cases() {
+        let cases = [
+            // Negative operands
+            "movimm r0 -7\nmovimm r1 2\nmul r2 r0 r1\ndiv r3 r0 r1\nmod r4 r0 r1\nhalt",
+            // Overflow wraps, including i64::MIN / -1
+            concat!(
+                "movimm r0 -2147483648\nmul r1 r0 r0\nmul r2 r1 r1\nmovimm r3 -2\nmul r4 r1 r3\n",
+                "add r5 r4 r4\nmovimm r6 -1\ndiv r7 r4 r6\nmod r8 r4 r6\nhalt"
+            ),
+            // Dividing by zero
+            "movimm r0 1\nmovimm r1 0\ndiv r2 r0 r1",
+            "movimm r0 1\nmovimm r1 0\nmod r2 r0 r1",
+            // Writing the program counter jumps
+            "movimm r15 2\nhalt\nmovimm r0 1\nhalt",
+            // Deep recursion
+            "movimm r0 0\ncall r0",
+            // Pushing forever
+            "movimm r0 1\npush r0\njmp r0",
+            // Writing an input
+            "movimm r0 0\narrins r0 r0 r0",
+            // Negative addresses
+            "movimm r0 -1\njmp r0",
+            // Returns from a procedure then ends the program
+            "movimm r0 3\ncall r0\nret\nmovimm r1 5\nret",
+        ];
+        for (program, gas_limit) in cases.iter().flat_map(|case| {
+            let program = Program::try_from_str(case).unwrap();
+            vec![(program.clone(), 7), (program, 10_000)]
+        }) {
+            let mut run = instance(&program, gas_limit, MemoryLimits::default());
+            let run_result = run.run();
+            let (step_result, stepped) =
+                stepped(instance(&program, gas_limit, MemoryLimits::default()));
+            let context = format!("gas limit {}\n{}", gas_limit, program.get_string());
+            assert_eq!(run_result, step_result, "{}", context);
+            assert_same(&run, &stepped, &context);
+        }
+    }
+
+    #[test]
+    fn divide_by_zero_faults() {
+        for operation in &["div", "mod"] {
+            let program = Program::try_from_str(&format!(
+                "movimm r0 1\nmovimm r1 0\nmovimm r2 5\n{} r2 r0 r1\nhalt",
+                operation
+            ))
+            .unwrap();
+            let mut run = instance(&program, 100, MemoryLimits::default());
+            assert_eq!(run.run(), Err(Error::DivideByZero));
+            assert_eq!(run.state().register_read(RegIdx(2)), 5);
+            assert_eq!(run.state().register_read(RP_IDX), 3);
+            let (step_result, _stepped) = stepped(instance(&program, 100, MemoryLimits::default()));
+            assert_eq!(step_result, Err(Error::DivideByZero));
+        }
+    }
+
+    #[test]
+    fn run_steps_when_tracing() {
+        let program =
+            Program::try_from_str("movimm r0 4\nmovimm r1 1\nadd r0 r0 r1\nhalt").unwrap();
+        let mut traced = instance(&program, 100, MemoryLimits::default());
+        traced.enable_tracing();
+        traced.run().unwrap();
+        assert_eq!(traced.trace().len(), 4);
+        assert_eq!(traced.state().register_read(RegIdx(0)), 5);
+    }
+}
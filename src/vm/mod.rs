@@ -1,32 +1,258 @@
+mod fast;
+pub mod trace;
+
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use trace::{ArrayAccess, RegisterWrite, TraceStep};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     ParseError,
     ExecutionError,
+    OutOfGas,
+    /// A write would have used more arrays or entries than the program's `MemoryLimits` allow
+    MemoryLimit,
+    ReadOnlyArray,
+    /// A `call` or `push` beyond `MAX_CALL_DEPTH` or `MAX_STACK_SIZE`
+    StackOverflow,
+    /// A `pop` from an empty stack
+    StackUnderflow,
+    /// A `div` or `mod` by zero
+    DivideByZero,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
+impl std::error::Error for Error {}
+
 const RP_IDX: RegIdx = RegIdx(15);
+const NUM_REGISTERS: usize = 16;
+/// Calls which may be in progress at once
+pub const MAX_CALL_DEPTH: usize = 64;
+/// Values which may be pushed and not yet popped
+pub const MAX_STACK_SIZE: usize = 256;
 
 pub struct ProgramInstance {
     program: Program,
     state: ExecutionState,
+    gas_used: u64,
+    gas_limit: u64,
+    /// Charged for each `arrins` into an array, on top of the instruction's own cost
+    array_write_gas: HashMap<u64, u64>,
+    memory_limits: MemoryLimits,
+    /// Inputs the program may read but not write
+    read_only_arrays: HashSet<u64>,
+    steps_executed: u64,
+    /// Only recorded once `enable_tracing` is called
+    trace: Option<Vec<TraceStep>>,
 }
 
 impl ProgramInstance {
     pub fn new(program: Program, state: ExecutionState) -> Self {
-        Self { program, state }
+        Self {
+            program,
+            state,
+            gas_used: 0,
+            gas_limit: u64::MAX,
+            array_write_gas: HashMap::default(),
+            memory_limits: MemoryLimits::default(),
+            read_only_arrays: HashSet::default(),
+            steps_executed: 0,
+            trace: None,
+        }
+    }
+
+    pub fn set_gas_limit(&mut self, gas_limit: u64) {
+        self.gas_limit = gas_limit;
+    }
+
+    /// Makes each write to `arr` cost `gas` more than other writes
+    pub fn set_array_write_gas(&mut self, arr: u64, gas: u64) {
+        self.array_write_gas.insert(arr, gas);
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    pub fn set_memory_limits(&mut self, memory_limits: MemoryLimits) {
+        self.memory_limits = memory_limits;
+    }
+
+    /// Makes `arrins` into `arr` fail with `Error::ReadOnlyArray`.  Read-only arrays don't count
+    /// towards the memory limits.
+    pub fn set_read_only(&mut self, arr: u64) {
+        self.read_only_arrays.insert(arr);
     }
 
+    /// Records a `TraceStep` for each instruction executed from now on
+    pub fn enable_tracing(&mut self) {
+        if self.trace.is_none() {
+            self.trace = Some(Vec::default());
+        }
+    }
+
+    /// Empty unless tracing is enabled
+    pub fn trace(&self) -> &[TraceStep] {
+        self.trace.as_ref().map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// The index of the next instruction to execute, which may be outside the program
+    pub fn pc(&self) -> i64 {
+        self.state.register_read(RP_IDX)
+    }
+
+    pub fn steps_executed(&self) -> u64 {
+        self.steps_executed
+    }
+
+    /// Executes one instruction, returning whether the program continues.  An instruction which
+    /// fails is neither executed nor traced.
     pub fn execute_step(&mut self) -> Result<bool, Error> {
+        if self.trace.is_none() {
+            let result = self.execute_instruction();
+            if result.is_ok() {
+                self.steps_executed += 1;
+            }
+            return result;
+        }
+
+        let pc = self.pc();
+        let instruction = self.program.instructions.get(pc as usize).copied();
+        let (array_reads, array_writes) = match &instruction {
+            Some(instruction) => self.array_accesses(instruction),
+            None => (Vec::default(), Vec::default()),
+        };
+        let result = self.execute_instruction();
+        if let (Ok(_), Some(instruction)) = (&result, instruction) {
+            let register_writes = instruction
+                .destination()
+                .map(|register| RegisterWrite {
+                    register,
+                    value: self.state.register_read(register),
+                })
+                .into_iter()
+                .collect();
+            let step = TraceStep {
+                step: self.steps_executed,
+                pc: pc as u64,
+                instruction,
+                gas_used: self.gas_used,
+                register_writes,
+                array_reads,
+                array_writes,
+            };
+            self.trace.as_mut().expect("tracing is enabled").push(step);
+            self.steps_executed += 1;
+        }
+        result
+    }
+
+    /// Whether the program may write to `arr[idx]`, which may need a new array or entry
+    fn check_write(&self, arr: u64, idx: u64) -> Result<(), Error> {
+        if self.read_only_arrays.contains(&arr) {
+            return Err(Error::ReadOnlyArray);
+        }
+        match self.state.arrays.get(&arr) {
+            Some(array) if array.0.contains_key(&idx) => Ok(()),
+            Some(array) if array.0.len() as u64 >= self.memory_limits.max_array_entries => {
+                Err(Error::MemoryLimit)
+            }
+            Some(_array) => Ok(()),
+            None => {
+                let writable_arrays = self
+                    .state
+                    .arrays
+                    .keys()
+                    .filter(|arr| !self.read_only_arrays.contains(arr))
+                    .count() as u64;
+                if writable_arrays >= self.memory_limits.max_arrays
+                    || self.memory_limits.max_array_entries == 0
+                {
+                    Err(Error::MemoryLimit)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// The array element `instruction` is about to read or write
+    fn array_accesses(&self, instruction: &Instruction) -> (Vec<ArrayAccess>, Vec<ArrayAccess>) {
+        match instruction {
+            Instruction::ArrIns { val, arr, idx } => (
+                Vec::default(),
+                vec![ArrayAccess {
+                    arr: self.state.register_read(*arr) as u64,
+                    idx: self.state.register_read(*idx) as u64,
+                    value: self.state.register_read(*val),
+                }],
+            ),
+            Instruction::ArrGet { arr, idx, .. } => {
+                let (arr, idx) = (
+                    self.state.register_read(*arr) as u64,
+                    self.state.register_read(*idx) as u64,
+                );
+                (
+                    vec![ArrayAccess {
+                        arr,
+                        idx,
+                        value: self.state.array_read(arr, idx),
+                    }],
+                    Vec::default(),
+                )
+            }
+            _ => (Vec::default(), Vec::default()),
+        }
+    }
+
+    fn execute_instruction(&mut self) -> Result<bool, Error> {
         let instruction = self
             .program
             .instructions
             .get(self.state.register_read(RP_IDX) as usize)
             .ok_or(Error::ExecutionError)?;
+        let write_gas = match instruction {
+            Instruction::ArrIns { arr, idx, .. } => {
+                let (arr, idx) = (
+                    self.state.register_read(*arr) as u64,
+                    self.state.register_read(*idx) as u64,
+                );
+                self.check_write(arr, idx)?;
+                self.array_write_gas.get(&arr).copied().unwrap_or(0)
+            }
+            Instruction::Call { .. } if self.state.call_stack.len() >= MAX_CALL_DEPTH => {
+                return Err(Error::StackOverflow)
+            }
+            Instruction::Push { .. } if self.state.stack.len() >= MAX_STACK_SIZE => {
+                return Err(Error::StackOverflow)
+            }
+            Instruction::Pop { .. } if self.state.stack.is_empty() => {
+                return Err(Error::StackUnderflow)
+            }
+            Instruction::Div { v1, .. } | Instruction::Mod { v1, .. }
+                if self.state.register_read(*v1) == 0 =>
+            {
+                return Err(Error::DivideByZero)
+            }
+            _ => 0,
+        };
+        let gas_used = self.gas_used + instruction.gas_cost() + write_gas;
+        if gas_used > self.gas_limit {
+            return Err(Error::OutOfGas);
+        }
+        self.gas_used = gas_used;
         match instruction {
             Instruction::ArrIns { arr, idx, val } => {
                 self.state.array_insert(
@@ -119,25 +345,37 @@ impl ProgramInstance {
                 Ok(true)
             }
             Instruction::Add { dst, v0, v1 } => {
-                let val = self.state.register_read(*v0) + self.state.register_read(*v1);
+                let val = self
+                    .state
+                    .register_read(*v0)
+                    .wrapping_add(self.state.register_read(*v1));
                 self.state.register_write(*dst, val);
                 self.state.incremement_rp();
                 Ok(true)
             }
             Instruction::Mul { dst, v0, v1 } => {
-                let val = self.state.register_read(*v0) * self.state.register_read(*v1);
+                let val = self
+                    .state
+                    .register_read(*v0)
+                    .wrapping_mul(self.state.register_read(*v1));
                 self.state.register_write(*dst, val);
                 self.state.incremement_rp();
                 Ok(true)
             }
             Instruction::Div { dst, v0, v1 } => {
-                let val = self.state.register_read(*v0) / self.state.register_read(*v1);
+                let val = self
+                    .state
+                    .register_read(*v0)
+                    .wrapping_div(self.state.register_read(*v1));
                 self.state.register_write(*dst, val);
                 self.state.incremement_rp();
                 Ok(true)
             }
             Instruction::Mod { dst, v0, v1 } => {
-                let val = self.state.register_read(*v0) % self.state.register_read(*v1);
+                let val = self
+                    .state
+                    .register_read(*v0)
+                    .wrapping_rem(self.state.register_read(*v1));
                 self.state.register_write(*dst, val);
                 self.state.incremement_rp();
                 Ok(true)
@@ -150,14 +388,41 @@ impl ProgramInstance {
                 self.state.incremement_rp();
                 Ok(true)
             }
+            Instruction::Call { adr } => {
+                let return_address = self.state.register_read(RP_IDX) + 1;
+                self.state.call_stack.push(return_address);
+                self.state
+                    .register_write(RP_IDX, self.state.register_read(*adr));
+                Ok(true)
+            }
+            Instruction::Ret {} => match self.state.call_stack.pop() {
+                Some(return_address) => {
+                    self.state.register_write(RP_IDX, return_address);
+                    Ok(true)
+                }
+                None => {
+                    self.state.incremement_rp();
+                    Ok(false)
+                }
+            },
+            Instruction::Push { src } => {
+                self.state.stack.push(self.state.register_read(*src));
+                self.state.incremement_rp();
+                Ok(true)
+            }
+            Instruction::Pop { dst } => {
+                let val = self.state.stack.pop().expect("checked before executing");
+                self.state.register_write(*dst, val);
+                self.state.incremement_rp();
+                Ok(true)
+            }
         }
     }
 
-    pub(crate) fn state(&self) -> &ExecutionState {
+    pub fn state(&self) -> &ExecutionState {
         &self.state
     }
 
-    #[cfg(test)]
     pub(crate) fn state_mut(&mut self) -> &mut ExecutionState {
         &mut self.state
     }
@@ -169,16 +434,68 @@ pub struct Program {
 }
 
 impl Program {
+    /// Parses assembly, one instruction per line.  A line `NAME:` labels the instruction after
+    /// it, and `movimm rN NAME` loads that instruction's address, for `jmp` or `call`.
     pub fn try_from_str(s: &str) -> Result<Self, Error> {
-        let instructions: Vec<_> = s
-            .lines()
-            .filter_map(|line| Instruction::try_from_line(line).expect("TODO"))
-            .collect();
+        Self::try_from_str_reporting_line(s).map_err(|_line| Error::ParseError)
+    }
+
+    /// As `try_from_str`, failing with the line number, from 1, of the first line which doesn't
+    /// parse or which repeats or uses an unknown label
+    pub fn try_from_str_reporting_line(s: &str) -> Result<Self, usize> {
+        lazy_static! {
+            static ref LABEL_RE: Regex = Regex::new(r"^\s*([A-Za-z_]\w*):\s*$").expect("TODO");
+            static ref LABEL_ADDRESS_RE: Regex =
+                Regex::new(r"(?i)^\s*movimm\s+r(\d{1,2})\s+([A-Za-z_]\w*)\s*$").expect("TODO");
+        }
+
+        let mut labels: HashMap<&str, usize> = HashMap::default();
+        let mut num_instructions = 0;
+        for (line_idx, line) in s.lines().enumerate() {
+            if let Some(captures) = LABEL_RE.captures(line) {
+                let name = captures.get(1).expect("TODO").as_str();
+                if labels.insert(name, num_instructions).is_some() {
+                    return Err(line_idx + 1);
+                }
+            } else if !line.chars().all(|c| c.is_whitespace()) {
+                num_instructions += 1;
+            }
+        }
+
+        let mut instructions: Vec<Instruction> = Vec::default();
+        for (line_idx, line) in s.lines().enumerate() {
+            if LABEL_RE.is_match(line) {
+                continue;
+            }
+            if let Some(captures) = LABEL_ADDRESS_RE.captures(line) {
+                let address = labels
+                    .get(captures.get(2).expect("TODO").as_str())
+                    .ok_or(line_idx + 1)?;
+                instructions.push(Instruction::MovImm {
+                    dst: parse_register(captures.get(1).expect("TODO").as_str())
+                        .map_err(|_| line_idx + 1)?,
+                    imm: i32::try_from(*address).map_err(|_| line_idx + 1)?,
+                });
+            } else if let Some(instruction) =
+                Instruction::try_from_line(line).map_err(|_| line_idx + 1)?
+            {
+                instructions.push(instruction);
+            }
+        }
         Ok(Self { instructions })
     }
 
+    /// Assembly text, one instruction per line, which `try_from_str` parses back
     pub fn get_string(&self) -> String {
-        todo!();
+        self.instructions
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
     }
 
     pub fn from_instructions(instructions: &[Instruction]) -> Self {
@@ -186,6 +503,44 @@ impl Program {
             instructions: instructions.to_vec(),
         }
     }
+
+    /// Compact encoding, each instruction an opcode byte followed by one byte per register
+    /// operand, or a little-endian `i32` for `movimm`'s immediate
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::default();
+        for instruction in &self.instructions {
+            instruction.encode_into(&mut bytes);
+        }
+        bytes
+    }
+
+    pub fn try_from_bytecode(mut bytes: &[u8]) -> Result<Self, Error> {
+        let mut instructions: Vec<Instruction> = Vec::default();
+        while !bytes.is_empty() {
+            let (instruction, len) = Instruction::try_decode(bytes)?;
+            instructions.push(instruction);
+            bytes = &bytes[len..];
+        }
+        Ok(Self { instructions })
+    }
+}
+
+/// How much array memory a program may use, not counting read-only arrays.  Unlimited by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLimits {
+    /// Arrays with at least one entry
+    pub max_arrays: u64,
+    /// Indexes written in any one array, including those written before the program started
+    pub max_array_entries: u64,
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        Self {
+            max_arrays: u64::MAX,
+            max_array_entries: u64::MAX,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -204,28 +559,30 @@ impl Array {
 struct Register(i64);
 
 pub struct ExecutionState {
-    arrays: RefCell<HashMap<u64, Array>>,
-    registers: [Register; 16],
+    arrays: HashMap<u64, Array>,
+    registers: [Register; NUM_REGISTERS],
+    /// Return addresses of the calls in progress, innermost last
+    call_stack: Vec<i64>,
+    /// Values pushed and not yet popped, most recent last
+    stack: Vec<i64>,
 }
 
 impl ExecutionState {
     pub fn default() -> Self {
         Self {
-            arrays: RefCell::default(),
-            registers: [Register(0); 16],
+            arrays: HashMap::default(),
+            registers: [Register(0); NUM_REGISTERS],
+            call_stack: Vec::default(),
+            stack: Vec::default(),
         }
     }
 
     pub fn array_insert(&mut self, arr: u64, idx: u64, val: i64) {
-        self.arrays
-            .borrow_mut()
-            .entry(arr)
-            .or_default()
-            .insert(idx, val)
+        self.arrays.entry(arr).or_default().insert(idx, val)
     }
 
     pub fn array_read(&self, arr: u64, idx: u64) -> i64 {
-        self.arrays.borrow_mut().entry(arr).or_default().get(idx)
+        self.arrays.get(&arr).map_or(0, |array| array.get(idx))
     }
 
     pub fn register_write(&mut self, idx: RegIdx, val: i64) {
@@ -236,20 +593,30 @@ impl ExecutionState {
         self.registers[idx.0 as usize].0
     }
 
+    pub fn call_stack(&self) -> &[i64] {
+        &self.call_stack
+    }
+
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
+    }
+
     pub fn incremement_rp(&mut self) {
         self.registers[RP_IDX.0 as usize].0 += 1;
     }
 
-    pub fn iter_touched_values(&self, arr: u64) -> Box<dyn Iterator<Item = (u64, i64)>> {
-        Box::new(
-            self.arrays
-                .borrow()
-                .get(&arr)
-                .map(|array| &array.0)
-                .unwrap_or(&HashMap::default())
-                .clone()
-                .into_iter(),
-        )
+    /// Arrays which have been written to, in order
+    pub fn touched_arrays(&self) -> Vec<u64> {
+        let mut arrays: Vec<u64> = self.arrays.keys().copied().collect();
+        arrays.sort();
+        arrays
+    }
+
+    pub fn iter_touched_values(&self, arr: u64) -> impl Iterator<Item = (u64, i64)> + '_ {
+        self.arrays
+            .get(&arr)
+            .into_iter()
+            .flat_map(|array| array.0.iter().map(|(idx, val)| (*idx, *val)))
     }
 }
 
@@ -262,6 +629,19 @@ impl From<u8> for RegIdx {
     }
 }
 
+impl std::fmt::Display for RegIdx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "r{}", self.0)
+    }
+}
+
+fn parse_register(s: &str) -> Result<RegIdx, Error> {
+    match s.parse::<u8>() {
+        Ok(idx) if (idx as usize) < NUM_REGISTERS => Ok(RegIdx(idx)),
+        _ => Err(Error::ParseError),
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     ArrIns {
@@ -337,9 +717,206 @@ pub enum Instruction {
     },
     Noop {},
     Halt {},
+    /// Jumps to `adr`, and `ret` comes back to the next instruction
+    Call {
+        adr: RegIdx,
+    },
+    /// Returns from the latest `call`, or ends the program if there is none
+    Ret {},
+    Push {
+        src: RegIdx,
+    },
+    Pop {
+        dst: RegIdx,
+    },
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ArrIns { val, arr, idx } => write!(f, "arrins {} {} {}", val, arr, idx),
+            Self::ArrGet { dst, arr, idx } => write!(f, "arrget {} {} {}", dst, arr, idx),
+            Self::MovImm { dst, imm } => write!(f, "movimm {} {}", dst, imm),
+            Self::Mov { dst, src } => write!(f, "mov {} {}", dst, src),
+            Self::Jmp { adr } => write!(f, "jmp {}", adr),
+            Self::Jeq { adr, v0, v1 } => write!(f, "jeq {} {} {}", adr, v0, v1),
+            Self::Jne { adr, v0, v1 } => write!(f, "jne {} {} {}", adr, v0, v1),
+            Self::Jgt { adr, v0, v1 } => write!(f, "jgt {} {} {}", adr, v0, v1),
+            Self::Jge { adr, v0, v1 } => write!(f, "jge {} {} {}", adr, v0, v1),
+            Self::Jlt { adr, v0, v1 } => write!(f, "jlt {} {} {}", adr, v0, v1),
+            Self::Jle { adr, v0, v1 } => write!(f, "jle {} {} {}", adr, v0, v1),
+            Self::Add { dst, v0, v1 } => write!(f, "add {} {} {}", dst, v0, v1),
+            Self::Mul { dst, v0, v1 } => write!(f, "mul {} {} {}", dst, v0, v1),
+            Self::Div { dst, v0, v1 } => write!(f, "div {} {} {}", dst, v0, v1),
+            Self::Mod { dst, v0, v1 } => write!(f, "mod {} {} {}", dst, v0, v1),
+            Self::Noop {} => write!(f, "noop"),
+            Self::Halt {} => write!(f, "halt"),
+            Self::Call { adr } => write!(f, "call {}", adr),
+            Self::Ret {} => write!(f, "ret"),
+            Self::Push { src } => write!(f, "push {}", src),
+            Self::Pop { dst } => write!(f, "pop {}", dst),
+        }
+    }
+}
+
+mod opcode {
+    pub const ARRINS: u8 = 0x01;
+    pub const ARRGET: u8 = 0x02;
+    pub const MOVIMM: u8 = 0x03;
+    pub const MOV: u8 = 0x04;
+    pub const JMP: u8 = 0x05;
+    pub const JEQ: u8 = 0x06;
+    pub const JNE: u8 = 0x07;
+    pub const JGT: u8 = 0x08;
+    pub const JGE: u8 = 0x09;
+    pub const JLT: u8 = 0x0a;
+    pub const JLE: u8 = 0x0b;
+    pub const ADD: u8 = 0x0c;
+    pub const MUL: u8 = 0x0d;
+    pub const DIV: u8 = 0x0e;
+    pub const MOD: u8 = 0x0f;
+    pub const NOOP: u8 = 0x10;
+    pub const HALT: u8 = 0x11;
+    pub const CALL: u8 = 0x12;
+    pub const RET: u8 = 0x13;
+    pub const PUSH: u8 = 0x14;
+    pub const POP: u8 = 0x15;
 }
 
 impl Instruction {
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        let (opcode, registers): (u8, &[RegIdx]) = match self {
+            Self::ArrIns { val, arr, idx } => (opcode::ARRINS, &[*val, *arr, *idx]),
+            Self::ArrGet { dst, arr, idx } => (opcode::ARRGET, &[*dst, *arr, *idx]),
+            Self::MovImm { dst, imm } => {
+                bytes.extend_from_slice(&[opcode::MOVIMM, dst.0]);
+                bytes.extend_from_slice(&imm.to_le_bytes());
+                return;
+            }
+            Self::Mov { dst, src } => (opcode::MOV, &[*dst, *src]),
+            Self::Jmp { adr } => (opcode::JMP, &[*adr]),
+            Self::Jeq { adr, v0, v1 } => (opcode::JEQ, &[*adr, *v0, *v1]),
+            Self::Jne { adr, v0, v1 } => (opcode::JNE, &[*adr, *v0, *v1]),
+            Self::Jgt { adr, v0, v1 } => (opcode::JGT, &[*adr, *v0, *v1]),
+            Self::Jge { adr, v0, v1 } => (opcode::JGE, &[*adr, *v0, *v1]),
+            Self::Jlt { adr, v0, v1 } => (opcode::JLT, &[*adr, *v0, *v1]),
+            Self::Jle { adr, v0, v1 } => (opcode::JLE, &[*adr, *v0, *v1]),
+            Self::Add { dst, v0, v1 } => (opcode::ADD, &[*dst, *v0, *v1]),
+            Self::Mul { dst, v0, v1 } => (opcode::MUL, &[*dst, *v0, *v1]),
+            Self::Div { dst, v0, v1 } => (opcode::DIV, &[*dst, *v0, *v1]),
+            Self::Mod { dst, v0, v1 } => (opcode::MOD, &[*dst, *v0, *v1]),
+            Self::Noop {} => (opcode::NOOP, &[]),
+            Self::Halt {} => (opcode::HALT, &[]),
+            Self::Call { adr } => (opcode::CALL, &[*adr]),
+            Self::Ret {} => (opcode::RET, &[]),
+            Self::Push { src } => (opcode::PUSH, &[*src]),
+            Self::Pop { dst } => (opcode::POP, &[*dst]),
+        };
+        bytes.push(opcode);
+        bytes.extend(registers.iter().map(|register| register.0));
+    }
+
+    /// Decodes the instruction at the start of `bytes`, returning it with its encoded length
+    fn try_decode(bytes: &[u8]) -> Result<(Self, usize), Error> {
+        let register = |idx: usize| -> Result<RegIdx, Error> {
+            match bytes.get(idx) {
+                Some(&register) if (register as usize) < NUM_REGISTERS => Ok(RegIdx(register)),
+                _ => Err(Error::ParseError),
+            }
+        };
+        let opcode = *bytes.first().ok_or(Error::ParseError)?;
+        let decoded = match opcode {
+            opcode::ARRINS => (
+                Self::ArrIns {
+                    val: register(1)?,
+                    arr: register(2)?,
+                    idx: register(3)?,
+                },
+                4,
+            ),
+            opcode::ARRGET => (
+                Self::ArrGet {
+                    dst: register(1)?,
+                    arr: register(2)?,
+                    idx: register(3)?,
+                },
+                4,
+            ),
+            opcode::MOVIMM => {
+                let mut imm = [0u8; 4];
+                imm.copy_from_slice(bytes.get(2..6).ok_or(Error::ParseError)?);
+                (
+                    Self::MovImm {
+                        dst: register(1)?,
+                        imm: i32::from_le_bytes(imm),
+                    },
+                    6,
+                )
+            }
+            opcode::MOV => (
+                Self::Mov {
+                    dst: register(1)?,
+                    src: register(2)?,
+                },
+                3,
+            ),
+            opcode::JMP => (Self::Jmp { adr: register(1)? }, 2),
+            opcode::JEQ | opcode::JNE | opcode::JGT | opcode::JGE | opcode::JLT | opcode::JLE => {
+                let (adr, v0, v1) = (register(1)?, register(2)?, register(3)?);
+                let instruction = match opcode {
+                    opcode::JEQ => Self::Jeq { adr, v0, v1 },
+                    opcode::JNE => Self::Jne { adr, v0, v1 },
+                    opcode::JGT => Self::Jgt { adr, v0, v1 },
+                    opcode::JGE => Self::Jge { adr, v0, v1 },
+                    opcode::JLT => Self::Jlt { adr, v0, v1 },
+                    _ => Self::Jle { adr, v0, v1 },
+                };
+                (instruction, 4)
+            }
+            opcode::ADD | opcode::MUL | opcode::DIV | opcode::MOD => {
+                let (dst, v0, v1) = (register(1)?, register(2)?, register(3)?);
+                let instruction = match opcode {
+                    opcode::ADD => Self::Add { dst, v0, v1 },
+                    opcode::MUL => Self::Mul { dst, v0, v1 },
+                    opcode::DIV => Self::Div { dst, v0, v1 },
+                    _ => Self::Mod { dst, v0, v1 },
+                };
+                (instruction, 4)
+            }
+            opcode::NOOP => (Self::Noop {}, 1),
+            opcode::HALT => (Self::Halt {}, 1),
+            opcode::CALL => (Self::Call { adr: register(1)? }, 2),
+            opcode::RET => (Self::Ret {}, 1),
+            opcode::PUSH => (Self::Push { src: register(1)? }, 2),
+            opcode::POP => (Self::Pop { dst: register(1)? }, 2),
+            _ => return Err(Error::ParseError),
+        };
+        Ok(decoded)
+    }
+
+    /// The register the instruction writes its result to, other than the program counter
+    pub fn destination(&self) -> Option<RegIdx> {
+        match self {
+            Self::ArrGet { dst, .. }
+            | Self::MovImm { dst, .. }
+            | Self::Mov { dst, .. }
+            | Self::Add { dst, .. }
+            | Self::Mul { dst, .. }
+            | Self::Div { dst, .. }
+            | Self::Mod { dst, .. }
+            | Self::Pop { dst } => Some(*dst),
+            _ => None,
+        }
+    }
+
+    pub fn gas_cost(&self) -> u64 {
+        match self {
+            Self::ArrIns { .. } | Self::ArrGet { .. } | Self::Call { .. } | Self::Ret {} => 2,
+            Self::Halt {} => 0,
+            _ => 1,
+        }
+    }
+
     pub fn try_from_line(line: &str) -> Result<Option<Self>, Error> {
         lazy_static! {
             static ref LINE_RE: Regex = Regex::new(
@@ -378,9 +955,9 @@ impl Instruction {
                 let idx = captures.name("r3");
                 if let (Some(val), Some(arr), Some(idx)) = (val, arr, idx) {
                     Ok(Some(Self::ArrIns {
-                        val: val.as_str().parse::<u8>().expect("TODO").into(),
-                        arr: arr.as_str().parse::<u8>().expect("TODO").into(),
-                        idx: idx.as_str().parse::<u8>().expect("TODO").into(),
+                        val: parse_register(val.as_str())?,
+                        arr: parse_register(arr.as_str())?,
+                        idx: parse_register(idx.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -392,9 +969,9 @@ impl Instruction {
                 let idx = captures.name("r3");
                 if let (Some(dst), Some(arr), Some(idx)) = (dst, arr, idx) {
                     Ok(Some(Self::ArrGet {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        arr: arr.as_str().parse::<u8>().expect("TODO").into(),
-                        idx: idx.as_str().parse::<u8>().expect("TODO").into(),
+                        dst: parse_register(dst.as_str())?,
+                        arr: parse_register(arr.as_str())?,
+                        idx: parse_register(idx.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -405,8 +982,8 @@ impl Instruction {
                 let imm = captures.name("immediate");
                 if let (Some(dst), Some(imm)) = (dst, imm) {
                     Ok(Some(Self::MovImm {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        imm: imm.as_str().parse().expect("TODO"),
+                        dst: parse_register(dst.as_str())?,
+                        imm: imm.as_str().parse().map_err(|_| Error::ParseError)?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -417,8 +994,8 @@ impl Instruction {
                 let src = captures.name("r2");
                 if let (Some(dst), Some(src)) = (dst, src) {
                     Ok(Some(Self::Mov {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        src: src.as_str().parse::<u8>().expect("TODO").into(),
+                        dst: parse_register(dst.as_str())?,
+                        src: parse_register(src.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -428,7 +1005,7 @@ impl Instruction {
                 let adr = captures.name("r1");
                 if let Some(adr) = adr {
                     Ok(Some(Self::Jmp {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -440,9 +1017,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(adr), Some(v0), Some(v1)) = (adr, v0, v1) {
                     Ok(Some(Self::Jeq {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -454,9 +1031,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(adr), Some(v0), Some(v1)) = (adr, v0, v1) {
                     Ok(Some(Self::Jne {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -468,9 +1045,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(adr), Some(v0), Some(v1)) = (adr, v0, v1) {
                     Ok(Some(Self::Jgt {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -482,9 +1059,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(adr), Some(v0), Some(v1)) = (adr, v0, v1) {
                     Ok(Some(Self::Jge {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -496,9 +1073,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(adr), Some(v0), Some(v1)) = (adr, v0, v1) {
                     Ok(Some(Self::Jlt {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -510,9 +1087,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(adr), Some(v0), Some(v1)) = (adr, v0, v1) {
                     Ok(Some(Self::Jle {
-                        adr: adr.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        adr: parse_register(adr.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -524,9 +1101,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(dst), Some(v0), Some(v1)) = (dst, v0, v1) {
                     Ok(Some(Self::Add {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        dst: parse_register(dst.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -538,9 +1115,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(dst), Some(v0), Some(v1)) = (dst, v0, v1) {
                     Ok(Some(Self::Mul {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        dst: parse_register(dst.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -552,9 +1129,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(dst), Some(v0), Some(v1)) = (dst, v0, v1) {
                     Ok(Some(Self::Div {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        dst: parse_register(dst.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -566,9 +1143,9 @@ impl Instruction {
                 let v1 = captures.name("r3");
                 if let (Some(dst), Some(v0), Some(v1)) = (dst, v0, v1) {
                     Ok(Some(Self::Mod {
-                        dst: dst.as_str().parse::<u8>().expect("TODO").into(),
-                        v0: v0.as_str().parse::<u8>().expect("TODO").into(),
-                        v1: v1.as_str().parse::<u8>().expect("TODO").into(),
+                        dst: parse_register(dst.as_str())?,
+                        v0: parse_register(v0.as_str())?,
+                        v1: parse_register(v1.as_str())?,
                     }))
                 } else {
                     Err(Error::ParseError)
@@ -576,6 +1153,25 @@ impl Instruction {
             }
             "halt" => Ok(Some(Self::Halt {})),
             "noop" => Ok(Some(Self::Noop {})),
+            "call" => match captures.name("r1") {
+                Some(adr) => Ok(Some(Self::Call {
+                    adr: parse_register(adr.as_str())?,
+                })),
+                None => Err(Error::ParseError),
+            },
+            "ret" => Ok(Some(Self::Ret {})),
+            "push" => match captures.name("r1") {
+                Some(src) => Ok(Some(Self::Push {
+                    src: parse_register(src.as_str())?,
+                })),
+                None => Err(Error::ParseError),
+            },
+            "pop" => match captures.name("r1") {
+                Some(dst) => Ok(Some(Self::Pop {
+                    dst: parse_register(dst.as_str())?,
+                })),
+                None => Err(Error::ParseError),
+            },
             _ => Err(Error::ParseError),
         }
     }
@@ -775,6 +1371,55 @@ mod tests {
             assert!(result.is_ok());
         }
 
+        #[test]
+        fn program_string_round_trip() {
+            let prog = "ArrIns r0 r1 r2\nmovimm r3 -7\n\njle r4 r5 r6\nmod r7 r8 r9\nnoop\nhalt";
+            let program = Program::try_from_str(prog).expect("TODO");
+            assert_eq!(
+                program.get_string(),
+                "arrins r0 r1 r2\nmovimm r3 -7\njle r4 r5 r6\nmod r7 r8 r9\nnoop\nhalt"
+            );
+            assert_eq!(
+                Program::try_from_str(&program.get_string()).expect("TODO"),
+                program
+            );
+        }
+
+        #[test]
+        fn bytecode_round_trip() {
+            let prog = "arrins r0 r1 r2\narrget r3 r4 r5\nmovimm r6 -123456\nmov r7 r8\njmp r9\n\
+                jeq r1 r2 r3\njne r1 r2 r3\njgt r1 r2 r3\njge r1 r2 r3\njlt r1 r2 r3\n\
+                jle r1 r2 r3\nadd r1 r2 r3\nmul r1 r2 r3\ndiv r1 r2 r3\nmod r15 r14 r13\n\
+                noop\nhalt\ncall r4\nret\npush r5\npop r6";
+            let program = Program::try_from_str(prog).expect("TODO");
+            assert_eq!(program.instructions().len(), 21);
+            let bytecode = program.to_bytecode();
+            assert_eq!(&bytecode[..4], &[opcode::ARRINS, 0, 1, 2]);
+            assert_eq!(
+                Program::try_from_bytecode(&bytecode).expect("TODO"),
+                program
+            );
+        }
+
+        #[test]
+        fn bad_bytecode() {
+            // Unknown opcode, truncated operands, register out of range
+            assert!(Program::try_from_bytecode(&[0xff]).is_err());
+            assert!(Program::try_from_bytecode(&[opcode::HALT, opcode::MOVIMM, 0, 1, 2]).is_err());
+            assert!(Program::try_from_bytecode(&[opcode::MOV, 0, 16]).is_err());
+            assert!(Program::try_from_bytecode(&[])
+                .expect("TODO")
+                .instructions()
+                .is_empty());
+        }
+
+        #[test]
+        fn parse_out_of_range_operands() {
+            assert!(Instruction::try_from_line("mov r16 r0").is_err());
+            assert!(Instruction::try_from_line("movimm r0 99999999999").is_err());
+            assert!(Program::try_from_str("halt\nadd r0 r1 r99").is_err());
+        }
+
         #[test]
         fn parse_empty_line() {
             let result = Instruction::try_from_line("");
@@ -805,6 +1450,54 @@ mod tests {
                 panic!("Failed to parse Noop")
             }
         }
+
+        #[test]
+        fn parse_call_and_stack() {
+            let parse = |line| Instruction::try_from_line(line).expect("TODO");
+            assert_eq!(parse("call r3"), Some(Instruction::Call { adr: RegIdx(3) }));
+            assert_eq!(parse("ret"), Some(Instruction::Ret {}));
+            assert_eq!(
+                parse("push r14"),
+                Some(Instruction::Push { src: RegIdx(14) })
+            );
+            assert_eq!(parse("Pop r0"), Some(Instruction::Pop { dst: R0_IDX }));
+            assert_eq!(Instruction::try_from_line("call"), Err(Error::ParseError));
+            assert_eq!(
+                Instruction::try_from_line("pop r16"),
+                Err(Error::ParseError)
+            );
+        }
+
+        #[test]
+        fn parse_labels() {
+            let prog = "
+                movimm r0 double
+                call r0
+                halt
+
+            double:
+                add r1 r1 r1
+              end:
+                ret";
+            let program = Program::try_from_str(prog).expect("TODO");
+            assert_eq!(
+                program.instructions()[0],
+                Instruction::MovImm {
+                    dst: R0_IDX,
+                    imm: 3
+                }
+            );
+            assert_eq!(program.instructions().len(), 5);
+            assert_eq!(
+                Program::try_from_str(&program.get_string()).expect("TODO"),
+                program
+            );
+
+            let duplicate = "a:\nnoop\na:\nhalt";
+            assert_eq!(Program::try_from_str(duplicate), Err(Error::ParseError));
+            let unknown = "movimm r0 b\njmp r0";
+            assert_eq!(Program::try_from_str(unknown), Err(Error::ParseError));
+        }
     }
 
     mod execute {
@@ -1608,5 +2301,274 @@ mod tests {
             }
             assert_eq!(program_instance.state.register_read(R0_IDX), vexpected);
         }
+
+        #[test]
+        fn exec_gas_accounting() {
+            let program = Program::from_instructions(&[
+                Instruction::MovImm {
+                    dst: R0_IDX,
+                    imm: 1,
+                },
+                Instruction::ArrIns {
+                    val: R0_IDX,
+                    arr: R0_IDX,
+                    idx: R0_IDX,
+                },
+                Instruction::Halt {},
+            ]);
+            let state = ExecutionState::default();
+            let mut program_instance = ProgramInstance::new(program, state);
+
+            assert_eq!(program_instance.execute_step(), Ok(true));
+            assert_eq!(program_instance.gas_used(), 1);
+            assert_eq!(program_instance.execute_step(), Ok(true));
+            assert_eq!(program_instance.gas_used(), 3);
+            assert_eq!(program_instance.execute_step(), Ok(false));
+            assert_eq!(program_instance.gas_used(), 3);
+        }
+
+        #[test]
+        fn exec_out_of_gas() {
+            let program = Program::from_instructions(&[
+                Instruction::MovImm {
+                    dst: R0_IDX,
+                    imm: 0,
+                },
+                Instruction::Jmp { adr: R0_IDX },
+            ]);
+            let state = ExecutionState::default();
+            let mut program_instance = ProgramInstance::new(program, state);
+            program_instance.set_gas_limit(3);
+
+            for _ in 0..3 {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.execute_step(), Err(Error::OutOfGas));
+            assert_eq!(program_instance.gas_used(), 3);
+        }
+
+        #[test]
+        fn exec_trace() {
+            let program = Program::try_from_str(
+                "movimm r0 2
+                movimm r1 7
+                arrins r1 r0 r0
+                arrget r2 r0 r0
+                halt",
+            )
+            .expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            assert_eq!(program_instance.trace(), &[]);
+            program_instance.enable_tracing();
+            while program_instance.execute_step().expect("TODO") {}
+
+            let trace = program_instance.trace();
+            assert_eq!(trace.len(), 5);
+            assert_eq!(program_instance.steps_executed(), 5);
+            assert_eq!(
+                trace[1].register_writes,
+                vec![trace::RegisterWrite {
+                    register: R1_IDX,
+                    value: 7
+                }]
+            );
+            let access = trace::ArrayAccess {
+                arr: 2,
+                idx: 2,
+                value: 7,
+            };
+            assert_eq!(trace[2].array_writes, vec![access]);
+            assert_eq!(trace[2].register_writes, vec![]);
+            assert_eq!(trace[2].gas_used, 4);
+            assert_eq!(trace[3].array_reads, vec![access]);
+            assert_eq!(
+                trace[3].to_string(),
+                "3: arrget r2 r0 r0  arr2[2] is 7, r2 = 7"
+            );
+            assert_eq!(trace[4].pc, 4);
+            assert_eq!(program_instance.state.touched_arrays(), vec![2]);
+
+            let json: serde_json::Value =
+                serde_json::from_str(&trace::to_json(trace)).expect("TODO");
+            assert_eq!(json[2]["instruction"], "arrins r1 r0 r0");
+            assert_eq!(json[3]["register_writes"][0]["register"], "r2");
+        }
+
+        #[test]
+        fn exec_array_write_gas() {
+            let program = Program::try_from_str(
+                "movimm r0 12
+                arrins r0 r0 r0
+                arrget r1 r0 r0
+                movimm r0 3
+                arrins r0 r0 r0",
+            )
+            .expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            program_instance.set_array_write_gas(12, 8);
+            program_instance.set_gas_limit(14);
+
+            for _ in 0..4 {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            // Reads and writes to other arrays cost the usual amount
+            assert_eq!(program_instance.gas_used(), 1 + 10 + 2 + 1);
+            assert_eq!(program_instance.execute_step(), Err(Error::OutOfGas));
+            assert_eq!(program_instance.state.array_read(3, 3), 0);
+        }
+
+        #[test]
+        fn exec_reads_do_not_allocate() {
+            let program = Program::try_from_str(
+                "movimm r0 5
+                arrget r1 r0 r0
+                arrget r1 r1 r0",
+            )
+            .expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            while program_instance.execute_step().is_ok() {}
+            assert_eq!(program_instance.state.touched_arrays(), Vec::<u64>::new());
+        }
+
+        #[test]
+        fn exec_memory_limits() {
+            // Writes arr13[0], arr13[1], arr13[0] again, then arr14[0]
+            let program = Program::try_from_str(
+                "movimm r0 13
+                movimm r1 0
+                movimm r2 1
+                arrins r2 r0 r1
+                arrins r2 r0 r2
+                arrins r0 r0 r1
+                movimm r0 14
+                arrins r2 r0 r1",
+            )
+            .expect("TODO");
+            let mut state = ExecutionState::default();
+            state.array_insert(1, 0, 100);
+            state.array_insert(9, 0, 1);
+            let mut program_instance = ProgramInstance::new(program, state);
+            program_instance.set_read_only(1);
+            program_instance.set_memory_limits(MemoryLimits {
+                max_arrays: 2,
+                max_array_entries: 2,
+            });
+
+            for _ in 0..7 {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.state.array_read(13, 0), 13);
+            // Read-only arr1 doesn't count, but arr9 and arr13 do
+            assert_eq!(program_instance.execute_step(), Err(Error::MemoryLimit));
+            assert_eq!(program_instance.state.touched_arrays(), vec![1, 9, 13]);
+
+            let program = Program::try_from_str(
+                "movimm r0 13
+                movimm r1 2
+                arrins r1 r0 r1",
+            )
+            .expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            program_instance.set_memory_limits(MemoryLimits {
+                max_arrays: 2,
+                max_array_entries: 1,
+            });
+            program_instance.state.array_insert(13, 0, 1);
+            for _ in 0..2 {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.execute_step(), Err(Error::MemoryLimit));
+            assert_eq!(program_instance.gas_used(), 2);
+        }
+
+        #[test]
+        fn exec_read_only() {
+            let program = Program::try_from_str(
+                "movimm r0 3
+                arrget r1 r0 r0
+                arrins r1 r0 r0",
+            )
+            .expect("TODO");
+            let mut state = ExecutionState::default();
+            state.array_insert(3, 3, 7);
+            let mut program_instance = ProgramInstance::new(program, state);
+            program_instance.set_read_only(3);
+
+            for _ in 0..2 {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.state.register_read(R1_IDX), 7);
+            assert_eq!(program_instance.execute_step(), Err(Error::ReadOnlyArray));
+        }
+
+        #[test]
+        fn exec_call_and_ret() {
+            // r1 = 3, doubled twice by a procedure which saves r2 on the stack
+            let program = Program::try_from_str(
+                "movimm r1 3
+                movimm r2 7
+                movimm r0 double
+                call r0
+                call r0
+                halt
+            double:
+                push r2
+                add r2 r1 r1
+                mov r1 r2
+                pop r2
+                ret",
+            )
+            .expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            while program_instance.execute_step().expect("TODO") {}
+
+            assert_eq!(program_instance.state.register_read(R1_IDX), 12);
+            assert_eq!(program_instance.state.register_read(R2_IDX), 7);
+            assert_eq!(program_instance.pc(), 6);
+            assert!(program_instance.state.call_stack().is_empty());
+            assert!(program_instance.state.stack().is_empty());
+            // 3 movimm, 2 calls, 2 * (push, add, mov, pop, ret)
+            assert_eq!(
+                program_instance.gas_used(),
+                3 + 2 * 2 + 2 * (1 + 1 + 1 + 1 + 2)
+            );
+        }
+
+        #[test]
+        fn exec_ret_without_call_ends_program() {
+            let program = Program::try_from_str("ret\nnoop").expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            assert_eq!(program_instance.execute_step(), Ok(false));
+        }
+
+        #[test]
+        fn exec_stack_overflow() {
+            let program = Program::try_from_str("call r0").expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            for _ in 0..MAX_CALL_DEPTH {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.execute_step(), Err(Error::StackOverflow));
+            assert_eq!(program_instance.state.call_stack().len(), MAX_CALL_DEPTH);
+
+            let program = Program::try_from_str("push r0\njmp r1").expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            for _ in 0..2 * MAX_STACK_SIZE {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.execute_step(), Err(Error::StackOverflow));
+            assert_eq!(program_instance.state.stack().len(), MAX_STACK_SIZE);
+        }
+
+        #[test]
+        fn exec_stack_underflow() {
+            let program = Program::try_from_str("push r0\npop r1\npop r1").expect("TODO");
+            let mut program_instance = ProgramInstance::new(program, ExecutionState::default());
+            for _ in 0..2 {
+                assert_eq!(program_instance.execute_step(), Ok(true));
+            }
+            assert_eq!(program_instance.execute_step(), Err(Error::StackUnderflow));
+            assert_eq!(program_instance.gas_used(), 2);
+        }
     }
 }
@@ -0,0 +1,74 @@
+use serde::{Serialize, Serializer};
+
+use super::{Instruction, RegIdx};
+
+/// What one executed instruction did, recorded by `ProgramInstance::enable_tracing`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TraceStep {
+    /// How many instructions were executed before this one
+    pub step: u64,
+    /// Where the instruction is in the program
+    pub pc: u64,
+    /// In assembly when exported
+    #[serde(serialize_with = "serialize_as_assembly")]
+    pub instruction: Instruction,
+    /// Total, including this instruction
+    pub gas_used: u64,
+    /// Not including the program counter, which is `pc` of the next step
+    pub register_writes: Vec<RegisterWrite>,
+    pub array_reads: Vec<ArrayAccess>,
+    pub array_writes: Vec<ArrayAccess>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct RegisterWrite {
+    #[serde(serialize_with = "serialize_as_assembly")]
+    pub register: RegIdx,
+    pub value: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ArrayAccess {
+    pub arr: u64,
+    pub idx: u64,
+    pub value: i64,
+}
+
+impl std::fmt::Display for TraceStep {
+    /// One line, such as `12: add r0 r1 r2  r0 = 5`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.pc, self.instruction)?;
+        let effects: Vec<String> = self
+            .array_reads
+            .iter()
+            .map(|read| format!("arr{}[{}] is {}", read.arr, read.idx, read.value))
+            .chain(
+                self.register_writes
+                    .iter()
+                    .map(|write| format!("{} = {}", write.register, write.value)),
+            )
+            .chain(
+                self.array_writes
+                    .iter()
+                    .map(|write| format!("arr{}[{}] = {}", write.arr, write.idx, write.value)),
+            )
+            .collect();
+        if !effects.is_empty() {
+            write!(f, "  {}", effects.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The steps as a JSON array
+pub fn to_json(steps: &[TraceStep]) -> String {
+    serde_json::to_string_pretty(steps).expect("traces always serialize")
+}
+
+fn serialize_as_assembly<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: std::fmt::Display,
+    S: Serializer,
+{
+    serializer.collect_str(value)
+}
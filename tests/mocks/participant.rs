@@ -12,7 +12,7 @@ pub struct MockParticipantPool {
 }
 
 impl MockParticipantPool {
-    pub fn participant(&self, participant_id: ParticipantId) -> Option<Ref<MockParticipant>> {
+    pub fn participant(&self, participant_id: ParticipantId) -> Option<Ref<'_, MockParticipant>> {
         self.participants
             .iter()
             .find(|p| p.borrow().participant_id == participant_id)
@@ -22,7 +22,7 @@ impl MockParticipantPool {
     pub fn participant_mut(
         &self,
         participant_id: ParticipantId,
-    ) -> Option<RefMut<MockParticipant>> {
+    ) -> Option<RefMut<'_, MockParticipant>> {
         self.participants
             .iter()
             .find(|p| p.borrow().participant_id == participant_id)
@@ -34,19 +34,15 @@ impl MockParticipantPool {
     }
 
     fn drain_all_directives(&mut self) {
-        self.pending_directives.extend(
-            self.participants
-                .iter_mut()
-                .map(|p| {
-                    let p_id = p.borrow().participant_id;
-                    p.borrow_mut()
-                        .pending_directives
-                        .drain(..)
-                        .map(|directive| (p_id, directive))
-                        .collect::<Vec<_>>()
-                })
-                .flatten(),
-        );
+        self.pending_directives
+            .extend(self.participants.iter_mut().flat_map(|p| {
+                let p_id = p.borrow().participant_id;
+                p.borrow_mut()
+                    .pending_directives
+                    .drain(..)
+                    .map(|directive| (p_id, directive))
+                    .collect::<Vec<_>>()
+            }));
     }
 }
 